@@ -0,0 +1,111 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+const MIN_FILTER_BITS: usize = 64;
+const MAX_HASH_COUNT: u32 = 30;
+
+/// Bloom filter over the keys of a memtable file. A lookup for a key the filter rules out can
+/// skip reading the file, with the share of absent keys let through depending on the bits
+/// given to each key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    /// Builds a filter from the [`bloom_hash`] of every key in the file.
+    pub fn new(bits_per_key: usize, key_hashes: &[u64]) -> Self {
+        //ln(2) times the bits per key minimises false positives
+        let hash_count = ((bits_per_key as f64 * 0.69) as u32).clamp(1, MAX_HASH_COUNT);
+        let bit_count = (key_hashes.len() * bits_per_key).max(MIN_FILTER_BITS);
+        let mut bloom_filter = BloomFilter {
+            bits: vec![0; bit_count.div_ceil(8)],
+            hash_count,
+        };
+        for key_hash in key_hashes {
+            for bit_position in bloom_filter.bit_positions(*key_hash) {
+                bloom_filter.bits[bit_position / 8] |= 1 << (bit_position % 8);
+            }
+        }
+        bloom_filter
+    }
+
+    /// Returns `false` only when the key was definitely not in the file.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(bloom_hash(key))
+            .all(|bit_position| self.bits[bit_position / 8] & (1 << (bit_position % 8)) != 0)
+    }
+
+    /// The filter bits followed by a single byte holding the number of hashes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut filter_block = self.bits.clone();
+        filter_block.push(self.hash_count as u8);
+        filter_block
+    }
+
+    pub fn decode(filter_block: &[u8]) -> Option<Self> {
+        let (hash_count, bits) = filter_block.split_last()?;
+        if bits.is_empty() {
+            return None;
+        }
+        Some(BloomFilter {
+            bits: bits.to_vec(),
+            hash_count: *hash_count as u32,
+        })
+    }
+
+    //Double hashing, deriving each probe from the one hash by adding a rotation of it
+    fn bit_positions(&self, key_hash: u64) -> impl Iterator<Item = usize> {
+        let bit_count = self.bits.len() as u64 * 8;
+        let delta = key_hash.rotate_right(17);
+        (0..self.hash_count as u64).map(move |probe| {
+            (key_hash.wrapping_add(probe.wrapping_mul(delta)) % bit_count) as usize
+        })
+    }
+}
+
+/// FNV-1a hash of the key, as recorded in a [`BloomFilter`].
+pub fn bloom_hash(key: &str) -> u64 {
+    key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bloom_hash, BloomFilter};
+
+    fn bloom_filter_of_keys(bits_per_key: usize, keys: &[String]) -> BloomFilter {
+        let key_hashes: Vec<u64> = keys.iter().map(|key| bloom_hash(key)).collect();
+        BloomFilter::new(bits_per_key, &key_hashes)
+    }
+
+    #[test]
+    fn bloom_filter_contains_every_inserted_key() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let bloom_filter = bloom_filter_of_keys(10, &keys);
+        assert!(keys.iter().all(|key| bloom_filter.may_contain(key)));
+    }
+
+    #[test]
+    fn bloom_filter_rules_out_most_absent_keys() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let bloom_filter = bloom_filter_of_keys(10, &keys);
+        let false_positives = (0..10000)
+            .filter(|i| bloom_filter.may_contain(&format!("absent{}", i)))
+            .count();
+        //Around 1% is expected at ten bits per key
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn bloom_filter_round_trip() {
+        let keys = vec!["A".to_string(), "B".to_string()];
+        let bloom_filter = bloom_filter_of_keys(10, &keys);
+        assert_eq!(
+            BloomFilter::decode(&bloom_filter.encode()),
+            Some(bloom_filter)
+        );
+        assert_eq!(BloomFilter::decode(&[]), None);
+    }
+}
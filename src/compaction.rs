@@ -0,0 +1,390 @@
+use std::ops::Range;
+
+use crate::{
+    error::Result,
+    manifest::{Manifest, ManifestEdit, ManifestFile},
+    memtable_config::MemtableConfig,
+    memtable_entry::{MemtableEntry, SequencedEntry},
+    memtable_file::{leveled_memtable_file_path, MemtableFile},
+    memtable_file_iterator::MemtableFileIterator,
+    memtable_write_to_file::write_data_to_file,
+    merging_iterator::{EntryIterator, MergingIterator},
+};
+
+//A file joins a run of similarly sized files when its size is within these ratios of the
+//run's average size
+const SIMILAR_SIZE_LOWER_RATIO: f64 = 0.5;
+const SIMILAR_SIZE_UPPER_RATIO: f64 = 1.5;
+
+/// Decides when and how memtable files are merged, registered through
+/// [`MemtableConfig::compaction_strategy`].
+pub trait CompactionStrategy: Send + Sync {
+    /// Compacts the memtable files until the strategy's limits are met. Files are grouped by
+    /// level, with level 0 ordered from oldest to most recently written and every deeper level
+    /// ordered by key. Output files take new numbers from the manifest, and each compaction
+    /// commits by appending a single edit to it. Inputs are taken out of the levels but left
+    /// on disk, for the caller to remove once no reader still holds them. A failed compaction
+    /// leaves the files it was merging in place.
+    fn compact(
+        &self,
+        config: &MemtableConfig,
+        memtable_file_levels: &mut Vec<Vec<MemtableFile>>,
+        manifest: &mut Manifest,
+    ) -> Result<()>;
+}
+
+/// Keeps every file in level 0, merging runs of consecutively written files of similar size
+/// once a run reaches the compaction threshold.
+pub struct SizeTieredCompaction;
+
+impl CompactionStrategy for SizeTieredCompaction {
+    fn compact(
+        &self,
+        config: &MemtableConfig,
+        memtable_file_levels: &mut Vec<Vec<MemtableFile>>,
+        manifest: &mut Manifest,
+    ) -> Result<()> {
+        let has_deeper_files = memtable_file_levels[1..]
+            .iter()
+            .any(|memtable_files| !memtable_files.is_empty());
+        let memtable_files = &mut memtable_file_levels[0];
+        while let Some(files_to_compact) =
+            find_size_tiered_compaction(memtable_files, config.compaction_threshold)
+        {
+            let includes_oldest_file = files_to_compact.start == 0 && !has_deeper_files;
+            let compacted_file = compact_memtable_files(
+                config,
+                &memtable_files[files_to_compact.clone()],
+                includes_oldest_file,
+                manifest,
+            )?;
+            memtable_files.splice(files_to_compact, compacted_file);
+        }
+        Ok(())
+    }
+}
+
+/// Finds the most recently written run of consecutive memtable files whose sizes are similar,
+/// once it holds at least `compaction_threshold` files. Files are given oldest first and the
+/// returned range indexes into them.
+///
+/// Only consecutive files are merged, as without versions on entries the merged file has to
+/// take the place of its inputs in write order for newer files to keep shadowing it.
+pub fn find_size_tiered_compaction(
+    memtable_files: &[MemtableFile],
+    compaction_threshold: usize,
+) -> Option<Range<usize>> {
+    let compaction_threshold = compaction_threshold.max(2);
+    let mut run_end = memtable_files.len();
+    let mut run_total_size = 0;
+    for file_index in (0..memtable_files.len()).rev() {
+        let file_size = memtable_files[file_index].data_length;
+        let run_length = run_end - file_index - 1;
+        if run_length > 0 && !is_similar_size(file_size, run_total_size / run_length) {
+            if run_length >= compaction_threshold {
+                return Some(file_index + 1..run_end);
+            }
+            run_end = file_index + 1;
+            run_total_size = 0;
+        }
+        run_total_size += file_size;
+    }
+    if run_end >= compaction_threshold {
+        return Some(0..run_end);
+    }
+    None
+}
+
+/// Merges the memtable files of level 0, given oldest first, into a single file that takes
+/// their place in write order, keeping only the newest entry for each key. Tombstones are
+/// dropped when the oldest input is the oldest file in the table, as no older value remains
+/// for them to hide, which is also when merge operands with no value under them are applied
+/// to none. Returns `None` when nothing is left to write. The inputs stay on disk.
+pub fn compact_memtable_files(
+    config: &MemtableConfig,
+    memtable_files: &[MemtableFile],
+    includes_oldest_file: bool,
+    manifest: &mut Manifest,
+) -> Result<Option<MemtableFile>> {
+    let sources = memtable_files
+        .iter()
+        .rev()
+        .map(|memtable_file| iterate_memtable_file(config, memtable_file))
+        .collect::<Result<Vec<EntryIterator>>>()?;
+    let mut merged_entries = MergingIterator::new(
+        sources,
+        config.merge_operator.clone(),
+        !includes_oldest_file,
+    )
+    .filter(|key_entry_pair| {
+        let is_tombstone = key_entry_pair
+            .as_ref()
+            .is_ok_and(|(_, sequenced_entry)| sequenced_entry.entry == MemtableEntry::Tombstone);
+        !(includes_oldest_file && is_tombstone)
+    })
+    .peekable();
+
+    let compacted_file = match merged_entries.peek() {
+        Some(_) => Some(write_compacted_memtable_file(
+            config,
+            manifest.new_file_number(),
+            0,
+            merged_entries,
+        )?),
+        None => None,
+    };
+
+    manifest.commit(ManifestEdit {
+        removed_files: memtable_files
+            .iter()
+            .map(|memtable_file| (memtable_file.level, memtable_file.file_number))
+            .collect(),
+        added_files: compacted_file.iter().map(ManifestFile::from).collect(),
+        ..Default::default()
+    })?;
+    Ok(compacted_file)
+}
+
+/// Reads every entry of the memtable file, as a source for merging.
+pub fn iterate_memtable_file<'a>(
+    config: &MemtableConfig,
+    memtable_file: &'a MemtableFile,
+) -> Result<EntryIterator<'a>> {
+    let memtable_file_iterator = MemtableFileIterator::new(
+        config,
+        &memtable_file.file_path,
+        memtable_file.format_version,
+        0,
+        memtable_file.data_length,
+    )?;
+    Ok(Box::new(memtable_file_iterator))
+}
+
+/// Writes the entries, given in key order, to a new file in the given level. As with every
+/// memtable file, a crash never leaves it partly written under a memtable file name, and until
+/// the compaction writing it commits to the manifest it is removed on open.
+pub fn write_compacted_memtable_file<I>(
+    config: &MemtableConfig,
+    file_number: u64,
+    level: usize,
+    entries: I,
+) -> Result<MemtableFile>
+where
+    I: IntoIterator<Item = Result<(String, SequencedEntry)>>,
+{
+    let file_path = leveled_memtable_file_path(&config.data_directory, file_number, level);
+    let written_data = write_data_to_file(config, &file_path, entries)?;
+    Ok(MemtableFile {
+        file_number,
+        level,
+        file_path,
+        key_offsets: written_data.key_offsets,
+        data_length: written_data.data_length,
+        smallest_key: written_data.smallest_key,
+        largest_key: written_data.largest_key,
+        bloom_filter: written_data.bloom_filter,
+        format_version: written_data.format_version,
+    })
+}
+
+fn is_similar_size(file_size: usize, average_size: usize) -> bool {
+    let file_size = file_size as f64;
+    let average_size = average_size as f64;
+    file_size >= average_size * SIMILAR_SIZE_LOWER_RATIO
+        && file_size <= average_size * SIMILAR_SIZE_UPPER_RATIO
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        manifest::{read_manifest, Manifest, ManifestState},
+        memtable::Memtable,
+        memtable_config::MemtableConfig,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+        memtable_file::MemtableFile,
+        memtable_file_index::BINARY_FORMAT_VERSION,
+        memtable_file_iterator::MemtableFileIterator,
+        test_utils::test_data_directory,
+    };
+
+    use super::{compact_memtable_files, find_size_tiered_compaction};
+
+    fn memtable_files_of_sizes(sizes: &[usize]) -> Vec<MemtableFile> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| MemtableFile {
+                file_number: i as u64 + 1,
+                level: 0,
+                file_path: PathBuf::new(),
+                key_offsets: None,
+                data_length: *size,
+                smallest_key: String::new(),
+                largest_key: String::new(),
+                bloom_filter: None,
+                format_version: BINARY_FORMAT_VERSION,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_size_tiered_compaction_below_threshold() {
+        let memtable_files = memtable_files_of_sizes(&[100, 10, 10, 10]);
+        assert_eq!(find_size_tiered_compaction(&memtable_files, 4), None);
+    }
+
+    #[test]
+    fn find_size_tiered_compaction_newest_similar_run() {
+        let memtable_files = memtable_files_of_sizes(&[100, 10, 12, 9, 11]);
+        assert_eq!(find_size_tiered_compaction(&memtable_files, 4), Some(1..5));
+    }
+
+    #[test]
+    fn find_size_tiered_compaction_older_similar_run() {
+        let memtable_files = memtable_files_of_sizes(&[100, 90, 110, 100, 10, 10]);
+        assert_eq!(find_size_tiered_compaction(&memtable_files, 4), Some(0..4));
+    }
+
+    #[test]
+    fn compact_memtable_files_keeps_newest_entries() {
+        let data_directory = test_data_directory("compact_memtable_files_newest_entries");
+        let config = MemtableConfig::new(2, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config.clone()).unwrap();
+        let mut memtable_files = vec![];
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable_files.push(memtable.insert("B".to_string(), "1").unwrap().unwrap());
+        memtable.insert("A".to_string(), "2").unwrap();
+        memtable_files.push(memtable.remove("C".to_string()).unwrap().unwrap());
+        memtable.remove("B".to_string()).unwrap();
+        memtable_files.push(memtable.insert("D".to_string(), "3").unwrap().unwrap());
+
+        //Numbers from 100 on are clear of those the memtable gives its files
+        let manifest_state = ManifestState {
+            next_file_number: 100,
+            ..Default::default()
+        };
+        let mut manifest = Manifest::create(&data_directory, &manifest_state).unwrap();
+
+        let compacted_file =
+            compact_memtable_files(&config, &memtable_files[1..], false, &mut manifest)
+                .unwrap()
+                .unwrap();
+        assert_eq!(compacted_file.file_number, 100);
+        let manifest_state = read_manifest(&data_directory).unwrap().unwrap();
+        assert!(manifest_state.is_live(0, 100));
+        assert!(!manifest_state.is_live(0, memtable_files[1].file_number));
+        assert!(!manifest_state.is_live(0, memtable_files[2].file_number));
+        let entries: Vec<(String, SequencedEntry)> = MemtableFileIterator::new(
+            &config,
+            &compacted_file.file_path,
+            compacted_file.format_version,
+            0,
+            compacted_file.data_length,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        //Entries keep the sequence numbers of the writes that made them
+        let sequenced_entry = |key: &str, sequence_number, entry| {
+            let sequenced_entry = SequencedEntry {
+                sequence_number,
+                entry,
+            };
+            (key.to_string(), sequenced_entry)
+        };
+        assert_eq!(
+            entries,
+            vec![
+                sequenced_entry("A", 3, MemtableEntry::Value("2".to_string())),
+                sequenced_entry("B", 5, MemtableEntry::Tombstone),
+                sequenced_entry("C", 4, MemtableEntry::Tombstone),
+                sequenced_entry("D", 6, MemtableEntry::Value("3".to_string())),
+            ]
+        );
+
+        let fully_compacted_file = compact_memtable_files(
+            &config,
+            &[memtable_files.remove(0), compacted_file],
+            true,
+            &mut manifest,
+        )
+        .unwrap()
+        .unwrap();
+        let keys: Vec<String> = MemtableFileIterator::new(
+            &config,
+            &fully_compacted_file.file_path,
+            fully_compacted_file.format_version,
+            0,
+            fully_compacted_file.data_length,
+        )
+        .unwrap()
+        .map(|key_entry_pair| key_entry_pair.unwrap().0)
+        .collect();
+        assert_eq!(keys, vec!["A", "D"]);
+    }
+
+    #[test]
+    fn compact_memtable_files_drops_expired_values() {
+        let data_directory = test_data_directory("compact_memtable_files_expired_values");
+        let config = MemtableConfig::new(2, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config.clone()).unwrap();
+        let expiring_value = |sequence_number, expires_at| SequencedEntry {
+            sequence_number,
+            entry: MemtableEntry::ExpiringValue("1".to_string(), expires_at),
+        };
+        let mut memtable_files = vec![];
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable_files.push(
+            memtable
+                .insert_entry("B".to_string(), expiring_value(2, u64::MAX))
+                .unwrap()
+                .unwrap(),
+        );
+        memtable
+            .insert_entry("A".to_string(), expiring_value(3, 0))
+            .unwrap();
+        memtable_files.push(memtable.insert("C".to_string(), "1").unwrap().unwrap());
+        let manifest_state = ManifestState {
+            next_file_number: 100,
+            ..Default::default()
+        };
+        let mut manifest = Manifest::create(&data_directory, &manifest_state).unwrap();
+
+        let compacted_entries = |includes_oldest_file, manifest: &mut Manifest| {
+            let compacted_file =
+                compact_memtable_files(&config, &memtable_files, includes_oldest_file, manifest)
+                    .unwrap()
+                    .unwrap();
+            MemtableFileIterator::new(
+                &config,
+                &compacted_file.file_path,
+                compacted_file.format_version,
+                0,
+                compacted_file.data_length,
+            )
+            .unwrap()
+            .map(|key_entry_pair| key_entry_pair.map(|(key, entry)| (key, entry.entry)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+        };
+        //An expired value still hides older values in files not being compacted
+        assert_eq!(
+            compacted_entries(false, &mut manifest),
+            vec![
+                ("A".to_string(), MemtableEntry::Tombstone),
+                (
+                    "B".to_string(),
+                    MemtableEntry::ExpiringValue("1".to_string(), u64::MAX)
+                ),
+                ("C".to_string(), MemtableEntry::Value("1".to_string())),
+            ]
+        );
+        let keys: Vec<String> = compacted_entries(true, &mut manifest)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["B", "C"]);
+    }
+}
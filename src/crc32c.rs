@@ -0,0 +1,54 @@
+//Reversed Castagnoli polynomial, as used by iSCSI and LevelDB
+const CASTAGNOLI_POLYNOMIAL: u32 = 0x82f6_3b78;
+const CRC32C_TABLE: [u32; 256] = create_crc32c_table();
+
+/// CRC32C checksum of the bytes, stored alongside blocks and write ahead log records so a
+/// flipped or torn byte is detected when they are read back.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+const fn create_crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CASTAGNOLI_POLYNOMIAL,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc32c;
+
+    #[test]
+    fn crc32c_matches_known_values() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+        assert_eq!(crc32c(&[0xff; 32]), 0x62a8_ab43);
+    }
+
+    #[test]
+    fn crc32c_detects_single_flipped_bit() {
+        let bytes = b"key:value\n".to_vec();
+        let checksum = crc32c(&bytes);
+        for bit in 0..bytes.len() * 8 {
+            let mut flipped_bytes = bytes.clone();
+            flipped_bytes[bit / 8] ^= 1 << (bit % 8);
+            assert_ne!(crc32c(&flipped_bytes), checksum);
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use std::io::Read;
+
+use crate::{
+    crc32c::crc32c,
+    error::{Error, Result},
+    memtable_entry::{MemtableEntry, SequencedEntry},
+    memtable_file_index::{
+        check_block_checksum, has_block_checksums, has_sequence_numbers, BLOCK_CHECKSUM_SIZE,
+    },
+    memtable_write_to_file::{
+        EXPIRING_VALUE_ENTRY_TYPE, MERGE_ENTRY_TYPE, TOMBSTONE_ENTRY_TYPE, VALUE_ENTRY_TYPE,
+    },
+    varint::{decode_varint, encode_varint},
+};
+
+const RESTART_OFFSET_SIZE: usize = 4;
+const BLOCK_LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Builds a data block of key ordered entries. Each key is stored as the length of the prefix
+/// it shares with the key before it and the bytes that follow, except at every
+/// `restart_interval`th entry, a restart point whose key is stored in full so a reader can
+/// binary search the restart points without decoding the whole block. In format versions
+/// with sequence numbers, each entry's sequence number follows its key.
+pub struct DataBlockBuilder {
+    contents: Vec<u8>,
+    restart_offsets: Vec<u32>,
+    restart_interval: usize,
+    entries_since_restart: usize,
+    last_key: Vec<u8>,
+    has_sequence_numbers: bool,
+}
+
+impl DataBlockBuilder {
+    pub fn new(restart_interval: usize, format_version: u32) -> Self {
+        DataBlockBuilder {
+            contents: vec![],
+            restart_offsets: vec![],
+            restart_interval,
+            entries_since_restart: 0,
+            last_key: vec![],
+            has_sequence_numbers: has_sequence_numbers(format_version),
+        }
+    }
+
+    pub fn add(&mut self, key: &str, sequenced_entry: &SequencedEntry) {
+        let key = key.as_bytes();
+        let is_restart_point =
+            self.restart_offsets.is_empty() || self.entries_since_restart >= self.restart_interval;
+        let shared_length = match is_restart_point {
+            true => {
+                self.restart_offsets.push(self.contents.len() as u32);
+                self.entries_since_restart = 0;
+                0
+            }
+            false => self
+                .last_key
+                .iter()
+                .zip(key)
+                .take_while(|(last_key_byte, key_byte)| last_key_byte == key_byte)
+                .count(),
+        };
+        encode_varint(shared_length as u64, &mut self.contents);
+        encode_varint((key.len() - shared_length) as u64, &mut self.contents);
+        let entry_type = match sequenced_entry.entry {
+            MemtableEntry::Value(_) => VALUE_ENTRY_TYPE,
+            MemtableEntry::Tombstone => TOMBSTONE_ENTRY_TYPE,
+            MemtableEntry::Merge(_) => MERGE_ENTRY_TYPE,
+            MemtableEntry::ExpiringValue(..) => EXPIRING_VALUE_ENTRY_TYPE,
+        };
+        self.contents.push(entry_type);
+        self.contents.extend_from_slice(&key[shared_length..]);
+        if self.has_sequence_numbers {
+            encode_varint(sequenced_entry.sequence_number, &mut self.contents);
+        }
+        if let MemtableEntry::ExpiringValue(_, expires_at) = &sequenced_entry.entry {
+            encode_varint(*expires_at, &mut self.contents);
+        }
+        if let MemtableEntry::Value(value)
+        | MemtableEntry::Merge(value)
+        | MemtableEntry::ExpiringValue(value, _) = &sequenced_entry.entry
+        {
+            encode_varint(value.len() as u64, &mut self.contents);
+            self.contents.extend_from_slice(value.as_bytes());
+        }
+        self.entries_since_restart += 1;
+        self.last_key = key.to_vec();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.restart_offsets.is_empty()
+    }
+
+    /// Size of the block were it finished now.
+    pub fn estimated_size(&self) -> usize {
+        self.contents.len() + (self.restart_offsets.len() + 1) * RESTART_OFFSET_SIZE
+    }
+
+    /// Returns the finished block, the entries followed by the restart offsets and their
+    /// count, and leaves the builder empty for the next block.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut contents = std::mem::take(&mut self.contents);
+        for restart_offset in &self.restart_offsets {
+            contents.extend_from_slice(&restart_offset.to_le_bytes());
+        }
+        contents.extend_from_slice(&(self.restart_offsets.len() as u32).to_le_bytes());
+        self.restart_offsets.clear();
+        self.entries_since_restart = 0;
+        self.last_key.clear();
+        contents
+    }
+}
+
+/// A data block read back from a memtable file.
+pub struct DataBlock {
+    contents: Vec<u8>,
+    restart_offsets: Vec<usize>,
+    //Where the entries end and the restart offsets begin
+    entries_length: usize,
+    has_sequence_numbers: bool,
+}
+
+impl DataBlock {
+    pub fn decode(contents: Vec<u8>, format_version: u32) -> Result<Self> {
+        let malformed_block = || Error::Corruption("data block is malformed".to_owned());
+        let restart_count_position = contents
+            .len()
+            .checked_sub(RESTART_OFFSET_SIZE)
+            .ok_or_else(malformed_block)?;
+        let read_u32 = |position: usize| {
+            u32::from_le_bytes(
+                contents[position..position + RESTART_OFFSET_SIZE]
+                    .try_into()
+                    .unwrap(),
+            ) as usize
+        };
+        let restart_count = read_u32(restart_count_position);
+        let entries_length = restart_count
+            .checked_mul(RESTART_OFFSET_SIZE)
+            .and_then(|restarts_length| restart_count_position.checked_sub(restarts_length))
+            .ok_or_else(malformed_block)?;
+        let restart_offsets: Vec<usize> = (0..restart_count)
+            .map(|restart_index| read_u32(entries_length + restart_index * RESTART_OFFSET_SIZE))
+            .collect();
+        if restart_offsets
+            .iter()
+            .any(|restart_offset| *restart_offset >= entries_length.max(1))
+        {
+            return Err(malformed_block());
+        }
+        Ok(DataBlock {
+            contents,
+            restart_offsets,
+            entries_length,
+            has_sequence_numbers: has_sequence_numbers(format_version),
+        })
+    }
+
+    /// Every entry in the block, in key order.
+    pub fn entries(&self) -> Result<Vec<(String, SequencedEntry)>> {
+        let mut entries = vec![];
+        let mut position = 0;
+        let mut key = vec![];
+        while position < self.entries_length {
+            let entry = self.read_entry(&mut position, &mut key)?;
+            entries.push((bytes_to_string(&key)?, entry));
+        }
+        Ok(entries)
+    }
+
+    /// Binary searches the restart points for the last one at or before the key, then reads
+    /// on from there until reaching the key or passing where it would be.
+    pub fn find(&self, key_to_find: &str) -> Result<Option<SequencedEntry>> {
+        let key_to_find = key_to_find.as_bytes();
+        let mut restart_keys_at_or_before = 0;
+        let (mut lower, mut upper) = (0, self.restart_offsets.len());
+        while lower < upper {
+            let middle = (lower + upper) / 2;
+            let mut position = self.restart_offsets[middle];
+            let mut restart_key = vec![];
+            self.read_entry(&mut position, &mut restart_key)?;
+            match restart_key.as_slice() <= key_to_find {
+                true => {
+                    restart_keys_at_or_before = middle + 1;
+                    lower = middle + 1;
+                }
+                false => upper = middle,
+            }
+        }
+        let mut position = match restart_keys_at_or_before {
+            0 => return Ok(None),
+            _ => self.restart_offsets[restart_keys_at_or_before - 1],
+        };
+        let mut key = vec![];
+        while position < self.entries_length {
+            let entry = self.read_entry(&mut position, &mut key)?;
+            if key.as_slice() >= key_to_find {
+                return Ok((key == key_to_find).then_some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    //Reads the entry at `position`, rebuilding its key from the previous one held in `key`
+    fn read_entry(&self, position: &mut usize, key: &mut Vec<u8>) -> Result<SequencedEntry> {
+        let malformed_entry = || Error::Corruption("data block entry is malformed".to_owned());
+        let entries = &self.contents[..self.entries_length];
+        let shared_length = decode_varint(entries, position).ok_or_else(malformed_entry)? as usize;
+        let unshared_length =
+            decode_varint(entries, position).ok_or_else(malformed_entry)? as usize;
+        let entry_type = *entries.get(*position).ok_or_else(malformed_entry)?;
+        *position += 1;
+        let unshared_key =
+            read_bytes(entries, position, unshared_length).ok_or_else(malformed_entry)?;
+        if shared_length > key.len() {
+            return Err(malformed_entry());
+        }
+        key.truncate(shared_length);
+        key.extend_from_slice(unshared_key);
+        let sequence_number = match self.has_sequence_numbers {
+            true => decode_varint(entries, position).ok_or_else(malformed_entry)?,
+            false => 0,
+        };
+        let entry = match entry_type {
+            VALUE_ENTRY_TYPE | MERGE_ENTRY_TYPE | EXPIRING_VALUE_ENTRY_TYPE => {
+                let expires_at = match entry_type {
+                    EXPIRING_VALUE_ENTRY_TYPE => {
+                        Some(decode_varint(entries, position).ok_or_else(malformed_entry)?)
+                    }
+                    _ => None,
+                };
+                let value_length =
+                    decode_varint(entries, position).ok_or_else(malformed_entry)? as usize;
+                let value =
+                    read_bytes(entries, position, value_length).ok_or_else(malformed_entry)?;
+                let value = bytes_to_string(value)?;
+                match (entry_type, expires_at) {
+                    (_, Some(expires_at)) => MemtableEntry::ExpiringValue(value, expires_at),
+                    (VALUE_ENTRY_TYPE, None) => MemtableEntry::Value(value),
+                    _ => MemtableEntry::Merge(value),
+                }
+            }
+            TOMBSTONE_ENTRY_TYPE => MemtableEntry::Tombstone,
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "unknown memtable file entry type {}",
+                    entry_type
+                )))
+            }
+        };
+        Ok(SequencedEntry {
+            sequence_number,
+            entry,
+        })
+    }
+}
+
+/// Frames a finished block for writing to a memtable file, preceded by its length so the
+/// blocks of a file can be read one after another, and in format versions with checksums
+/// followed by its CRC32C.
+pub fn create_framed_data_block(contents: &[u8], format_version: u32) -> Vec<u8> {
+    let mut framed_data_block =
+        Vec::with_capacity(BLOCK_LENGTH_PREFIX_SIZE + contents.len() + BLOCK_CHECKSUM_SIZE);
+    framed_data_block.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+    framed_data_block.extend_from_slice(contents);
+    if has_block_checksums(format_version) {
+        framed_data_block.extend_from_slice(&crc32c(contents).to_le_bytes());
+    }
+    framed_data_block
+}
+
+/// Reads the next framed block. Returns `None` when the reader is already at its end. The
+/// whole frame is read before its checksum is compared, so after a checksum mismatch the
+/// reader is left at the next block.
+pub fn read_framed_data_block<R: Read>(
+    reader: &mut R,
+    format_version: u32,
+) -> Result<Option<DataBlock>> {
+    let mut length_bytes = vec![];
+    reader
+        .by_ref()
+        .take(BLOCK_LENGTH_PREFIX_SIZE as u64)
+        .read_to_end(&mut length_bytes)?;
+    if length_bytes.is_empty() {
+        return Ok(None);
+    }
+    let cut_short_block = || Error::Corruption("data block is cut short".to_owned());
+    let length_bytes: [u8; BLOCK_LENGTH_PREFIX_SIZE] =
+        length_bytes.try_into().map_err(|_| cut_short_block())?;
+    let block_length = u32::from_le_bytes(length_bytes) as usize;
+    let checksum_size = match has_block_checksums(format_version) {
+        true => BLOCK_CHECKSUM_SIZE,
+        false => 0,
+    };
+    let mut contents = vec![];
+    reader
+        .by_ref()
+        .take((block_length + checksum_size) as u64)
+        .read_to_end(&mut contents)?;
+    if contents.len() != block_length + checksum_size {
+        return Err(cut_short_block());
+    }
+    let stored_checksum = contents.split_off(block_length);
+    if checksum_size > 0 {
+        check_block_checksum(&contents, &stored_checksum)?;
+    }
+    DataBlock::decode(contents, format_version).map(Some)
+}
+
+fn read_bytes<'a>(bytes: &'a [u8], position: &mut usize, length: usize) -> Option<&'a [u8]> {
+    let read_bytes = bytes.get(*position..position.checked_add(length)?)?;
+    *position += length;
+    Some(read_bytes)
+}
+
+fn bytes_to_string(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| Error::Corruption("data block entry is not valid UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+        memtable_file_index::{CHECKSUM_FORMAT_VERSION, SEQUENCE_FORMAT_VERSION},
+    };
+
+    use super::{DataBlock, DataBlockBuilder};
+
+    //Each key's entry has the key's index as its sequence number
+    fn build_data_block(restart_interval: usize, keys: &[String]) -> Vec<u8> {
+        let mut data_block_builder =
+            DataBlockBuilder::new(restart_interval, SEQUENCE_FORMAT_VERSION);
+        for (i, key) in keys.iter().enumerate() {
+            data_block_builder.add(key, &sequenced_entry(i as u64, key));
+        }
+        data_block_builder.finish()
+    }
+
+    fn sequenced_entry(sequence_number: u64, key: &str) -> SequencedEntry {
+        let entry = match sequence_number.is_multiple_of(10) {
+            true => MemtableEntry::Tombstone,
+            false => MemtableEntry::Value(key.to_uppercase()),
+        };
+        SequencedEntry {
+            sequence_number,
+            entry,
+        }
+    }
+
+    fn decode(contents: Vec<u8>) -> crate::error::Result<DataBlock> {
+        DataBlock::decode(contents, SEQUENCE_FORMAT_VERSION)
+    }
+
+    #[test]
+    fn data_block_round_trip() {
+        let keys: Vec<String> = (0..50).map(|i| format!("user/{:03}/profile", i)).collect();
+        let data_block = decode(build_data_block(16, &keys)).unwrap();
+        assert_eq!(data_block.restart_offsets.len(), 4);
+        let entries = data_block.entries().unwrap();
+        assert_eq!(entries.len(), 50);
+        assert_eq!(
+            entries[10],
+            (keys[10].clone(), sequenced_entry(10, &keys[10]))
+        );
+        assert_eq!(
+            entries[49],
+            (keys[49].clone(), sequenced_entry(49, &keys[49]))
+        );
+    }
+
+    #[test]
+    fn data_block_shares_key_prefixes() {
+        let mut data_block_builder = DataBlockBuilder::new(16, CHECKSUM_FORMAT_VERSION);
+        for i in 0..100 {
+            let key = format!("user/profile/settings/{:03}", i);
+            data_block_builder.add(&key, &sequenced_entry(0, &key));
+        }
+        //Each key is 25 bytes, but past a restart point only its last digits are stored
+        let data_block = data_block_builder.finish();
+        assert!(
+            data_block.len() < 100 * 25 / 3,
+            "{} bytes",
+            data_block.len()
+        );
+        assert!(data_block_builder.is_empty());
+    }
+
+    #[test]
+    fn data_block_find_uses_restart_points() {
+        let keys: Vec<String> = (0..50).map(|i| format!("key{:02}", i * 2)).collect();
+        for restart_interval in [1, 3, 16, 100] {
+            let data_block = decode(build_data_block(restart_interval, &keys)).unwrap();
+            assert_eq!(
+                data_block.find("key00").unwrap(),
+                Some(sequenced_entry(0, "key00"))
+            );
+            assert_eq!(
+                data_block.find("key42").unwrap(),
+                Some(sequenced_entry(21, "key42"))
+            );
+            assert_eq!(
+                data_block.find("key98").unwrap(),
+                Some(sequenced_entry(49, "key98"))
+            );
+            for missing_key in ["a", "key01", "key43", "key99", "z"] {
+                assert_eq!(data_block.find(missing_key).unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn data_block_rejects_malformed_contents() {
+        assert!(matches!(decode(vec![1, 0]), Err(Error::Corruption(_))));
+        assert!(matches!(
+            decode(vec![0, 0, 0, 0, 5, 0, 0, 0]),
+            Err(Error::Corruption(_))
+        ));
+        let keys = vec!["A".to_owned(), "B".to_owned()];
+        let mut contents = build_data_block(16, &keys);
+        contents[0] = 4;
+        let data_block = decode(contents).unwrap();
+        assert!(matches!(data_block.entries(), Err(Error::Corruption(_))));
+    }
+}
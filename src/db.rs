@@ -0,0 +1,142 @@
+use std::{ops::Deref, sync::Arc};
+
+use crate::{
+    error::Result,
+    memtable_config::MemtableConfig,
+    sorted_string_table::{SortedStringTable, StringLike},
+};
+
+/// A handle to a table that can be cloned and sent to other threads, each clone reading and
+/// writing the same table. Finds and ranges run alongside inserts, only waiting on them while
+/// an entry is copied in or out of the memtable. The table is closed once the last handle is
+/// dropped.
+pub struct Db<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    sorted_string_table: Arc<SortedStringTable<K, V>>,
+}
+
+impl<K, V> Db<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    /// Opens the table held in the configured data directory, see [`SortedStringTable::open`].
+    pub fn new(memtable_config: MemtableConfig) -> Result<Self> {
+        Ok(Db::from(SortedStringTable::new(memtable_config)?))
+    }
+
+    /// See [`SortedStringTable::open`].
+    pub fn open(data_directory: &str, memtable_config: MemtableConfig) -> Result<Self> {
+        Ok(Db::from(SortedStringTable::open(
+            data_directory,
+            memtable_config,
+        )?))
+    }
+}
+
+impl<K, V> From<SortedStringTable<K, V>> for Db<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    fn from(sorted_string_table: SortedStringTable<K, V>) -> Self {
+        Db {
+            sorted_string_table: Arc::new(sorted_string_table),
+        }
+    }
+}
+
+impl<K, V> Clone for Db<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    fn clone(&self) -> Self {
+        Db {
+            sorted_string_table: Arc::clone(&self.sorted_string_table),
+        }
+    }
+}
+
+impl<K, V> Deref for Db<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    type Target = SortedStringTable<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sorted_string_table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{memtable_config::MemtableConfig, test_utils::test_data_directory};
+
+    use super::Db;
+
+    #[test]
+    fn db_finds_values_while_other_threads_insert() {
+        let data_directory = test_data_directory("db_concurrent_find_and_insert");
+        let db = Db::<String, String>::new(MemtableConfig::new(16, &data_directory)).unwrap();
+        db.insert("A".to_string(), "0".to_string()).unwrap();
+
+        let writer_threads: Vec<_> = (0..4)
+            .map(|thread_index| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for key_index in 0..100 {
+                        let key = format!("{thread_index}-{key_index:03}");
+                        db.insert(key, key_index.to_string()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let reader_threads: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert_eq!(db.find(&"A".to_string()).unwrap(), Some("0".to_string()));
+                        for key_value_pair in db.range("0".to_string()..="A".to_string()) {
+                            key_value_pair.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in writer_threads.into_iter().chain(reader_threads) {
+            thread.join().unwrap();
+        }
+
+        db.wait_for_flush().unwrap();
+        assert_eq!(db.count_live_keys().unwrap(), 401);
+        for thread_index in 0..4 {
+            let key = format!("{thread_index}-099");
+            assert_eq!(db.find(&key).unwrap(), Some("99".to_string()));
+        }
+    }
+
+    #[test]
+    fn db_reopens_with_values_written_from_clones() {
+        let data_directory = test_data_directory("db_reopens_with_clone_writes");
+        let db = Db::<&str, &str>::new(MemtableConfig::new(2, &data_directory)).unwrap();
+        let cloned_db = db.clone();
+        thread::spawn(move || cloned_db.insert("A", "1").unwrap())
+            .join()
+            .unwrap();
+        db.insert("B", "2").unwrap();
+        db.insert("C", "3").unwrap();
+        drop(db);
+
+        let reopened_db = Db::<&str, &str>::new(MemtableConfig::new(2, &data_directory)).unwrap();
+        assert_eq!(reopened_db.find(&"A").unwrap(), Some("1".to_string()));
+        assert_eq!(reopened_db.find(&"C").unwrap(), Some("3".to_string()));
+    }
+}
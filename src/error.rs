@@ -0,0 +1,53 @@
+use std::{fmt, io};
+
+/// Errors returned when reading or writing the table's files, or when opening it with a
+/// configuration it cannot use.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    //A file's contents do not follow the format it claims to be written in
+    Corruption(String),
+    //A block or write ahead log record does not match the checksum stored with it
+    ChecksumMismatch(String),
+    InvalidConfiguration(String),
+    //A transaction read a key that was written by another after its snapshot was taken
+    Conflict(String),
+    //The merge operator could not apply a merge operand to the value or operand before it
+    MergeFailed(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(formatter, "I/O error: {}", error),
+            Error::Corruption(message) => write!(formatter, "corruption: {}", message),
+            Error::ChecksumMismatch(message) => write!(formatter, "checksum mismatch: {}", message),
+            Error::InvalidConfiguration(message) => {
+                write!(formatter, "invalid configuration: {}", message)
+            }
+            Error::Conflict(message) => write!(formatter, "conflict: {}", message),
+            Error::MergeFailed(message) => write!(formatter, "merge failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Corruption(_)
+            | Error::ChecksumMismatch(_)
+            | Error::InvalidConfiguration(_)
+            | Error::Conflict(_)
+            | Error::MergeFailed(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
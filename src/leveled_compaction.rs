@@ -0,0 +1,330 @@
+use crate::{
+    compaction::{iterate_memtable_file, write_compacted_memtable_file, CompactionStrategy},
+    error::Result,
+    manifest::{Manifest, ManifestEdit, ManifestFile},
+    memtable_config::MemtableConfig,
+    memtable_entry::MemtableEntry,
+    memtable_file::MemtableFile,
+    merging_iterator::{EntryIterator, MergingIterator},
+};
+
+/// Organises memtable files into levels. Level 0 holds flushed files, whose key ranges may
+/// overlap, and once it reaches the compaction threshold its files are merged into level 1.
+/// Files in level 1 and below never overlap one another, so a lookup reads at most one file
+/// in each of them, and each level may grow `level_size_ratio` times larger than the one
+/// above before a file is merged down into the next.
+pub struct LeveledCompaction {
+    //Total data length level 1 may hold before a file is merged into level 2
+    pub level_one_size: usize,
+    pub level_size_ratio: usize,
+    //Data length after which compaction output moves on to a new file
+    pub target_file_size: usize,
+}
+
+impl Default for LeveledCompaction {
+    fn default() -> Self {
+        LeveledCompaction {
+            level_one_size: 64 * 1024,
+            level_size_ratio: 10,
+            target_file_size: 16 * 1024,
+        }
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    fn compact(
+        &self,
+        config: &MemtableConfig,
+        memtable_file_levels: &mut Vec<Vec<MemtableFile>>,
+        manifest: &mut Manifest,
+    ) -> Result<()> {
+        loop {
+            if memtable_file_levels[0].len() >= config.compaction_threshold.max(1) {
+                //Newest first, so newer entries shadow older ones when merged
+                let level_zero_file_indices: Vec<usize> =
+                    (0..memtable_file_levels[0].len()).rev().collect();
+                self.compact_into_next_level(
+                    config,
+                    memtable_file_levels,
+                    manifest,
+                    0,
+                    &level_zero_file_indices,
+                )?;
+                continue;
+            }
+            let oversized_level = (1..memtable_file_levels.len()).find(|level| {
+                level_size(&memtable_file_levels[*level]) > self.max_level_size(*level)
+            });
+            match oversized_level {
+                Some(level) => {
+                    //The oldest file in the level has waited longest to move down
+                    let (oldest_file_index, _) = memtable_file_levels[level]
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, memtable_file)| memtable_file.file_number)
+                        .unwrap();
+                    self.compact_into_next_level(
+                        config,
+                        memtable_file_levels,
+                        manifest,
+                        level,
+                        &[oldest_file_index],
+                    )?;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl LeveledCompaction {
+    fn max_level_size(&self, level: usize) -> usize {
+        self.level_one_size * self.level_size_ratio.pow(level as u32 - 1)
+    }
+
+    /// Merges the files of `level` at the given indices, newest first, with the files in the
+    /// level below whose key ranges overlap theirs. The output replaces those overlapping
+    /// files, split into files of around the target size so the level below stays free of
+    /// overlaps. The levels are only changed once the output is written and committed to the
+    /// manifest.
+    fn compact_into_next_level(
+        &self,
+        config: &MemtableConfig,
+        memtable_file_levels: &mut Vec<Vec<MemtableFile>>,
+        manifest: &mut Manifest,
+        level: usize,
+        upper_file_indices: &[usize],
+    ) -> Result<()> {
+        let next_level = level + 1;
+        if memtable_file_levels.len() == next_level {
+            memtable_file_levels.push(vec![]);
+        }
+        //The merge borrows the files it reads, so it ends before the levels are changed
+        let (smallest_key, largest_key, output_files) = {
+            let upper_files: Vec<&MemtableFile> = upper_file_indices
+                .iter()
+                .map(|file_index| &memtable_file_levels[level][*file_index])
+                .collect();
+            let smallest_key = upper_files
+                .iter()
+                .map(|memtable_file| memtable_file.smallest_key.clone())
+                .min()
+                .unwrap();
+            let largest_key = upper_files
+                .iter()
+                .map(|memtable_file| memtable_file.largest_key.clone())
+                .max()
+                .unwrap();
+            let lower_files: Vec<&MemtableFile> = memtable_file_levels[next_level]
+                .iter()
+                .filter(|memtable_file| {
+                    memtable_file.overlaps_key_range(&smallest_key, &largest_key)
+                })
+                .collect();
+            //Tombstones only need keeping while an older value may remain in a deeper level
+            let is_bottommost_level = memtable_file_levels[next_level + 1..]
+                .iter()
+                .all(|memtable_files| memtable_files.is_empty());
+
+            let mut sources = upper_files
+                .iter()
+                .map(|memtable_file| iterate_memtable_file(config, memtable_file))
+                .collect::<Result<Vec<EntryIterator>>>()?;
+            let lower_level_sources = lower_files
+                .iter()
+                .map(|memtable_file| iterate_memtable_file(config, memtable_file))
+                .collect::<Result<Vec<EntryIterator>>>()?;
+            sources.push(Box::new(lower_level_sources.into_iter().flatten()));
+            let mut merged_entries =
+                MergingIterator::new(sources, config.merge_operator.clone(), !is_bottommost_level)
+                    .filter(|key_entry_pair| {
+                        let is_tombstone =
+                            key_entry_pair.as_ref().is_ok_and(|(_, sequenced_entry)| {
+                                sequenced_entry.entry == MemtableEntry::Tombstone
+                            });
+                        !(is_bottommost_level && is_tombstone)
+                    })
+                    .peekable();
+            let mut output_files = vec![];
+            while merged_entries.peek().is_some() {
+                let mut output_size = 0;
+                let output_entries = std::iter::from_fn(|| {
+                    if output_size >= self.target_file_size {
+                        return None;
+                    }
+                    let key_entry_pair = merged_entries.next()?;
+                    if let Ok((key, entry)) = &key_entry_pair {
+                        output_size += approximate_line_size(key, &entry.entry);
+                    }
+                    Some(key_entry_pair)
+                });
+                output_files.push(write_compacted_memtable_file(
+                    config,
+                    manifest.new_file_number(),
+                    next_level,
+                    output_entries,
+                )?);
+            }
+            let removed_files = upper_files
+                .iter()
+                .chain(&lower_files)
+                .map(|memtable_file| (memtable_file.level, memtable_file.file_number))
+                .collect();
+            manifest.commit(ManifestEdit {
+                removed_files,
+                added_files: output_files.iter().map(ManifestFile::from).collect(),
+                ..Default::default()
+            })?;
+            (smallest_key, largest_key, output_files)
+        };
+
+        //Replaced files are dropped from the levels and left on disk for the caller
+        let mut next_level_files: Vec<MemtableFile> =
+            std::mem::take(&mut memtable_file_levels[next_level])
+                .into_iter()
+                .filter(|memtable_file| {
+                    !memtable_file.overlaps_key_range(&smallest_key, &largest_key)
+                })
+                .collect();
+        let mut upper_file_indices = upper_file_indices.to_vec();
+        //Removed from the back so the remaining indices stay valid
+        upper_file_indices
+            .sort_unstable_by(|first_index, second_index| second_index.cmp(first_index));
+        for file_index in upper_file_indices {
+            memtable_file_levels[level].remove(file_index);
+        }
+        next_level_files.extend(output_files);
+        next_level_files.sort_by(|first_file, second_file| {
+            first_file.smallest_key.cmp(&second_file.smallest_key)
+        });
+        memtable_file_levels[next_level] = next_level_files;
+        Ok(())
+    }
+}
+
+fn level_size(memtable_files: &[MemtableFile]) -> usize {
+    memtable_files
+        .iter()
+        .map(|memtable_file| memtable_file.data_length)
+        .sum()
+}
+
+fn approximate_line_size(key: &str, entry: &MemtableEntry) -> usize {
+    match entry {
+        MemtableEntry::Value(value)
+        | MemtableEntry::Merge(value)
+        | MemtableEntry::ExpiringValue(value, _) => key.len() + value.len() + 2,
+        MemtableEntry::Tombstone => key.len() + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compaction::CompactionStrategy,
+        manifest::{Manifest, ManifestState},
+        memtable::Memtable,
+        memtable_config::MemtableConfig,
+        memtable_entry::MemtableEntry,
+        memtable_file::MemtableFile,
+        memtable_file_iterator::MemtableFileIterator,
+        test_utils::test_data_directory,
+    };
+
+    use super::LeveledCompaction;
+
+    //Numbers from 100 on are clear of those the memtable gives its files
+    fn test_manifest(data_directory: &str) -> Manifest {
+        let manifest_state = ManifestState {
+            next_file_number: 100,
+            ..Default::default()
+        };
+        Manifest::create(data_directory, &manifest_state).unwrap()
+    }
+
+    fn read_entries(
+        config: &MemtableConfig,
+        memtable_files: &[MemtableFile],
+    ) -> Vec<(String, MemtableEntry)> {
+        memtable_files
+            .iter()
+            .flat_map(|memtable_file| {
+                MemtableFileIterator::new(
+                    config,
+                    &memtable_file.file_path,
+                    memtable_file.format_version,
+                    0,
+                    memtable_file.data_length,
+                )
+                .unwrap()
+            })
+            .map(|key_entry_pair| key_entry_pair.map(|(key, entry)| (key, entry.entry)))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn leveled_compaction_merges_level_zero_into_level_one() {
+        let data_directory = test_data_directory("leveled_compaction_level_zero");
+        let mut config = MemtableConfig::new(2, &data_directory);
+        config.compaction_threshold = 2;
+        let mut memtable = Memtable::<String, &str>::new(config.clone()).unwrap();
+        let mut memtable_file_levels = vec![vec![]];
+        let mut manifest = test_manifest(&data_directory);
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable_file_levels[0].push(memtable.insert("B".to_string(), "1").unwrap().unwrap());
+        memtable.insert("A".to_string(), "2").unwrap();
+        memtable_file_levels[0].push(memtable.remove("C".to_string()).unwrap().unwrap());
+
+        LeveledCompaction::default()
+            .compact(&config, &mut memtable_file_levels, &mut manifest)
+            .unwrap();
+        assert!(memtable_file_levels[0].is_empty());
+        assert_eq!(memtable_file_levels[1].len(), 1);
+        assert_eq!(memtable_file_levels[1][0].level, 1);
+        assert_eq!(
+            read_entries(&config, &memtable_file_levels[1]),
+            vec![
+                ("A".to_string(), MemtableEntry::Value("2".to_string())),
+                ("B".to_string(), MemtableEntry::Value("1".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn leveled_compaction_splits_oversized_level_into_next() {
+        let data_directory = test_data_directory("leveled_compaction_oversized_level");
+        let mut config = MemtableConfig::new(4, &data_directory);
+        config.compaction_threshold = 1;
+        let leveled_compaction = LeveledCompaction {
+            level_one_size: 20,
+            level_size_ratio: 10,
+            target_file_size: 16,
+        };
+        let mut memtable = Memtable::<String, &str>::new(config.clone()).unwrap();
+        let mut memtable_file_levels = vec![vec![]];
+        let mut manifest = test_manifest(&data_directory);
+        for i in 0..12 {
+            if let Some(written_file) = memtable.insert(format!("key{:02}", i), "1").unwrap() {
+                memtable_file_levels[0].push(written_file);
+                leveled_compaction
+                    .compact(&config, &mut memtable_file_levels, &mut manifest)
+                    .unwrap();
+            }
+        }
+
+        assert!(memtable_file_levels[0].is_empty());
+        assert!(memtable_file_levels.len() >= 3);
+        for memtable_files in &memtable_file_levels[1..] {
+            for adjacent_files in memtable_files.windows(2) {
+                assert!(adjacent_files[0].largest_key < adjacent_files[1].smallest_key);
+            }
+        }
+        let keys: Vec<String> = memtable_file_levels[1..]
+            .iter()
+            .flat_map(|memtable_files| read_entries(&config, memtable_files))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys.len(), 12);
+    }
+}
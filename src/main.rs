@@ -1,17 +1,39 @@
 use memtable_config::MemtableConfig;
 use sorted_string_table::SortedStringTable;
 
+pub mod bloom_filter;
+pub mod compaction;
+pub mod crc32c;
+pub mod data_block;
+pub mod db;
+pub mod error;
+pub mod leveled_compaction;
+pub mod manifest;
 pub mod memtable;
 pub mod memtable_config;
+pub mod memtable_entry;
+pub mod memtable_file;
+pub mod memtable_file_index;
+pub mod memtable_file_iterator;
+pub mod memtable_file_verify;
 pub mod memtable_search_file;
 pub mod memtable_write_to_file;
+pub mod merge_operator;
+pub mod merging_iterator;
 pub mod sorted_string_table;
+#[cfg(test)]
+mod test_utils;
+pub mod transaction;
+pub mod varint;
+pub mod write_ahead_log;
+pub mod write_batch;
 
-fn main() {
-    let config = MemtableConfig::new(5, "./output/main.txt");
-    let mut ss_table = SortedStringTable::<&str, &str>::new(config);
+fn main() -> Result<(), error::Error> {
+    let config = MemtableConfig::new(5, "./output/main");
+    let ss_table = SortedStringTable::<&str, &str>::new(config)?;
     let key = "A";
-    ss_table.insert(key, "1");
-    let search_result = ss_table.find(&key);
+    ss_table.insert(key, "1")?;
+    let search_result = ss_table.find(&key)?;
     println!("{}", search_result.unwrap());
+    Ok(())
 }
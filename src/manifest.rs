@@ -0,0 +1,516 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    crc32c::crc32c,
+    error::{Error, Result},
+    memtable_file::MemtableFile,
+    memtable_write_to_file::sync_directory,
+    varint::{decode_varint, encode_varint},
+};
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const TEMPORARY_MANIFEST_FILE_NAME: &str = "MANIFEST.tmp";
+const RECORD_CHECKSUM_SIZE: usize = 4;
+const LENGTH_PREFIX_SIZE: usize = 4;
+//Each field of an edit starts with its tag, so fields an edit leaves unchanged take no space
+const LOG_NUMBER_TAG: u64 = 1;
+const NEXT_FILE_NUMBER_TAG: u64 = 2;
+const REMOVED_FILE_TAG: u64 = 3;
+const ADDED_FILE_TAG: u64 = 4;
+const LAST_SEQUENCE_NUMBER_TAG: u64 = 5;
+
+/// A memtable file as recorded in the manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestFile {
+    pub file_number: u64,
+    pub level: usize,
+    pub smallest_key: String,
+    pub largest_key: String,
+}
+
+impl From<&MemtableFile> for ManifestFile {
+    fn from(memtable_file: &MemtableFile) -> Self {
+        ManifestFile {
+            file_number: memtable_file.file_number,
+            level: memtable_file.level,
+            smallest_key: memtable_file.smallest_key.clone(),
+            largest_key: memtable_file.largest_key.clone(),
+        }
+    }
+}
+
+/// A change to the live set of memtable files, appended to the manifest as a single record
+/// so that it is applied on open either in full or not at all.
+#[derive(Debug, Default, PartialEq)]
+pub struct ManifestEdit {
+    //Write ahead logs numbered below this hold no entries missing from the live files
+    pub log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+    //Level and number of each file no longer live
+    pub removed_files: Vec<(usize, u64)>,
+    pub added_files: Vec<ManifestFile>,
+    //Sequence numbers up to this may be held by the live files
+    pub last_sequence_number: Option<u64>,
+}
+
+impl ManifestEdit {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded_edit = vec![];
+        if let Some(log_number) = self.log_number {
+            encode_varint(LOG_NUMBER_TAG, &mut encoded_edit);
+            encode_varint(log_number, &mut encoded_edit);
+        }
+        if let Some(next_file_number) = self.next_file_number {
+            encode_varint(NEXT_FILE_NUMBER_TAG, &mut encoded_edit);
+            encode_varint(next_file_number, &mut encoded_edit);
+        }
+        for (level, file_number) in &self.removed_files {
+            encode_varint(REMOVED_FILE_TAG, &mut encoded_edit);
+            encode_varint(*level as u64, &mut encoded_edit);
+            encode_varint(*file_number, &mut encoded_edit);
+        }
+        for added_file in &self.added_files {
+            encode_varint(ADDED_FILE_TAG, &mut encoded_edit);
+            encode_varint(added_file.level as u64, &mut encoded_edit);
+            encode_varint(added_file.file_number, &mut encoded_edit);
+            for key in [&added_file.smallest_key, &added_file.largest_key] {
+                encode_varint(key.len() as u64, &mut encoded_edit);
+                encoded_edit.extend_from_slice(key.as_bytes());
+            }
+        }
+        if let Some(last_sequence_number) = self.last_sequence_number {
+            encode_varint(LAST_SEQUENCE_NUMBER_TAG, &mut encoded_edit);
+            encode_varint(last_sequence_number, &mut encoded_edit);
+        }
+        encoded_edit
+    }
+
+    /// Returns `None` when a field is cut short or has an unknown tag.
+    pub fn decode(encoded_edit: &[u8]) -> Option<Self> {
+        let mut edit = ManifestEdit::default();
+        let mut position = 0;
+        let read_key = |position: &mut usize| {
+            let key_length = decode_varint(encoded_edit, position)? as usize;
+            let key_bytes = encoded_edit.get(*position..position.checked_add(key_length)?)?;
+            *position += key_length;
+            String::from_utf8(key_bytes.to_vec()).ok()
+        };
+        while position < encoded_edit.len() {
+            match decode_varint(encoded_edit, &mut position)? {
+                LOG_NUMBER_TAG => {
+                    edit.log_number = Some(decode_varint(encoded_edit, &mut position)?);
+                }
+                NEXT_FILE_NUMBER_TAG => {
+                    edit.next_file_number = Some(decode_varint(encoded_edit, &mut position)?);
+                }
+                REMOVED_FILE_TAG => {
+                    let level = decode_varint(encoded_edit, &mut position)? as usize;
+                    let file_number = decode_varint(encoded_edit, &mut position)?;
+                    edit.removed_files.push((level, file_number));
+                }
+                ADDED_FILE_TAG => {
+                    let level = decode_varint(encoded_edit, &mut position)? as usize;
+                    let file_number = decode_varint(encoded_edit, &mut position)?;
+                    edit.added_files.push(ManifestFile {
+                        file_number,
+                        level,
+                        smallest_key: read_key(&mut position)?,
+                        largest_key: read_key(&mut position)?,
+                    });
+                }
+                LAST_SEQUENCE_NUMBER_TAG => {
+                    edit.last_sequence_number = Some(decode_varint(encoded_edit, &mut position)?);
+                }
+                _ => return None,
+            }
+        }
+        Some(edit)
+    }
+}
+
+/// The live memtable files and counters the manifest's edits add up to.
+#[derive(Debug, PartialEq)]
+pub struct ManifestState {
+    pub log_number: u64,
+    pub next_file_number: u64,
+    pub last_sequence_number: u64,
+    //Level 0 is ordered from oldest to most recently written, deeper levels by key
+    pub manifest_file_levels: Vec<Vec<ManifestFile>>,
+    //Whether the manifest read ended in a record cut short by a crash, whose edit is lost, so
+    //files it does not list may still be needed
+    pub ends_in_torn_record: bool,
+}
+
+impl Default for ManifestState {
+    fn default() -> Self {
+        ManifestState {
+            log_number: 0,
+            next_file_number: 1,
+            last_sequence_number: 0,
+            manifest_file_levels: vec![vec![]],
+            ends_in_torn_record: false,
+        }
+    }
+}
+
+impl ManifestState {
+    /// Applies the edit's removals before its additions. A file added to level 0 takes the
+    /// place of the first level 0 file the same edit removes, so a merged file keeps the
+    /// position of its inputs in write order, or otherwise becomes the newest file.
+    pub fn apply(&mut self, edit: ManifestEdit) {
+        if let Some(log_number) = edit.log_number {
+            self.log_number = log_number;
+        }
+        if let Some(next_file_number) = edit.next_file_number {
+            self.next_file_number = next_file_number;
+        }
+        if let Some(last_sequence_number) = edit.last_sequence_number {
+            self.last_sequence_number = last_sequence_number;
+        }
+        let mut level_zero_position = None;
+        for (level, file_number) in edit.removed_files {
+            let Some(manifest_files) = self.manifest_file_levels.get_mut(level) else {
+                continue;
+            };
+            if let Some(file_index) = manifest_files
+                .iter()
+                .position(|manifest_file| manifest_file.file_number == file_number)
+            {
+                manifest_files.remove(file_index);
+                if level == 0 {
+                    level_zero_position = Some(
+                        level_zero_position
+                            .map_or(file_index, |position: usize| position.min(file_index)),
+                    );
+                }
+            }
+        }
+        for added_file in edit.added_files {
+            let level = added_file.level;
+            if self.manifest_file_levels.len() <= level {
+                self.manifest_file_levels.resize_with(level + 1, Vec::new);
+            }
+            let manifest_files = &mut self.manifest_file_levels[level];
+            match (level, level_zero_position) {
+                (0, Some(file_index)) => {
+                    manifest_files.insert(file_index, added_file);
+                    level_zero_position = Some(file_index + 1);
+                }
+                (0, None) => manifest_files.push(added_file),
+                _ => {
+                    let file_index = manifest_files.partition_point(|manifest_file| {
+                        manifest_file.smallest_key < added_file.smallest_key
+                    });
+                    manifest_files.insert(file_index, added_file);
+                }
+            }
+        }
+    }
+
+    /// Hands out a file number for a new memtable file or write ahead log. The number is
+    /// only recorded by the next edit committed to the manifest.
+    pub fn new_file_number(&mut self) -> u64 {
+        let file_number = self.next_file_number;
+        self.next_file_number += 1;
+        file_number
+    }
+
+    pub fn is_live(&self, level: usize, file_number: u64) -> bool {
+        self.manifest_file_levels
+            .get(level)
+            .is_some_and(|manifest_files| {
+                manifest_files
+                    .iter()
+                    .any(|manifest_file| manifest_file.file_number == file_number)
+            })
+    }
+
+    //A single edit recreating the whole state
+    fn snapshot_edit(&self) -> ManifestEdit {
+        ManifestEdit {
+            log_number: Some(self.log_number),
+            next_file_number: Some(self.next_file_number),
+            removed_files: vec![],
+            added_files: self
+                .manifest_file_levels
+                .iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            last_sequence_number: Some(self.last_sequence_number),
+        }
+    }
+}
+
+/// Append only log of edits to the live set of memtable files in the data directory, read
+/// back on open in place of listing the directory. Only the log number and file number
+/// counters are kept in memory, as the levels themselves are held by the table.
+pub struct Manifest {
+    file: File,
+    log_number: u64,
+    next_file_number: u64,
+    has_failed: bool,
+}
+
+impl Manifest {
+    /// Starts a new manifest holding a single edit that recreates the state, replacing any
+    /// earlier manifest in the directory in one rename so that it never grows without bound.
+    pub fn create(data_directory: &str, manifest_state: &ManifestState) -> Result<Self> {
+        let temporary_file_path = Path::new(data_directory).join(TEMPORARY_MANIFEST_FILE_NAME);
+        let mut temporary_file = File::create(&temporary_file_path)?;
+        temporary_file.write_all(&create_manifest_record(&manifest_state.snapshot_edit()))?;
+        temporary_file.sync_all()?;
+        fs::rename(&temporary_file_path, manifest_path(data_directory))?;
+        sync_directory(Path::new(data_directory))?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(manifest_path(data_directory))?;
+        Ok(Manifest {
+            file,
+            log_number: manifest_state.log_number,
+            next_file_number: manifest_state.next_file_number,
+            has_failed: false,
+        })
+    }
+
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// See [`ManifestState::new_file_number`].
+    pub fn new_file_number(&mut self) -> u64 {
+        let file_number = self.next_file_number;
+        self.next_file_number += 1;
+        file_number
+    }
+
+    /// Appends the edit, along with the next file number, and syncs it before returning. An
+    /// append that fails may leave part of a record behind, so every later commit fails too
+    /// and the table has to be opened again, which drops the partial record.
+    pub fn commit(&mut self, mut edit: ManifestEdit) -> Result<()> {
+        if self.has_failed {
+            return Err(Error::Corruption(
+                "manifest cannot take edits after a failed write".to_owned(),
+            ));
+        }
+        edit.next_file_number = Some(self.next_file_number);
+        let log_number = edit.log_number;
+        let record = create_manifest_record(&edit);
+        if let Err(error) = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_all())
+        {
+            self.has_failed = true;
+            return Err(Error::Io(error));
+        }
+        if let Some(log_number) = log_number {
+            self.log_number = log_number;
+        }
+        Ok(())
+    }
+}
+
+pub fn manifest_path(data_directory: &str) -> PathBuf {
+    Path::new(data_directory).join(MANIFEST_FILE_NAME)
+}
+
+/// Replays every edit in the data directory's manifest, returning `None` when there is no
+/// manifest. As with the write ahead log, a last record cut short or failing its checksum is
+/// dropped, while a bad record before it is returned as an error.
+pub fn read_manifest(data_directory: &str) -> Result<Option<ManifestState>> {
+    let manifest_contents = match fs::read(manifest_path(data_directory)) {
+        Ok(manifest_contents) => manifest_contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(Error::Io(error)),
+    };
+    let mut manifest_state = ManifestState::default();
+    let mut position = 0;
+    while let Some(edit) = read_manifest_record(&manifest_contents, &mut position)? {
+        manifest_state.apply(edit);
+    }
+    //Only the last record can have been cut short by a crash, so a record that cannot be read
+    //in full but is followed by a complete one has been corrupted
+    let has_complete_record_after = (position + 1..manifest_contents.len()).any(|record_start| {
+        let mut record_position = record_start;
+        matches!(
+            read_manifest_record(&manifest_contents, &mut record_position),
+            Ok(Some(_))
+        )
+    });
+    if has_complete_record_after {
+        return Err(Error::ChecksumMismatch(format!(
+            "manifest record at offset {} cannot be read but is followed by others",
+            position
+        )));
+    }
+    manifest_state.ends_in_torn_record = position < manifest_contents.len();
+    Ok(Some(manifest_state))
+}
+
+//The CRC32C of the rest of the record, then the length of the edit and the edit itself
+fn create_manifest_record(edit: &ManifestEdit) -> Vec<u8> {
+    let encoded_edit = edit.encode();
+    let mut record =
+        Vec::with_capacity(RECORD_CHECKSUM_SIZE + LENGTH_PREFIX_SIZE + encoded_edit.len());
+    record.extend_from_slice(&[0; RECORD_CHECKSUM_SIZE]);
+    record.extend_from_slice(&(encoded_edit.len() as u32).to_le_bytes());
+    record.extend_from_slice(&encoded_edit);
+    let checksum = crc32c(&record[RECORD_CHECKSUM_SIZE..]);
+    record[..RECORD_CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+    record
+}
+
+//Returns `None` once the remaining bytes do not hold a complete record, leaving the position at
+//its start
+fn read_manifest_record(
+    manifest_contents: &[u8],
+    position: &mut usize,
+) -> Result<Option<ManifestEdit>> {
+    let record_start = *position;
+    let length_end = record_start + RECORD_CHECKSUM_SIZE + LENGTH_PREFIX_SIZE;
+    let Some(record_header) = manifest_contents.get(record_start..length_end) else {
+        return Ok(None);
+    };
+    let edit_length =
+        u32::from_le_bytes(record_header[RECORD_CHECKSUM_SIZE..].try_into().unwrap()) as usize;
+    let record_end = length_end + edit_length;
+    let Some(encoded_edit) = manifest_contents.get(length_end..record_end) else {
+        return Ok(None);
+    };
+    let checksum = crc32c(&manifest_contents[record_start + RECORD_CHECKSUM_SIZE..record_end]);
+    if record_header[..RECORD_CHECKSUM_SIZE] != checksum.to_le_bytes() {
+        return match record_end == manifest_contents.len() {
+            true => Ok(None),
+            false => Err(Error::ChecksumMismatch(format!(
+                "manifest record at offset {} does not match its checksum",
+                record_start
+            ))),
+        };
+    }
+    *position = record_end;
+    ManifestEdit::decode(encoded_edit).map(Some).ok_or_else(|| {
+        Error::Corruption(format!(
+            "manifest record at offset {} is malformed",
+            record_start
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use crate::{error::Error, test_utils::test_data_directory};
+
+    use super::{
+        manifest_path, read_manifest, Manifest, ManifestEdit, ManifestFile, ManifestState,
+    };
+
+    fn manifest_file(file_number: u64, level: usize, smallest_key: &str) -> ManifestFile {
+        ManifestFile {
+            file_number,
+            level,
+            smallest_key: smallest_key.to_owned(),
+            largest_key: format!("{}~", smallest_key),
+        }
+    }
+
+    #[test]
+    fn manifest_edit_round_trip() {
+        let edit = ManifestEdit {
+            log_number: Some(7),
+            next_file_number: Some(300),
+            removed_files: vec![(0, 3), (2, 150)],
+            added_files: vec![manifest_file(200, 1, "key:\n")],
+            last_sequence_number: Some(u64::MAX),
+        };
+        let encoded_edit = edit.encode();
+        assert_eq!(ManifestEdit::decode(&encoded_edit), Some(edit));
+        assert_eq!(
+            ManifestEdit::decode(&encoded_edit[..encoded_edit.len() - 1]),
+            None
+        );
+        assert_eq!(ManifestEdit::decode(&[9, 1]), None);
+    }
+
+    #[test]
+    fn manifest_state_keeps_merged_files_in_write_order() {
+        let mut manifest_state = ManifestState::default();
+        for file_number in 1..=4 {
+            manifest_state.apply(ManifestEdit {
+                added_files: vec![manifest_file(file_number, 0, "A")],
+                ..Default::default()
+            });
+        }
+        manifest_state.apply(ManifestEdit {
+            removed_files: vec![(0, 2), (0, 3)],
+            added_files: vec![manifest_file(5, 0, "A")],
+            ..Default::default()
+        });
+        manifest_state.apply(ManifestEdit {
+            removed_files: vec![(0, 1)],
+            added_files: vec![manifest_file(7, 1, "M"), manifest_file(6, 1, "B")],
+            ..Default::default()
+        });
+        let file_numbers: Vec<Vec<u64>> = manifest_state
+            .manifest_file_levels
+            .iter()
+            .map(|manifest_files| {
+                manifest_files
+                    .iter()
+                    .map(|manifest_file| manifest_file.file_number)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(file_numbers, vec![vec![5, 4], vec![6, 7]]);
+        assert!(manifest_state.is_live(1, 7));
+        assert!(!manifest_state.is_live(0, 7));
+    }
+
+    #[test]
+    fn manifest_round_trip_drops_torn_last_record() {
+        let data_directory = test_data_directory("manifest_round_trip");
+        assert_eq!(read_manifest(&data_directory).unwrap(), None);
+        let mut manifest = Manifest::create(&data_directory, &ManifestState::default()).unwrap();
+        assert_eq!(manifest.new_file_number(), 1);
+        manifest
+            .commit(ManifestEdit {
+                log_number: Some(2),
+                added_files: vec![manifest_file(1, 0, "A")],
+                last_sequence_number: Some(9),
+                ..Default::default()
+            })
+            .unwrap();
+        manifest
+            .commit(ManifestEdit {
+                removed_files: vec![(0, 1)],
+                ..Default::default()
+            })
+            .unwrap();
+        let mut manifest_file_handle = OpenOptions::new()
+            .append(true)
+            .open(manifest_path(&data_directory))
+            .unwrap();
+        manifest_file_handle.write_all(&[1, 2, 3]).unwrap();
+
+        let manifest_state = read_manifest(&data_directory).unwrap().unwrap();
+        assert!(manifest_state.ends_in_torn_record);
+        assert_eq!(manifest_state.log_number, 2);
+        assert_eq!(manifest_state.next_file_number, 2);
+        assert_eq!(manifest_state.last_sequence_number, 9);
+        assert_eq!(manifest_state.manifest_file_levels, vec![vec![]]);
+
+        //The first record is followed by others, so a bad byte in it cannot be from a crash
+        let mut manifest_contents = fs::read(manifest_path(&data_directory)).unwrap();
+        manifest_contents[8] ^= 1;
+        fs::write(manifest_path(&data_directory), manifest_contents).unwrap();
+        assert!(matches!(
+            read_manifest(&data_directory),
+            Err(Error::ChecksumMismatch(_))
+        ));
+    }
+}
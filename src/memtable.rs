@@ -1,18 +1,38 @@
 use crate::{
-    memtable_config::MemtableConfig, memtable_write_to_file::write_data_to_file,
+    error::Result,
+    memtable_config::MemtableConfig,
+    memtable_entry::{current_time_in_millis, MemtableEntry, SequencedEntry},
+    memtable_file::{memtable_file_path, next_memtable_file_number, MemtableFile},
+    memtable_write_to_file::write_data_to_file,
+    merge_operator::fold_entries,
     sorted_string_table::StringLike,
 };
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fs, marker::PhantomData, ops::Bound, sync::Arc};
 
+//Rough size of the key and entry strings' headers and the tree node an entry sits in
+const ENTRY_OVERHEAD_SIZE: usize = 64;
+
+/// Keys and values are held as strings, the same form they take on disk, so that entries
+/// read back from the write ahead log can be inserted alongside new ones.
+///
+/// Each key holds its versions newest first. An overwrite drops the versions it replaces
+/// unless a snapshot may still read them, or they are merge operands or the entry under them,
+/// so without snapshots or merges a key holds a single entry.
 pub struct Memtable<K, V>
 where
     K: StringLike,
     V: StringLike,
 {
-    table: BTreeMap<K, V>,
+    table: BTreeMap<String, Vec<SequencedEntry>>,
     config: MemtableConfig,
+    //Number of distinct keys held, counting tombstones
     pub current_size: usize,
-    pub key_offsets_of_most_recent_written_memtable: Option<Vec<(K, usize)>>,
+    //Approximate bytes taken by the versions held, without those since dropped
+    pub current_size_in_bytes: usize,
+    next_file_number: u64,
+    //Highest sequence number put so far, flushed entries included
+    last_sequence_number: u64,
+    key_value_types: PhantomData<(K, V)>,
 }
 
 impl<K, V> Memtable<K, V>
@@ -20,81 +40,471 @@ where
     K: StringLike,
     V: StringLike,
 {
-    pub fn new(config: MemtableConfig) -> Self {
-        Memtable {
-            table: BTreeMap::<K, V>::new(),
+    pub fn new(config: MemtableConfig) -> Result<Self> {
+        config.validate()?;
+        fs::create_dir_all(&config.data_directory)?;
+        let next_file_number = next_memtable_file_number(&config.data_directory)?;
+        Ok(Memtable {
+            table: BTreeMap::new(),
             config,
             current_size: 0,
-            key_offsets_of_most_recent_written_memtable: None,
-        }
+            current_size_in_bytes: 0,
+            next_file_number,
+            last_sequence_number: 0,
+            key_value_types: PhantomData,
+        })
+    }
+
+    /// Inserts the key value pair under the sequence number after the last one put, flushing
+    /// the memtable to a new file once its capacity or write buffer size is reached. Returns
+    /// the written file when a flush happened.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<MemtableFile>> {
+        let sequenced_entry = self.next_sequenced_entry(MemtableEntry::Value(value.into()));
+        self.insert_entry(key.into(), sequenced_entry)
+    }
+
+    /// Records a tombstone for the key, flushing in the same way as [`Memtable::insert`].
+    pub fn remove(&mut self, key: K) -> Result<Option<MemtableFile>> {
+        let sequenced_entry = self.next_sequenced_entry(MemtableEntry::Tombstone);
+        self.insert_entry(key.into(), sequenced_entry)
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        self.table.insert(key, value);
-        self.current_size += 1;
-        if self.current_size == self.config.capacity {
-            let key_value_pairs = self.get_all_key_value_pairs();
-            self.key_offsets_of_most_recent_written_memtable =
-                write_data_to_file(&self.config, &key_value_pairs);
-            self.table.clear();
-            self.current_size = 0;
+    /// When a flush fails the entries are kept, and the flush is retried on the next insert.
+    pub fn insert_entry(
+        &mut self,
+        key: String,
+        sequenced_entry: SequencedEntry,
+    ) -> Result<Option<MemtableFile>> {
+        self.put_entry(key, sequenced_entry, None);
+        if self.is_full() {
+            let written_file =
+                write_table_to_file(&self.config, self.next_file_number, &self.table)?;
+            self.next_file_number += 1;
+            self.clear();
+            return Ok(Some(written_file));
         }
+        Ok(None)
     }
 
-    pub fn find(&self, key: &K) -> Option<&V> {
-        let memtable_search_result = self.table.get(key);
-        if memtable_search_result.is_some() {
-            memtable_search_result
-        } else {
-            None
+    /// Adds the entry without flushing, leaving it to the caller to check [`Memtable::is_full`].
+    /// The versions it replaces are dropped, apart from those written after the oldest
+    /// snapshot's sequence number and the newest of those the snapshot can see, and the merge
+    /// operands it starts and the entry under them.
+    pub fn put_entry(
+        &mut self,
+        key: String,
+        sequenced_entry: SequencedEntry,
+        oldest_snapshot_sequence_number: Option<u64>,
+    ) {
+        self.last_sequence_number = self
+            .last_sequence_number
+            .max(sequenced_entry.sequence_number);
+        self.current_size_in_bytes += approximate_entry_size(&key, &sequenced_entry.entry);
+        let versions = match self.table.get_mut(&key) {
+            Some(versions) => versions,
+            None => {
+                self.current_size += 1;
+                self.table.insert(key, vec![sequenced_entry]);
+                return;
+            }
+        };
+        versions.insert(0, sequenced_entry);
+        let snapshot_version_count = match oldest_snapshot_sequence_number {
+            Some(oldest_snapshot_sequence_number) => versions
+                .iter()
+                .position(|version| version.sequence_number <= oldest_snapshot_sequence_number)
+                .map_or(versions.len(), |version_index| version_index + 1),
+            None => 1,
+        };
+        let merge_version_count = visible_versions(versions, u64::MAX).len();
+        let retained_version_count = snapshot_version_count.max(merge_version_count);
+        for dropped_version in versions.drain(retained_version_count..) {
+            self.current_size_in_bytes -= approximate_entry_size(&key, &dropped_version.entry);
         }
     }
 
-    fn get_all_key_value_pairs(&self) -> Vec<(K, V)> {
+    /// Whether the memtable has reached its capacity or write buffer size.
+    pub fn is_full(&self) -> bool {
+        self.current_size >= self.config.capacity
+            || self.current_size_in_bytes >= self.config.write_buffer_size
+    }
+
+    /// Hands the entries over to an immutable memtable to be flushed to the memtable's next
+    /// file, leaving this one empty and taking the following file number.
+    pub fn freeze(&mut self) -> ImmutableMemtable {
+        let immutable_memtable = ImmutableMemtable {
+            table: std::mem::take(&mut self.table),
+            config: self.config.clone(),
+            file_number: self.next_file_number,
+        };
+        self.next_file_number += 1;
+        self.clear();
+        immutable_memtable
+    }
+
+    /// Returns the key's newest entry.
+    pub fn find(&self, key: &K) -> Option<&MemtableEntry> {
+        let key_as_string: String = key.clone().into();
+        let versions = self.table.get(&key_as_string)?;
+        versions.first().map(|version| &version.entry)
+    }
+
+    /// Returns the key's newest entry with a sequence number no higher than the one given.
+    pub fn find_as_of(&self, key: &str, sequence_number: u64) -> Option<&SequencedEntry> {
+        find_version_as_of(self.table.get(key)?, sequence_number)
+    }
+
+    /// Returns the key's versions needed to read it as of the sequence number, newest first:
+    /// the newest with a sequence number no higher than the one given, and when that is a merge
+    /// operand the versions under it down to the first that is not.
+    pub fn versions_as_of(&self, key: &str, sequence_number: u64) -> &[SequencedEntry] {
+        self.table
+            .get(key)
+            .map_or(&[], |versions| visible_versions(versions, sequence_number))
+    }
+
+    /// Returns the entries with keys inside the bounds, in key order, each key's being those
+    /// given by [`Memtable::versions_as_of`]. Bounds that hold no keys, such as a start after
+    /// the end, give no entries.
+    pub fn range(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        sequence_number: u64,
+    ) -> impl Iterator<Item = (String, SequencedEntry)> + '_ {
+        range_of_table(&self.table, start, end, sequence_number)
+    }
+
+    /// The number of the file the memtable will be written to on its next flush.
+    pub fn next_file_number(&self) -> u64 {
+        self.next_file_number
+    }
+
+    /// Sets the number of the file the memtable will be written to on its next flush, for
+    /// when file numbers are handed out by the manifest.
+    pub fn set_next_file_number(&mut self, next_file_number: u64) {
+        self.next_file_number = next_file_number;
+    }
+
+    /// The highest sequence number put into the memtable since it was created.
+    pub fn last_sequence_number(&self) -> u64 {
+        self.last_sequence_number
+    }
+
+    /// Returns the newest entry of every key, preceded by the versions under it when it is a
+    /// merge operand, oldest first so they can be put again in the order they were written.
+    pub fn get_all_key_value_pairs(&self) -> Vec<(String, SequencedEntry)> {
         let mut key_value_pairs = vec![];
-        self.table.iter().for_each(|(key, value)| {
-            key_value_pairs.push((key.clone(), value.clone()));
+        self.table.iter().for_each(|(key, versions)| {
+            for version in visible_versions(versions, u64::MAX).iter().rev() {
+                key_value_pairs.push((key.clone(), version.clone()));
+            }
         });
         key_value_pairs
     }
+
+    fn next_sequenced_entry(&self, entry: MemtableEntry) -> SequencedEntry {
+        SequencedEntry {
+            sequence_number: self.last_sequence_number + 1,
+            entry,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.table.clear();
+        self.current_size = 0;
+        self.current_size_in_bytes = 0;
+    }
+}
+
+/// A full memtable waiting to be flushed, which stays readable until its file is written
+/// and takes its place. Snapshots taken while it was the memtable keep reading it after.
+pub struct ImmutableMemtable {
+    table: BTreeMap<String, Vec<SequencedEntry>>,
+    config: MemtableConfig,
+    pub file_number: u64,
+}
+
+impl ImmutableMemtable {
+    /// See [`Memtable::find_as_of`].
+    pub fn find(&self, key: &str, sequence_number: u64) -> Option<&SequencedEntry> {
+        find_version_as_of(self.table.get(key)?, sequence_number)
+    }
+
+    /// See [`Memtable::versions_as_of`].
+    pub fn versions_as_of(&self, key: &str, sequence_number: u64) -> &[SequencedEntry] {
+        self.table
+            .get(key)
+            .map_or(&[], |versions| visible_versions(versions, sequence_number))
+    }
+
+    /// See [`Memtable::range`].
+    pub fn range(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        sequence_number: u64,
+    ) -> impl Iterator<Item = (String, SequencedEntry)> + '_ {
+        range_of_table(&self.table, start, end, sequence_number)
+    }
+
+    /// Iterates the entries inside the bounds in the same way as [`ImmutableMemtable::range`],
+    /// but holds its own reference to the memtable so the iterator can outlive the slot it was
+    /// taken from. Each key's entries are found by searching on from the key before it.
+    pub fn shared_range(
+        self: Arc<Self>,
+        start: Bound<String>,
+        end: Bound<String>,
+        sequence_number: u64,
+    ) -> impl Iterator<Item = (String, SequencedEntry)> {
+        let mut next_start = start;
+        //Entries of the last key found not yet returned, oldest first
+        let mut key_entries: Vec<(String, SequencedEntry)> = vec![];
+        std::iter::from_fn(move || {
+            if key_entries.is_empty() {
+                let mut entries = range_of_table(
+                    &self.table,
+                    next_start.clone(),
+                    end.clone(),
+                    sequence_number,
+                );
+                let (key, entry) = entries.next()?;
+                key_entries = entries
+                    .take_while(|(entries_key, _)| *entries_key == key)
+                    .collect();
+                key_entries.reverse();
+                next_start = Bound::Excluded(key.clone());
+                key_entries.push((key, entry));
+            }
+            key_entries.pop()
+        })
+    }
+
+    /// Writes the newest entry of every key, with merge operands folded into the versions
+    /// under them and expired values replaced by tombstones.
+    pub fn write_to_file(&self) -> Result<MemtableFile> {
+        write_table_to_file(&self.config, self.file_number, &self.table)
+    }
+}
+
+//The newest version as old as the sequence number, and the versions under it down to the first
+//that is not a merge operand
+fn visible_versions(versions: &[SequencedEntry], sequence_number: u64) -> &[SequencedEntry] {
+    let first_version_index = versions
+        .iter()
+        .position(|version| version.sequence_number <= sequence_number)
+        .unwrap_or(versions.len());
+    let versions = &versions[first_version_index..];
+    let version_count = versions
+        .iter()
+        .position(|version| !matches!(version.entry, MemtableEntry::Merge(_)))
+        .map_or(versions.len(), |version_index| version_index + 1);
+    &versions[..version_count]
+}
+
+fn find_version_as_of(
+    versions: &[SequencedEntry],
+    sequence_number: u64,
+) -> Option<&SequencedEntry> {
+    versions
+        .iter()
+        .find(|version| version.sequence_number <= sequence_number)
+}
+
+//Keys without a version as old as the sequence number are skipped
+fn range_of_table(
+    table: &BTreeMap<String, Vec<SequencedEntry>>,
+    start: Bound<String>,
+    end: Bound<String>,
+    sequence_number: u64,
+) -> impl Iterator<Item = (String, SequencedEntry)> + '_ {
+    let range_is_empty = match (&start, &end) {
+        (Bound::Included(start_key), Bound::Included(end_key)) => start_key > end_key,
+        (Bound::Included(start_key) | Bound::Excluded(start_key), Bound::Excluded(end_key))
+        | (Bound::Excluded(start_key), Bound::Included(end_key)) => start_key >= end_key,
+        _ => false,
+    };
+    (!range_is_empty)
+        .then(|| table.range((start, end)))
+        .into_iter()
+        .flatten()
+        .flat_map(move |(key, versions)| {
+            visible_versions(versions, sequence_number)
+                .iter()
+                .map(|version| (key.clone(), version.clone()))
+        })
+}
+
+fn write_table_to_file(
+    config: &MemtableConfig,
+    file_number: u64,
+    table: &BTreeMap<String, Vec<SequencedEntry>>,
+) -> Result<MemtableFile> {
+    let file_path = memtable_file_path(&config.data_directory, file_number);
+    //Older versions were only kept for snapshots, which go on reading them from memory, or for
+    //merge operands, which are folded as far as the versions held allow. Expired values are
+    //written as the tombstones they are read as
+    let current_time = current_time_in_millis();
+    let key_value_pairs = table.iter().map(move |(key, versions)| {
+        let versions = visible_versions(versions, u64::MAX)
+            .iter()
+            .map(|version| Ok(version.clone().hide_if_expired(current_time)));
+        let folded_entry = fold_entries(config.merge_operator.as_deref(), key, versions, true)?;
+        Ok((key.clone(), folded_entry.unwrap()))
+    });
+    let written_data = write_data_to_file(config, &file_path, key_value_pairs)?;
+    Ok(MemtableFile {
+        file_number,
+        level: 0,
+        file_path,
+        key_offsets: written_data.key_offsets,
+        data_length: written_data.data_length,
+        smallest_key: written_data.smallest_key,
+        largest_key: written_data.largest_key,
+        bloom_filter: written_data.bloom_filter,
+        format_version: written_data.format_version,
+    })
+}
+
+/// Bytes an entry takes in the memtable: its key and value, plus the strings and tree node
+/// holding them.
+pub fn approximate_entry_size(key: &str, entry: &MemtableEntry) -> usize {
+    let value_length = match entry {
+        MemtableEntry::Value(value)
+        | MemtableEntry::Merge(value)
+        | MemtableEntry::ExpiringValue(value, _) => value.len(),
+        MemtableEntry::Tombstone => 0,
+    };
+    key.len() + value_length + ENTRY_OVERHEAD_SIZE
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Memtable, MemtableConfig};
+    use std::{fs, ops::Bound};
+
+    use crate::{error::Error, test_utils::test_data_directory};
+
+    use super::{Memtable, MemtableConfig, MemtableEntry, SequencedEntry, ENTRY_OVERHEAD_SIZE};
 
     #[test]
     fn new_memtable() {
         let config = MemtableConfig::new(10, "./");
-        let memtable = Memtable::<String, String>::new(config);
+        let memtable = Memtable::<String, String>::new(config).unwrap();
         assert_eq!(memtable.current_size, 0);
     }
 
     #[test]
     fn new_memtable_inserts_below_capacity() {
         let config = MemtableConfig::new(10, "./");
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("A".to_owned(), "10");
-        memtable.insert("B".to_owned(), "20");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "10").unwrap();
+        memtable.insert("B".to_owned(), "20").unwrap();
         assert_eq!(memtable.current_size, 2)
     }
 
     #[test]
     fn new_memtable_inserts_beyond_capacity() {
-        let config = MemtableConfig::new(10, "./output/test_result.txt");
-        let mut memtable = Memtable::<String, &str>::new(config);
+        let data_directory = test_data_directory("memtable_inserts_beyond_capacity");
+        let config = MemtableConfig::new(10, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
         for i in 0..10 {
-            memtable.insert(i.to_string(), "10");
+            memtable.insert(i.to_string(), "10").unwrap();
         }
         assert_eq!(memtable.current_size, 0)
     }
 
+    #[test]
+    fn memtable_flushes_write_separate_files() {
+        let data_directory = test_data_directory("memtable_flushes_write_separate_files");
+        let config = MemtableConfig::new(2, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "10").unwrap();
+        let first_file = memtable.insert("B".to_owned(), "20").unwrap().unwrap();
+        memtable.insert("C".to_owned(), "30").unwrap();
+        let second_file = memtable.insert("D".to_owned(), "40").unwrap().unwrap();
+        assert_eq!(first_file.file_number, 1);
+        assert_eq!(second_file.file_number, 2);
+        assert!(first_file.file_path.exists());
+        assert!(second_file.file_path.exists());
+    }
+
+    #[test]
+    fn memtable_flushes_at_write_buffer_size() {
+        let data_directory = test_data_directory("memtable_flushes_at_write_buffer_size");
+        let config = MemtableConfig::with_write_buffer_size(10 * 1024, &data_directory);
+        let mut memtable = Memtable::<String, String>::new(config).unwrap();
+        let large_value = "v".repeat(1000);
+        let written_files: Vec<usize> = (0..30)
+            .filter(|i| {
+                memtable
+                    .insert(format!("key{:02}", i), large_value.clone())
+                    .unwrap()
+                    .is_some()
+            })
+            .collect();
+        //Each entry takes a little over a kilobyte, so ten of them fill the buffer
+        assert_eq!(written_files, vec![9, 19, 29]);
+        assert_eq!(memtable.current_size_in_bytes, 0);
+
+        memtable.insert("small".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(memtable.current_size_in_bytes, 5 + 1 + ENTRY_OVERHEAD_SIZE);
+    }
+
+    #[test]
+    fn memtable_overwrites_do_not_count_towards_capacity() {
+        let data_directory = test_data_directory("memtable_overwrites_capacity");
+        let config = MemtableConfig::new(3, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        for value in ["1", "22", "333"] {
+            assert!(memtable.insert("A".to_owned(), value).unwrap().is_none());
+        }
+        assert!(memtable.remove("B".to_owned()).unwrap().is_none());
+        assert_eq!(memtable.current_size, 2);
+        assert_eq!(
+            memtable.current_size_in_bytes,
+            (1 + 3 + ENTRY_OVERHEAD_SIZE) + (1 + ENTRY_OVERHEAD_SIZE)
+        );
+        assert!(memtable.insert("C".to_owned(), "1").unwrap().is_some());
+    }
+
+    #[test]
+    fn memtable_freeze_hands_entries_to_immutable_memtable() {
+        let data_directory = test_data_directory("memtable_freeze");
+        let config = MemtableConfig::new(10, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "1").unwrap();
+        memtable.remove("B".to_owned()).unwrap();
+        let immutable_memtable = memtable.freeze();
+        assert_eq!(immutable_memtable.file_number, 1);
+        assert_eq!(memtable.next_file_number(), 2);
+        assert_eq!(memtable.current_size, 0);
+        assert!(memtable.find(&"A".to_owned()).is_none());
+        assert_eq!(
+            immutable_memtable.find("A", u64::MAX),
+            Some(&SequencedEntry {
+                sequence_number: 1,
+                entry: MemtableEntry::Value("1".to_owned())
+            })
+        );
+        assert_eq!(
+            immutable_memtable
+                .range(Bound::Unbounded, Bound::Unbounded, u64::MAX)
+                .count(),
+            2
+        );
+
+        let written_file = immutable_memtable.write_to_file().unwrap();
+        assert_eq!(written_file.file_number, 1);
+        assert_eq!(written_file.smallest_key, "A");
+        assert_eq!(written_file.largest_key, "B");
+    }
+
     #[test]
     fn memtable_find_key_does_not_exist() {
         let config = MemtableConfig::new(10, "./");
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("A".to_owned(), "10");
-        memtable.insert("B".to_owned(), "20");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "10").unwrap();
+        memtable.insert("B".to_owned(), "20").unwrap();
         assert_eq!(memtable.current_size, 2);
         let key_to_find = "Key".to_string();
         let find_result = memtable.find(&key_to_find);
@@ -104,12 +514,102 @@ mod tests {
     #[test]
     fn memtable_find_key_does_exist() {
         let config = MemtableConfig::new(10, "./");
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("A".to_owned(), "10");
-        memtable.insert("B".to_owned(), "20");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "10").unwrap();
+        memtable.insert("B".to_owned(), "20").unwrap();
         assert_eq!(memtable.current_size, 2);
         let key_to_find = "B".to_string();
         let find_result = memtable.find(&key_to_find);
         assert!(find_result.is_some());
     }
+
+    #[test]
+    fn memtable_range_within_bounds() {
+        let config = MemtableConfig::new(10, "./");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        for key in ["A", "B", "C", "D"] {
+            memtable.insert(key.to_owned(), "10").unwrap();
+        }
+        let keys: Vec<String> = memtable
+            .range(
+                Bound::Excluded("A".to_owned()),
+                Bound::Included("C".to_owned()),
+                u64::MAX,
+            )
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["B", "C"]);
+        let empty_range = memtable.range(
+            Bound::Included("C".to_owned()),
+            Bound::Excluded("A".to_owned()),
+            u64::MAX,
+        );
+        assert_eq!(empty_range.count(), 0);
+    }
+
+    #[test]
+    fn memtable_keeps_versions_the_oldest_snapshot_can_read() {
+        let config = MemtableConfig::new(10, "./");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        let value = |sequence_number: u64| SequencedEntry {
+            sequence_number,
+            entry: MemtableEntry::Value(sequence_number.to_string()),
+        };
+        memtable.put_entry("A".to_owned(), value(1), None);
+        memtable.put_entry("A".to_owned(), value(2), None);
+        assert_eq!(memtable.find_as_of("A", 1), None);
+
+        //Versions 3 and 4 are newer than the snapshot at 2, which goes on reading version 2
+        memtable.put_entry("A".to_owned(), value(3), Some(2));
+        memtable.put_entry("A".to_owned(), value(4), Some(2));
+        memtable.put_entry("B".to_owned(), value(5), Some(2));
+        assert_eq!(memtable.find_as_of("A", 2), Some(&value(2)));
+        assert_eq!(memtable.find_as_of("A", 3), Some(&value(3)));
+        assert_eq!(memtable.find(&"A".to_owned()), Some(&value(4).entry));
+        let keys_as_of_snapshot: Vec<String> = memtable
+            .range(Bound::Unbounded, Bound::Unbounded, 2)
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys_as_of_snapshot, vec!["A"]);
+
+        memtable.put_entry("A".to_owned(), value(6), None);
+        assert_eq!(memtable.find_as_of("A", 5), None);
+        assert_eq!(memtable.last_sequence_number(), 6);
+        assert_eq!(
+            memtable.current_size_in_bytes,
+            2 * (1 + 1 + ENTRY_OVERHEAD_SIZE)
+        );
+    }
+
+    #[test]
+    fn memtable_find_removed_key_is_tombstone() {
+        let config = MemtableConfig::new(10, "./");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "10").unwrap();
+        memtable.remove("A".to_owned()).unwrap();
+        assert_eq!(memtable.current_size, 1);
+        let find_result = memtable.find(&"A".to_string());
+        assert_eq!(find_result, Some(&MemtableEntry::Tombstone));
+    }
+
+    #[test]
+    fn memtable_keeps_entries_when_flush_fails() {
+        let data_directory = test_data_directory("memtable_keeps_entries_when_flush_fails");
+        let config = MemtableConfig::new(2, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_owned(), "10").unwrap();
+        fs::remove_dir_all(&data_directory).unwrap();
+        assert!(matches!(
+            memtable.insert("B".to_owned(), "20"),
+            Err(Error::Io(_))
+        ));
+        assert_eq!(memtable.current_size, 2);
+
+        fs::create_dir_all(&data_directory).unwrap();
+        let written_file = memtable.insert("C".to_owned(), "30").unwrap().unwrap();
+        assert_eq!(written_file.file_number, 1);
+        assert_eq!(written_file.smallest_key, "A");
+        assert_eq!(written_file.largest_key, "C");
+        assert_eq!(memtable.current_size, 0);
+    }
 }
@@ -1,20 +1,160 @@
+use std::sync::Arc;
+
+use crate::{
+    compaction::{CompactionStrategy, SizeTieredCompaction},
+    error::{Error, Result},
+    memtable_file_index::{FILTER_FORMAT_VERSION, SEQUENCE_FORMAT_VERSION},
+    merge_operator::MergeOperator,
+};
+
 #[derive(Clone)]
 pub struct MemtableConfig {
     pub key_value_delimeter: char,
     pub key_offset_indicator: char,
     pub key_offset_frequency: u32,
+    //Number of entries written to the memtable before it is flushed
     pub capacity: usize,
-    pub file_path: String,
+    //Approximate size in bytes of the keys, values and per entry overhead written to the
+    //memtable before it is flushed, whichever of this and `capacity` is reached first
+    pub write_buffer_size: usize,
+    pub data_directory: String,
+    //Number of consecutively written memtable files of similar size that triggers compaction,
+    //or under leveled compaction the number of files in level 0
+    pub compaction_threshold: usize,
+    pub compaction_strategy: Arc<dyn CompactionStrategy>,
+    //Bloom filter bits given to each key of a memtable file, with 0 writing no filter
+    pub bits_per_key: usize,
+    //Format new memtable files are written in, either one of the binary formats or the text
+    //format of delimited lines, which cannot hold keys or values containing the delimiter or a
+    //newline
+    pub format_version: u32,
+    //Size in bytes a data block is filled to before the next one is started, in the block format
+    pub block_size: usize,
+    //Number of keys between restart points, where a key is stored in full rather than sharing a
+    //prefix with the key before it
+    pub block_restart_interval: usize,
+    //Folds the operands written by `merge` into values, with none configured rejecting merges.
+    //Operands are only written to files in formats with sequence numbers
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
 }
 
+//Flushes a memtable at around 4 MiB when it is limited by entry count as well
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
 impl MemtableConfig {
-    pub fn new(capacity: usize, file_path: &str) -> Self {
+    /// Flushes the memtable after `capacity` entries, or sooner should they reach the default
+    /// write buffer size.
+    pub fn new(capacity: usize, data_directory: &str) -> Self {
         MemtableConfig {
             key_value_delimeter: ':',
             key_offset_indicator: '&',
             key_offset_frequency: 5,
             capacity,
-            file_path: file_path.to_owned(),
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            data_directory: data_directory.to_owned(),
+            compaction_threshold: 4,
+            compaction_strategy: Arc::new(SizeTieredCompaction),
+            bits_per_key: 10,
+            format_version: SEQUENCE_FORMAT_VERSION,
+            block_size: 4 * 1024,
+            block_restart_interval: 16,
+            merge_operator: None,
+        }
+    }
+
+    /// Flushes the memtable once its entries take up `write_buffer_size` bytes, however many
+    /// of them there are.
+    pub fn with_write_buffer_size(write_buffer_size: usize, data_directory: &str) -> Self {
+        MemtableConfig {
+            write_buffer_size,
+            ..MemtableConfig::new(usize::MAX, data_directory)
+        }
+    }
+
+    /// Checks the settings the file format and flushing rely on, so a table is never opened
+    /// with ones that would write files it cannot read back.
+    pub fn validate(&self) -> Result<()> {
+        if self.capacity == 0 || self.write_buffer_size == 0 {
+            return Err(Error::InvalidConfiguration(
+                "capacity and write_buffer_size must be at least 1".to_owned(),
+            ));
+        }
+        if self.key_offset_frequency == 0 {
+            return Err(Error::InvalidConfiguration(
+                "key_offset_frequency must be at least 1".to_owned(),
+            ));
         }
+        if self.key_value_delimeter == self.key_offset_indicator {
+            return Err(Error::InvalidConfiguration(
+                "key_value_delimeter and key_offset_indicator must differ".to_owned(),
+            ));
+        }
+        if self.key_value_delimeter == '\n' || self.key_offset_indicator == '\n' {
+            return Err(Error::InvalidConfiguration(
+                "key_value_delimeter and key_offset_indicator cannot be a newline".to_owned(),
+            ));
+        }
+        if !(FILTER_FORMAT_VERSION..=SEQUENCE_FORMAT_VERSION).contains(&self.format_version) {
+            return Err(Error::InvalidConfiguration(format!(
+                "memtable files cannot be written in format version {}",
+                self.format_version
+            )));
+        }
+        if self.merge_operator.is_some() && self.format_version < SEQUENCE_FORMAT_VERSION {
+            return Err(Error::InvalidConfiguration(format!(
+                "merge operands cannot be written in format version {}",
+                self.format_version
+            )));
+        }
+        if self.block_size == 0 || self.block_restart_interval == 0 {
+            return Err(Error::InvalidConfiguration(
+                "block_size and block_restart_interval must be at least 1".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        error::Error, memtable_file_index::CHECKSUM_FORMAT_VERSION, merge_operator::U64AddOperator,
+    };
+
+    use super::MemtableConfig;
+
+    #[test]
+    fn validate_rejects_unusable_settings() {
+        assert!(MemtableConfig::new(4, "./").validate().is_ok());
+        assert!(matches!(
+            MemtableConfig::new(0, "./").validate(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            MemtableConfig::with_write_buffer_size(0, "./").validate(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        let mut config = MemtableConfig::new(4, "./");
+        config.key_offset_indicator = config.key_value_delimeter;
+        assert!(matches!(
+            config.validate(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        let mut config = MemtableConfig::new(4, "./");
+        config.format_version = 1;
+        assert!(matches!(
+            config.validate(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        let mut config = MemtableConfig::new(4, "./");
+        config.merge_operator = Some(Arc::new(U64AddOperator::new()));
+        assert!(config.validate().is_ok());
+        config.format_version = CHECKSUM_FORMAT_VERSION;
+        assert!(matches!(
+            config.validate(),
+            Err(Error::InvalidConfiguration(_))
+        ));
     }
 }
@@ -0,0 +1,52 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a memtable or memtable file holds for a key. A tombstone records that the key was
+/// removed, hiding any value for it in older memtable files. A merge operand is applied to
+/// the entry before it by the configured merge operator, see
+/// [`crate::merge_operator::MergeOperator`]. An expiring value is read as a tombstone once
+/// the time it expires at, in milliseconds since the Unix epoch, is reached.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MemtableEntry {
+    Value(String),
+    Tombstone,
+    Merge(String),
+    ExpiringValue(String, u64),
+}
+
+impl MemtableEntry {
+    /// Whether the entry is a value that has expired by the time given, in milliseconds since
+    /// the Unix epoch.
+    pub fn has_expired(&self, current_time: u64) -> bool {
+        matches!(self, MemtableEntry::ExpiringValue(_, expires_at) if *expires_at <= current_time)
+    }
+}
+
+/// An entry along with the sequence number of the write that made it, which orders it against
+/// every other write to the table. Entries read from memtable files and write ahead logs that
+/// predate sequence numbers have sequence number 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequencedEntry {
+    pub sequence_number: u64,
+    pub entry: MemtableEntry,
+}
+
+impl SequencedEntry {
+    /// Replaces a value that has expired by the time given with a tombstone, which goes on
+    /// hiding the older entries for its key that the value hid.
+    pub fn hide_if_expired(self, current_time: u64) -> Self {
+        match self.entry.has_expired(current_time) {
+            true => SequencedEntry {
+                sequence_number: self.sequence_number,
+                entry: MemtableEntry::Tombstone,
+            },
+            false => self,
+        }
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch, the form expiry times are held in.
+pub fn current_time_in_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time_since_epoch| time_since_epoch.as_millis() as u64)
+}
@@ -0,0 +1,379 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    bloom_filter::BloomFilter,
+    error::Result,
+    manifest::{ManifestFile, ManifestState},
+    memtable_config::MemtableConfig,
+    memtable_file_index::{read_memtable_file_blocks, MemtableFileBlocks, TEXT_FORMAT_VERSION},
+    memtable_file_iterator::MemtableFileIterator,
+    memtable_search_file::rebuild_key_offsets_from_file,
+    write_ahead_log::parse_write_ahead_log_number,
+};
+
+const MEMTABLE_FILE_EXTENSION: &str = "sst";
+const LEVEL_PREFIX: &str = "L";
+const TEMPORARY_FILE_EXTENSION: &str = "tmp";
+
+/// Keys found at every `key_offset_frequency`th entry of a memtable file, or in the block
+/// format the first key of every data block, paired with the byte offset of that entry or block.
+pub type KeyOffsets = Vec<(String, usize)>;
+
+/// A memtable that has been flushed to its own numbered file in the data directory, or the
+/// output of compacting such files. Flushes are written to level 0, where higher file numbers
+/// hold more recently written data.
+#[derive(Clone)]
+pub struct MemtableFile {
+    pub file_number: u64,
+    pub level: usize,
+    pub file_path: PathBuf,
+    pub key_offsets: Option<KeyOffsets>,
+    //Length of the entries, which are followed by the filter and index blocks
+    pub data_length: usize,
+    pub smallest_key: String,
+    pub largest_key: String,
+    pub bloom_filter: Option<BloomFilter>,
+    pub format_version: u32,
+}
+
+impl MemtableFile {
+    /// Checks the key against the file's key range and Bloom filter, both held in memory, so
+    /// the file only needs reading when this returns `true`.
+    pub fn may_contain_key(&self, key: &str) -> bool {
+        self.smallest_key.as_str() <= key
+            && key <= self.largest_key.as_str()
+            && self
+                .bloom_filter
+                .as_ref()
+                .is_none_or(|bloom_filter| bloom_filter.may_contain(key))
+    }
+
+    pub fn overlaps_key_range(&self, smallest_key: &str, largest_key: &str) -> bool {
+        self.smallest_key.as_str() <= largest_key && smallest_key <= self.largest_key.as_str()
+    }
+}
+
+pub fn memtable_file_path(data_directory: &str, file_number: u64) -> PathBuf {
+    leveled_memtable_file_path(data_directory, file_number, 0)
+}
+
+/// Files below level 0 carry their level in their name, such as `000012.L1.sst`.
+pub fn leveled_memtable_file_path(data_directory: &str, file_number: u64, level: usize) -> PathBuf {
+    let file_name = match level {
+        0 => format!("{:06}.{}", file_number, MEMTABLE_FILE_EXTENSION),
+        _ => format!(
+            "{:06}.{}{}.{}",
+            file_number, LEVEL_PREFIX, level, MEMTABLE_FILE_EXTENSION
+        ),
+    };
+    Path::new(data_directory).join(file_name)
+}
+
+/// The name a memtable file is written under before being renamed into place, such as
+/// `000012.sst.tmp`.
+pub fn temporary_memtable_file_path(file_path: &Path) -> PathBuf {
+    let mut file_name = file_path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".");
+    file_name.push(TEMPORARY_FILE_EXTENSION);
+    file_path.with_file_name(file_name)
+}
+
+/// Removes the temporary files of memtable files whose writes were interrupted by a crash.
+pub fn remove_temporary_memtable_files(data_directory: &str) -> Result<()> {
+    for entry in fs::read_dir(data_directory)? {
+        let file_path = entry?.path();
+        let is_temporary_memtable_file = file_path
+            .extension()
+            .is_some_and(|extension| extension == TEMPORARY_FILE_EXTENSION)
+            && parse_memtable_file_name(&file_path.with_extension("")).is_some();
+        if is_temporary_memtable_file {
+            fs::remove_file(&file_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the file number and level of a memtable file from its name.
+pub fn parse_memtable_file_name(file_path: &Path) -> Option<(u64, usize)> {
+    if file_path.extension()? != MEMTABLE_FILE_EXTENSION {
+        return None;
+    }
+    let file_stem = file_path.file_stem()?.to_str()?;
+    match file_stem.split_once('.') {
+        Some((file_number, level)) => Some((
+            file_number.parse::<u64>().ok()?,
+            level.strip_prefix(LEVEL_PREFIX)?.parse::<usize>().ok()?,
+        )),
+        None => Some((file_stem.parse::<u64>().ok()?, 0)),
+    }
+}
+
+pub fn parse_memtable_file_number(file_path: &Path) -> Option<u64> {
+    parse_memtable_file_name(file_path).map(|(file_number, _)| file_number)
+}
+
+/// Returns the numbers, levels and paths of every memtable file in the data directory, in
+/// file number order.
+pub fn find_memtable_files(data_directory: &str) -> Result<Vec<(u64, usize, PathBuf)>> {
+    let mut memtable_files = vec![];
+    for entry in fs::read_dir(data_directory)? {
+        let file_path = entry?.path();
+        if let Some((file_number, level)) = parse_memtable_file_name(&file_path) {
+            memtable_files.push((file_number, level, file_path));
+        }
+    }
+    memtable_files.sort();
+    Ok(memtable_files)
+}
+
+/// Loads a memtable file written by an earlier process. Its key offsets and Bloom filter come
+/// from its blocks, falling back to scanning the whole file for key offsets when it was
+/// written before index blocks were persisted, and its key range from its first and last
+/// entries.
+pub fn load_memtable_file(
+    memtable_config: &MemtableConfig,
+    file_number: u64,
+    level: usize,
+    file_path: PathBuf,
+) -> Result<MemtableFile> {
+    let memtable_file_blocks = read_memtable_file_blocks_or_rebuild(memtable_config, &file_path)?;
+    let MemtableFileBlocks {
+        key_offsets,
+        data_length,
+        format_version,
+        ..
+    } = &memtable_file_blocks;
+
+    let smallest_key = MemtableFileIterator::new(
+        memtable_config,
+        &file_path,
+        *format_version,
+        0,
+        *data_length,
+    )?
+    .next()
+    .transpose()?
+    .map(|(key, _)| key)
+    .unwrap_or_default();
+    //Only the segment after the last key offset needs reading to find the largest key
+    let last_segment_position = match key_offsets {
+        Some(key_offsets) => key_offsets.last().unwrap().1,
+        None => 0,
+    };
+    let mut largest_key = String::new();
+    for key_entry_pair in MemtableFileIterator::new(
+        memtable_config,
+        &file_path,
+        *format_version,
+        last_segment_position,
+        *data_length,
+    )? {
+        largest_key = key_entry_pair?.0;
+    }
+
+    Ok(memtable_file_from_blocks(
+        file_number,
+        level,
+        file_path,
+        memtable_file_blocks,
+        smallest_key,
+        largest_key,
+    ))
+}
+
+/// Loads a memtable file recorded in the manifest, which already holds its key range, so only
+/// its blocks are read.
+pub fn load_manifest_file(
+    memtable_config: &MemtableConfig,
+    manifest_file: &ManifestFile,
+) -> Result<MemtableFile> {
+    let file_path = leveled_memtable_file_path(
+        &memtable_config.data_directory,
+        manifest_file.file_number,
+        manifest_file.level,
+    );
+    let memtable_file_blocks = read_memtable_file_blocks_or_rebuild(memtable_config, &file_path)?;
+    Ok(memtable_file_from_blocks(
+        manifest_file.file_number,
+        manifest_file.level,
+        file_path,
+        memtable_file_blocks,
+        manifest_file.smallest_key.clone(),
+        manifest_file.largest_key.clone(),
+    ))
+}
+
+fn read_memtable_file_blocks_or_rebuild(
+    memtable_config: &MemtableConfig,
+    file_path: &Path,
+) -> Result<MemtableFileBlocks> {
+    match read_memtable_file_blocks(file_path)? {
+        Some(memtable_file_blocks) => Ok(memtable_file_blocks),
+        None => {
+            let file_length = fs::metadata(file_path)?.len() as usize;
+            Ok(MemtableFileBlocks {
+                key_offsets: rebuild_key_offsets_from_file(
+                    memtable_config,
+                    file_path,
+                    file_length,
+                )?,
+                bloom_filter: None,
+                data_length: file_length,
+                format_version: TEXT_FORMAT_VERSION,
+            })
+        }
+    }
+}
+
+fn memtable_file_from_blocks(
+    file_number: u64,
+    level: usize,
+    file_path: PathBuf,
+    memtable_file_blocks: MemtableFileBlocks,
+    smallest_key: String,
+    largest_key: String,
+) -> MemtableFile {
+    MemtableFile {
+        file_number,
+        level,
+        file_path,
+        key_offsets: memtable_file_blocks.key_offsets,
+        data_length: memtable_file_blocks.data_length,
+        smallest_key,
+        largest_key,
+        bloom_filter: memtable_file_blocks.bloom_filter,
+        format_version: memtable_file_blocks.format_version,
+    }
+}
+
+/// Removes every memtable file in the data directory that the manifest does not list as
+/// live, such as the inputs of a compaction that committed before they were removed or the
+/// output of one that never committed.
+pub fn remove_obsolete_memtable_files(
+    data_directory: &str,
+    manifest_state: &ManifestState,
+) -> Result<()> {
+    for (file_number, level, file_path) in find_memtable_files(data_directory)? {
+        if !manifest_state.is_live(level, file_number) {
+            fs::remove_file(file_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the first file number not yet used by any memtable file or write ahead log in the
+/// data directory, so that a new flush never overwrites an existing one.
+pub fn next_memtable_file_number(data_directory: &str) -> Result<u64> {
+    let mut highest_existing_file_number = None;
+    for entry in fs::read_dir(data_directory)? {
+        let file_path = entry?.path();
+        let file_number = parse_memtable_file_number(&file_path)
+            .or_else(|| parse_write_ahead_log_number(&file_path));
+        highest_existing_file_number = highest_existing_file_number.max(file_number);
+    }
+    match highest_existing_file_number {
+        Some(file_number) => Ok(file_number + 1),
+        None => Ok(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::{test_utils::test_data_directory, write_ahead_log::write_ahead_log_path};
+
+    use super::{
+        find_memtable_files, leveled_memtable_file_path, memtable_file_path,
+        next_memtable_file_number, parse_memtable_file_name, parse_memtable_file_number,
+        remove_temporary_memtable_files, temporary_memtable_file_path,
+    };
+
+    #[test]
+    fn memtable_file_path_is_zero_padded() {
+        let file_path = memtable_file_path("./output", 12);
+        assert_eq!(file_path, Path::new("./output/000012.sst"));
+    }
+
+    #[test]
+    fn parse_memtable_file_number_ignores_other_files() {
+        assert_eq!(
+            parse_memtable_file_number(Path::new("./output/000012.sst")),
+            Some(12)
+        );
+        assert_eq!(
+            parse_memtable_file_number(Path::new("./output/000012.txt")),
+            None
+        );
+        assert_eq!(
+            parse_memtable_file_number(Path::new("./output/main.sst")),
+            None
+        );
+    }
+
+    #[test]
+    fn leveled_memtable_file_path_round_trip() {
+        let file_path = leveled_memtable_file_path("./output", 12, 2);
+        assert_eq!(file_path, Path::new("./output/000012.L2.sst"));
+        assert_eq!(parse_memtable_file_name(&file_path), Some((12, 2)));
+        assert_eq!(
+            parse_memtable_file_name(&memtable_file_path("./output", 3)),
+            Some((3, 0))
+        );
+        assert_eq!(
+            parse_memtable_file_name(Path::new("./output/000012.X2.sst")),
+            None
+        );
+    }
+
+    #[test]
+    fn find_memtable_files_in_order() {
+        let data_directory = test_data_directory("find_memtable_files");
+        fs::write(memtable_file_path(&data_directory, 10), "").unwrap();
+        fs::write(leveled_memtable_file_path(&data_directory, 9, 1), "").unwrap();
+        fs::write(write_ahead_log_path(&data_directory, 11), "").unwrap();
+        let file_numbers_and_levels: Vec<(u64, usize)> = find_memtable_files(&data_directory)
+            .unwrap()
+            .into_iter()
+            .map(|(file_number, level, _)| (file_number, level))
+            .collect();
+        assert_eq!(file_numbers_and_levels, vec![(9, 1), (10, 0)]);
+    }
+
+    #[test]
+    fn next_memtable_file_number_follows_highest_existing() {
+        let data_directory = test_data_directory("memtable_file_next_number");
+        assert_eq!(next_memtable_file_number(&data_directory).unwrap(), 1);
+        fs::write(memtable_file_path(&data_directory, 1), "").unwrap();
+        fs::write(memtable_file_path(&data_directory, 7), "").unwrap();
+        assert_eq!(next_memtable_file_number(&data_directory).unwrap(), 8);
+        fs::write(write_ahead_log_path(&data_directory, 9), "").unwrap();
+        assert_eq!(next_memtable_file_number(&data_directory).unwrap(), 10);
+    }
+
+    #[test]
+    fn remove_temporary_memtable_files_keeps_other_files() {
+        let data_directory = test_data_directory("memtable_file_remove_temporary");
+        let memtable_file_path = memtable_file_path(&data_directory, 1);
+        let temporary_file_path =
+            temporary_memtable_file_path(&leveled_memtable_file_path(&data_directory, 2, 1));
+        let other_temporary_file_path = Path::new(&data_directory).join("notes.tmp");
+        assert_eq!(
+            temporary_file_path,
+            Path::new(&data_directory).join("000002.L1.sst.tmp")
+        );
+        for file_path in [
+            &memtable_file_path,
+            &temporary_file_path,
+            &other_temporary_file_path,
+        ] {
+            fs::write(file_path, "").unwrap();
+        }
+        remove_temporary_memtable_files(&data_directory).unwrap();
+        assert!(memtable_file_path.exists());
+        assert!(!temporary_file_path.exists());
+        assert!(other_temporary_file_path.exists());
+    }
+}
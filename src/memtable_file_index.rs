@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use crate::{
+    bloom_filter::BloomFilter,
+    crc32c::crc32c,
+    error::{Error, Result},
+    memtable_file::KeyOffsets,
+};
+
+//Every footer ends in the format version and magic number, which give the size of the rest
+const FOOTER_TRAILER_SIZE: usize = 8;
+const BLOCK_HANDLE_SIZE: usize = 16;
+pub const BLOCK_CHECKSUM_SIZE: usize = 4;
+const MEMTABLE_FILE_MAGIC: u32 = 0x5353_5442;
+//Key value lines followed by the index block
+pub const TEXT_FORMAT_VERSION: u32 = 1;
+//Adds a filter block ahead of the index block
+pub const FILTER_FORMAT_VERSION: u32 = 2;
+//Replaces the key value lines with length prefixed entries, laid out as in the filter format
+pub const BINARY_FORMAT_VERSION: u32 = 3;
+//Groups the binary entries into data blocks with shared key prefixes, indexed by block
+pub const BLOCK_FORMAT_VERSION: u32 = 4;
+//Follows every data, filter and index block with a CRC32C of its contents
+pub const CHECKSUM_FORMAT_VERSION: u32 = 5;
+//Stores the sequence number of each data block entry after its key
+pub const SEQUENCE_FORMAT_VERSION: u32 = 6;
+
+/// Trailer at the very end of a memtable file, locating the blocks written after the key
+/// value lines. Files of the first format version have no filter block, which is then
+/// recorded as empty.
+#[derive(Debug, PartialEq)]
+pub struct MemtableFileFooter {
+    pub index_block_offset: u64,
+    pub index_block_length: u64,
+    pub filter_block_offset: u64,
+    pub filter_block_length: u64,
+    pub format_version: u32,
+}
+
+impl MemtableFileFooter {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(footer_size(self.format_version));
+        footer.extend_from_slice(&self.index_block_offset.to_le_bytes());
+        footer.extend_from_slice(&self.index_block_length.to_le_bytes());
+        if self.format_version >= FILTER_FORMAT_VERSION {
+            footer.extend_from_slice(&self.filter_block_offset.to_le_bytes());
+            footer.extend_from_slice(&self.filter_block_length.to_le_bytes());
+        }
+        footer.extend_from_slice(&self.format_version.to_le_bytes());
+        footer.extend_from_slice(&MEMTABLE_FILE_MAGIC.to_le_bytes());
+        footer
+    }
+
+    /// Decodes the footer from the end of `file_tail`. Returns `None` when the bytes do not end
+    /// in the magic number, as is the case for files written before the index was persisted,
+    /// or are too short for the footer of their format version.
+    pub fn decode(file_tail: &[u8]) -> Option<Self> {
+        let trailer = file_tail.get(file_tail.len().checked_sub(FOOTER_TRAILER_SIZE)?..)?;
+        let magic = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+        if magic != MEMTABLE_FILE_MAGIC {
+            return None;
+        }
+        let format_version = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+        let footer = &file_tail[file_tail.len().checked_sub(footer_size(format_version))?..];
+        let read_u64 = |position: usize| {
+            u64::from_le_bytes(footer[position..position + 8].try_into().unwrap())
+        };
+        let (filter_block_offset, filter_block_length) = match format_version {
+            TEXT_FORMAT_VERSION => (0, 0),
+            _ => (read_u64(16), read_u64(24)),
+        };
+        Some(MemtableFileFooter {
+            index_block_offset: read_u64(0),
+            index_block_length: read_u64(8),
+            filter_block_offset,
+            filter_block_length,
+            format_version,
+        })
+    }
+}
+
+/// The blocks read back from the end of a memtable file.
+pub struct MemtableFileBlocks {
+    pub key_offsets: Option<KeyOffsets>,
+    pub bloom_filter: Option<BloomFilter>,
+    //Length of the entries preceding the blocks
+    pub data_length: usize,
+    pub format_version: u32,
+}
+
+/// Appends the filter block, index block and footer after `data_length` bytes of entries
+/// written in the given format version. The footer records the length of each block without
+/// the checksum that follows it.
+pub fn write_blocks_and_footer<W: Write>(
+    output: &mut W,
+    bloom_filter: Option<&BloomFilter>,
+    key_offsets: &[(String, usize)],
+    data_length: usize,
+    format_version: u32,
+) -> Result<()> {
+    let filter_block = bloom_filter
+        .map(|bloom_filter| bloom_filter.encode())
+        .unwrap_or_default();
+    let index_block = create_index_block(key_offsets);
+    let checksum_size = match has_block_checksums(format_version) {
+        true => BLOCK_CHECKSUM_SIZE,
+        false => 0,
+    };
+    let footer = MemtableFileFooter {
+        filter_block_offset: data_length as u64,
+        filter_block_length: filter_block.len() as u64,
+        index_block_offset: (data_length + filter_block.len() + checksum_size) as u64,
+        index_block_length: index_block.len() as u64,
+        format_version,
+    };
+    for block in [filter_block, index_block] {
+        output.write_all(&block)?;
+        if has_block_checksums(format_version) {
+            output.write_all(&crc32c(&block).to_le_bytes())?;
+        }
+    }
+    output.write_all(&footer.encode())?;
+    Ok(())
+}
+
+/// Loads the key offsets and Bloom filter of a memtable file from its blocks. Returns `None`
+/// for files without a footer.
+pub fn read_memtable_file_blocks(file_path: &Path) -> Result<Option<MemtableFileBlocks>> {
+    let mut memtable_file = File::open(file_path)?;
+    let file_length = memtable_file.metadata()?.len();
+    let file_tail_length = file_length.min(footer_size(SEQUENCE_FORMAT_VERSION) as u64);
+    memtable_file.seek(io::SeekFrom::End(-(file_tail_length as i64)))?;
+    let mut file_tail = vec![0; file_tail_length as usize];
+    memtable_file.read_exact(&mut file_tail)?;
+    let footer = match MemtableFileFooter::decode(&file_tail) {
+        Some(footer) => footer,
+        None => return Ok(None),
+    };
+    if footer.format_version > SEQUENCE_FORMAT_VERSION {
+        return Err(Error::Corruption(format!(
+            "{} has unsupported format version {}",
+            file_path.display(),
+            footer.format_version
+        )));
+    }
+
+    let checksum_size = match has_block_checksums(footer.format_version) {
+        true => BLOCK_CHECKSUM_SIZE as u64,
+        false => 0,
+    };
+    let mut read_block = |block_offset: u64, block_length: u64| -> Result<Vec<u8>> {
+        if block_offset
+            .saturating_add(block_length)
+            .saturating_add(checksum_size)
+            > file_length
+        {
+            return Err(Error::Corruption(format!(
+                "{} has a block past the end of the file",
+                file_path.display()
+            )));
+        }
+        memtable_file.seek(io::SeekFrom::Start(block_offset))?;
+        let mut block = vec![0; (block_length + checksum_size) as usize];
+        memtable_file.read_exact(&mut block)?;
+        if checksum_size > 0 {
+            let stored_checksum = block.split_off(block_length as usize);
+            check_block_checksum(&block, &stored_checksum).map_err(|_| {
+                Error::ChecksumMismatch(format!(
+                    "{} has a bad block at offset {}",
+                    file_path.display(),
+                    block_offset
+                ))
+            })?;
+        }
+        Ok(block)
+    };
+    let index_block = read_block(footer.index_block_offset, footer.index_block_length)?;
+    let key_offsets = parse_index_block(&index_block).ok_or_else(|| {
+        Error::Corruption(format!(
+            "{} has a malformed index block",
+            file_path.display()
+        ))
+    })?;
+    let key_offsets = if !key_offsets.is_empty() {
+        Some(key_offsets)
+    } else {
+        None
+    };
+    let filter_block = read_block(footer.filter_block_offset, footer.filter_block_length)?;
+    let bloom_filter = BloomFilter::decode(&filter_block);
+    //The filter block, when there is one, directly follows the key value lines
+    let data_length = match footer.format_version {
+        TEXT_FORMAT_VERSION => footer.index_block_offset,
+        _ => footer.filter_block_offset,
+    };
+    Ok(Some(MemtableFileBlocks {
+        key_offsets,
+        bloom_filter,
+        data_length: data_length as usize,
+        format_version: footer.format_version,
+    }))
+}
+
+pub fn has_data_blocks(format_version: u32) -> bool {
+    format_version >= BLOCK_FORMAT_VERSION
+}
+
+pub fn has_block_checksums(format_version: u32) -> bool {
+    format_version >= CHECKSUM_FORMAT_VERSION
+}
+
+pub fn has_sequence_numbers(format_version: u32) -> bool {
+    format_version >= SEQUENCE_FORMAT_VERSION
+}
+
+/// Compares the block's CRC32C with the checksum stored after it.
+pub fn check_block_checksum(block: &[u8], stored_checksum: &[u8]) -> Result<()> {
+    match stored_checksum == crc32c(block).to_le_bytes() {
+        true => Ok(()),
+        false => Err(Error::ChecksumMismatch(
+            "block does not match its checksum".to_owned(),
+        )),
+    }
+}
+
+fn footer_size(format_version: u32) -> usize {
+    match format_version {
+        TEXT_FORMAT_VERSION => BLOCK_HANDLE_SIZE + FOOTER_TRAILER_SIZE,
+        _ => 2 * BLOCK_HANDLE_SIZE + FOOTER_TRAILER_SIZE,
+    }
+}
+
+fn create_index_block(key_offsets: &[(String, usize)]) -> Vec<u8> {
+    let mut index_block = vec![];
+    key_offsets.iter().for_each(|(key, offset)| {
+        index_block.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        index_block.extend_from_slice(key.as_bytes());
+        index_block.extend_from_slice(&(*offset as u64).to_le_bytes());
+    });
+    index_block
+}
+
+//Returns `None` when an entry runs past the end of the block
+fn parse_index_block(index_block: &[u8]) -> Option<KeyOffsets> {
+    let mut key_offsets = vec![];
+    let mut position = 0;
+    while position < index_block.len() {
+        let key_length =
+            u32::from_le_bytes(index_block.get(position..position + 4)?.try_into().unwrap())
+                as usize;
+        position += 4;
+        let key_bytes = index_block.get(position..position + key_length)?;
+        let key = String::from_utf8(key_bytes.to_vec()).ok()?;
+        position += key_length;
+        let offset =
+            u64::from_le_bytes(index_block.get(position..position + 8)?.try_into().unwrap())
+                as usize;
+        position += 8;
+        key_offsets.push((key, offset));
+    }
+    Some(key_offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_index_block, parse_index_block, MemtableFileFooter};
+
+    #[test]
+    fn footer_round_trip() {
+        let footer = MemtableFileFooter {
+            index_block_offset: 130,
+            index_block_length: 36,
+            filter_block_offset: 120,
+            filter_block_length: 10,
+            format_version: 2,
+        };
+        let mut file_tail = b"A:1\n".to_vec();
+        file_tail.extend_from_slice(&footer.encode());
+        assert_eq!(MemtableFileFooter::decode(&file_tail), Some(footer));
+    }
+
+    #[test]
+    fn footer_of_first_format_version_has_no_filter_block() {
+        let footer = MemtableFileFooter {
+            index_block_offset: 120,
+            index_block_length: 36,
+            filter_block_offset: 0,
+            filter_block_length: 0,
+            format_version: 1,
+        };
+        let encoded_footer = footer.encode();
+        assert_eq!(encoded_footer.len(), 24);
+        assert_eq!(MemtableFileFooter::decode(&encoded_footer), Some(footer));
+    }
+
+    #[test]
+    fn footer_without_magic_is_not_decoded() {
+        let footer = [b'A'; 24];
+        assert_eq!(MemtableFileFooter::decode(&footer), None);
+    }
+
+    #[test]
+    fn index_block_round_trip() {
+        let key_offsets = vec![("F".to_string(), 20), ("Key:K".to_string(), 40)];
+        let index_block = create_index_block(&key_offsets);
+        assert_eq!(parse_index_block(&index_block), Some(key_offsets));
+        assert_eq!(parse_index_block(&index_block[..10]), None);
+    }
+}
@@ -0,0 +1,280 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, Take};
+use std::path::Path;
+
+use crate::data_block::read_framed_data_block;
+use crate::error::{Error, Result};
+use crate::memtable_config::MemtableConfig;
+use crate::memtable_entry::{MemtableEntry, SequencedEntry};
+use crate::memtable_file_index::{has_data_blocks, BINARY_FORMAT_VERSION};
+use crate::memtable_write_to_file::{TOMBSTONE_ENTRY_TYPE, VALUE_ENTRY_TYPE};
+use crate::varint::read_varint;
+
+/// Reads the entries of a memtable file in key order, starting from an entry offset such as
+/// one taken from the file's key offsets, or in the block format the offset of a data block.
+/// A read error is returned in place of the next entry and ends the iteration. Entries of
+/// format versions without sequence numbers are given sequence number 0.
+pub struct MemtableFileIterator {
+    reader: BufReader<Take<File>>,
+    format_version: u32,
+    key_offset_indicator: char,
+    key_value_delimeter: char,
+    line_offset: usize,
+    //Entries of the data block being read, in the block format
+    block_entries: std::vec::IntoIter<(String, SequencedEntry)>,
+    has_failed: bool,
+}
+
+impl MemtableFileIterator {
+    pub fn new(
+        memtable_config: &MemtableConfig,
+        file_path: &Path,
+        format_version: u32,
+        start_position: usize,
+        data_length: usize,
+    ) -> Result<Self> {
+        let mut memtable_file = File::open(file_path)?;
+        memtable_file.seek(io::SeekFrom::Start(start_position as u64))?;
+        //The index block follows the entries, so reading stops where they end
+        let reader = BufReader::new(memtable_file.take((data_length - start_position) as u64));
+        Ok(MemtableFileIterator {
+            reader,
+            format_version,
+            key_offset_indicator: memtable_config.key_offset_indicator,
+            key_value_delimeter: memtable_config.key_value_delimeter,
+            line_offset: start_position,
+            block_entries: Vec::new().into_iter(),
+            has_failed: false,
+        })
+    }
+
+    fn read_text_entry(&mut self) -> Result<Option<(String, SequencedEntry)>> {
+        let mut line_as_string = String::new();
+        let bytes_read = self.reader.read_line(&mut line_as_string)?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        //Only lines after the first in the file can be key offset lines
+        let is_key_offset_line =
+            self.line_offset != 0 && line_as_string.starts_with(self.key_offset_indicator);
+        self.line_offset += bytes_read;
+
+        let line_key_value = match is_key_offset_line {
+            true => &line_as_string[self.key_offset_indicator.len_utf8()..],
+            false => &line_as_string[..],
+        }
+        .trim_end_matches('\n');
+        //A line without a delimiter is a tombstone for its key
+        let (key, entry) = match line_key_value.find(self.key_value_delimeter) {
+            Some(delimiter_position) => (
+                line_key_value[..delimiter_position].to_string(),
+                MemtableEntry::Value(line_key_value[delimiter_position + 1..].to_string()),
+            ),
+            None => (line_key_value.to_string(), MemtableEntry::Tombstone),
+        };
+        Ok(Some((key, unsequenced_entry(entry))))
+    }
+
+    fn read_block_entry(&mut self) -> Result<Option<(String, SequencedEntry)>> {
+        loop {
+            if let Some(key_entry_pair) = self.block_entries.next() {
+                return Ok(Some(key_entry_pair));
+            }
+            match read_framed_data_block(&mut self.reader, self.format_version)? {
+                Some(data_block) => self.block_entries = data_block.entries()?.into_iter(),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn read_binary_entry(&mut self) -> Result<Option<(String, SequencedEntry)>> {
+        let mut entry_type = [0; 1];
+        if self.reader.read(&mut entry_type)? == 0 {
+            return Ok(None);
+        }
+        let key = read_length_prefixed_string(&mut self.reader)?;
+        let entry = match entry_type[0] {
+            VALUE_ENTRY_TYPE => {
+                MemtableEntry::Value(read_length_prefixed_string(&mut self.reader)?)
+            }
+            TOMBSTONE_ENTRY_TYPE => MemtableEntry::Tombstone,
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "unknown memtable file entry type {}",
+                    entry_type[0]
+                )))
+            }
+        };
+        Ok(Some((key, unsequenced_entry(entry))))
+    }
+}
+
+impl Iterator for MemtableFileIterator {
+    type Item = Result<(String, SequencedEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.has_failed {
+            return None;
+        }
+        let read_result = match self.format_version {
+            format_version if has_data_blocks(format_version) => self.read_block_entry(),
+            BINARY_FORMAT_VERSION => self.read_binary_entry(),
+            _ => self.read_text_entry(),
+        };
+        match read_result {
+            Ok(key_entry_pair) => key_entry_pair.map(Ok),
+            Err(error) => {
+                self.has_failed = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+fn unsequenced_entry(entry: MemtableEntry) -> SequencedEntry {
+    SequencedEntry {
+        sequence_number: 0,
+        entry,
+    }
+}
+
+//The entries are bounded by the data length, so one ending part way through is corrupt
+fn read_length_prefixed_string<R: Read>(reader: &mut R) -> Result<String> {
+    let read_bytes = |reader: &mut R| -> io::Result<Vec<u8>> {
+        let length = read_varint(reader)?;
+        let mut string_bytes = vec![];
+        reader
+            .by_ref()
+            .take(length)
+            .read_to_end(&mut string_bytes)?;
+        if string_bytes.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(string_bytes)
+    };
+    let string_bytes = read_bytes(reader).map_err(|error| match error.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            Error::Corruption("memtable file entry is cut short".to_owned())
+        }
+        io::ErrorKind::InvalidData => Error::Corruption(format!(
+            "memtable file entry length is malformed: {}",
+            error
+        )),
+        _ => Error::Io(error),
+    })?;
+    String::from_utf8(string_bytes)
+        .map_err(|_| Error::Corruption("memtable file entry is not valid UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        error::Error,
+        memtable::Memtable,
+        memtable_config::MemtableConfig,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+        memtable_file::memtable_file_path,
+        memtable_file_index::{BINARY_FORMAT_VERSION, TEXT_FORMAT_VERSION},
+        test_utils::test_data_directory,
+    };
+
+    use super::MemtableFileIterator;
+
+    #[test]
+    fn memtable_file_iterator_reads_entries_in_order() {
+        let data_directory = test_data_directory("memtable_file_iterator_in_order");
+        let config = MemtableConfig::new(8, &data_directory);
+        let config_clone = config.clone();
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        for key in ["H", "B", "C", "D", "E", "F", "G"] {
+            memtable.insert(key.to_string(), "1").unwrap();
+        }
+        let written_file = memtable.remove("A".to_string()).unwrap().unwrap();
+
+        let entries: Vec<(String, SequencedEntry)> = MemtableFileIterator::new(
+            &config_clone,
+            &written_file.file_path,
+            written_file.format_version,
+            0,
+            written_file.data_length,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(entries.len(), 8);
+        //Each write was given the next sequence number, the removal of A being the last
+        let sequenced_entry = |sequence_number, entry| SequencedEntry {
+            sequence_number,
+            entry,
+        };
+        assert_eq!(
+            entries[0],
+            (
+                "A".to_string(),
+                sequenced_entry(8, MemtableEntry::Tombstone)
+            )
+        );
+        assert_eq!(
+            entries[5],
+            (
+                "F".to_string(),
+                sequenced_entry(6, MemtableEntry::Value("1".to_string()))
+            )
+        );
+        assert_eq!(entries[7].0, "H");
+    }
+
+    #[test]
+    fn memtable_file_iterator_returns_read_error_once() {
+        let data_directory = test_data_directory("memtable_file_iterator_read_error");
+        let config = MemtableConfig::new(8, &data_directory);
+        let file_path = memtable_file_path(&data_directory, 1);
+        fs::write(&file_path, b"A:1\nB:\xff\nC:1\n").unwrap();
+
+        let mut memtable_file_iterator =
+            MemtableFileIterator::new(&config, &file_path, TEXT_FORMAT_VERSION, 0, 14).unwrap();
+        assert!(matches!(memtable_file_iterator.next(), Some(Ok(_))));
+        assert!(matches!(
+            memtable_file_iterator.next(),
+            Some(Err(Error::Io(_)))
+        ));
+        assert!(memtable_file_iterator.next().is_none());
+        assert!(MemtableFileIterator::new(
+            &config,
+            &memtable_file_path(&data_directory, 2),
+            TEXT_FORMAT_VERSION,
+            0,
+            0
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn memtable_file_iterator_starts_from_key_offset() {
+        let data_directory = test_data_directory("memtable_file_iterator_from_key_offset");
+        let mut config = MemtableConfig::new(8, &data_directory);
+        config.format_version = BINARY_FORMAT_VERSION;
+        let config_clone = config.clone();
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        let mut written_file = None;
+        for key in ["A", "B", "C", "D", "E", "F", "G", "H"] {
+            written_file = memtable.insert(key.to_string(), "1").unwrap();
+        }
+        let written_file = written_file.unwrap();
+        let key_offsets = written_file.key_offsets.unwrap();
+
+        let keys: Vec<String> = MemtableFileIterator::new(
+            &config_clone,
+            &written_file.file_path,
+            written_file.format_version,
+            key_offsets[0].1,
+            written_file.data_length,
+        )
+        .unwrap()
+        .map(|key_entry_pair| key_entry_pair.unwrap().0)
+        .collect();
+        assert_eq!(keys, vec!["F", "G", "H"]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use crate::{
+    data_block::read_framed_data_block,
+    error::{Error, Result},
+    memtable_config::MemtableConfig,
+    memtable_file::MemtableFile,
+    memtable_file_index::{has_data_blocks, read_memtable_file_blocks},
+    memtable_file_iterator::MemtableFileIterator,
+};
+
+/// A block of a memtable file that could not be read back intact.
+#[derive(Debug)]
+pub struct CorruptBlock {
+    pub file_path: PathBuf,
+    //Offset of the data block, or `None` when the filter block, index block or footer is bad
+    pub block_offset: Option<usize>,
+    pub error: Error,
+}
+
+/// Reads every block of the memtable file, comparing each with its checksum in the formats
+/// that store one, and returns those that are bad. Files written before data blocks were
+/// introduced are checked as a single block at offset 0. An error reading the file itself,
+/// rather than a problem with its contents, is returned as an error.
+pub fn verify_memtable_file(
+    memtable_config: &MemtableConfig,
+    memtable_file: &MemtableFile,
+) -> Result<Vec<CorruptBlock>> {
+    let mut corrupt_blocks = vec![];
+    if let Err(error) = read_memtable_file_blocks(&memtable_file.file_path) {
+        report_corrupt_block(&mut corrupt_blocks, memtable_file, None, error)?;
+    }
+
+    if !has_data_blocks(memtable_file.format_version) {
+        let memtable_file_iterator = MemtableFileIterator::new(
+            memtable_config,
+            &memtable_file.file_path,
+            memtable_file.format_version,
+            0,
+            memtable_file.data_length,
+        )?;
+        for key_entry_pair in memtable_file_iterator {
+            if let Err(error) = key_entry_pair {
+                report_corrupt_block(&mut corrupt_blocks, memtable_file, Some(0), error)?;
+            }
+        }
+        return Ok(corrupt_blocks);
+    }
+
+    let mut data = vec![0; memtable_file.data_length];
+    File::open(&memtable_file.file_path)?.read_exact(&mut data)?;
+    //Reading a block leaves the slice at the next one, even when the block turns out to be bad
+    let mut remaining_data = &data[..];
+    loop {
+        let block_offset = data.len() - remaining_data.len();
+        let read_result = read_framed_data_block(&mut remaining_data, memtable_file.format_version)
+            .and_then(|data_block| match data_block {
+                Some(data_block) => data_block.entries().map(Some),
+                None => Ok(None),
+            });
+        match read_result {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(error) => report_corrupt_block(
+                &mut corrupt_blocks,
+                memtable_file,
+                Some(block_offset),
+                error,
+            )?,
+        }
+    }
+    Ok(corrupt_blocks)
+}
+
+//Bytes that are not valid UTF-8 in a text format file surface as invalid data from the reader
+fn report_corrupt_block(
+    corrupt_blocks: &mut Vec<CorruptBlock>,
+    memtable_file: &MemtableFile,
+    block_offset: Option<usize>,
+    error: Error,
+) -> Result<()> {
+    match error {
+        Error::Io(io_error) if io_error.kind() != io::ErrorKind::InvalidData => {
+            Err(Error::Io(io_error))
+        }
+        _ => {
+            corrupt_blocks.push(CorruptBlock {
+                file_path: memtable_file.file_path.clone(),
+                block_offset,
+                error,
+            });
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        error::Error, memtable::Memtable, memtable_config::MemtableConfig,
+        memtable_file_index::FILTER_FORMAT_VERSION, test_utils::test_data_directory,
+    };
+
+    use super::verify_memtable_file;
+
+    #[test]
+    fn verify_memtable_file_reports_bad_index_block() {
+        let data_directory = test_data_directory("verify_memtable_file_index_block");
+        let config = MemtableConfig::new(2, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config.clone()).unwrap();
+        memtable.insert("A".to_string(), "1").unwrap();
+        let written_file = memtable.insert("B".to_string(), "1").unwrap().unwrap();
+        assert!(verify_memtable_file(&config, &written_file)
+            .unwrap()
+            .is_empty());
+
+        //The footer is 40 bytes, directly preceded by the index block's checksum
+        let mut file_contents = fs::read(&written_file.file_path).unwrap();
+        let index_block_checksum_byte = file_contents.len() - 41;
+        file_contents[index_block_checksum_byte] ^= 1;
+        fs::write(&written_file.file_path, file_contents).unwrap();
+        let corrupt_blocks = verify_memtable_file(&config, &written_file).unwrap();
+        assert_eq!(corrupt_blocks.len(), 1);
+        assert_eq!(corrupt_blocks[0].block_offset, None);
+        assert!(matches!(
+            corrupt_blocks[0].error,
+            Error::ChecksumMismatch(_)
+        ));
+    }
+
+    #[test]
+    fn verify_memtable_file_checks_text_format_as_one_block() {
+        let data_directory = test_data_directory("verify_memtable_file_text_format");
+        let mut config = MemtableConfig::new(2, &data_directory);
+        config.format_version = FILTER_FORMAT_VERSION;
+        let mut memtable = Memtable::<String, &str>::new(config.clone()).unwrap();
+        memtable.insert("A".to_string(), "1").unwrap();
+        let written_file = memtable.insert("B".to_string(), "1").unwrap().unwrap();
+
+        let mut file_contents = fs::read(&written_file.file_path).unwrap();
+        file_contents[2] = 0xff;
+        fs::write(&written_file.file_path, file_contents).unwrap();
+        let corrupt_blocks = verify_memtable_file(&config, &written_file).unwrap();
+        assert_eq!(corrupt_blocks.len(), 1);
+        assert_eq!(corrupt_blocks[0].block_offset, Some(0));
+    }
+}
@@ -1,12 +1,18 @@
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Seek};
+use std::io::{self, BufRead, BufReader, Read, Seek};
+use std::path::Path;
 
+use crate::data_block::read_framed_data_block;
+use crate::error::Result;
 use crate::memtable_config::MemtableConfig;
+use crate::memtable_entry::{MemtableEntry, SequencedEntry};
+use crate::memtable_file_index::{has_data_blocks, BINARY_FORMAT_VERSION};
+use crate::memtable_file_iterator::MemtableFileIterator;
 use crate::sorted_string_table::StringLike;
 
 pub fn determine_file_search_start_position<K>(
     key_to_find: &K,
-    key_offsets_of_most_recent_written_memtable: &Vec<(K, usize)>,
+    key_offsets_of_most_recent_written_memtable: &[(K, usize)],
 ) -> usize
 where
     K: StringLike,
@@ -34,43 +40,166 @@ where
 pub fn search_file_for_key_from_starting_position_until_next_offset<K>(
     key_to_find: &K,
     memtable_config: &MemtableConfig,
+    file_path: &Path,
+    format_version: u32,
     search_start_position: usize,
-) -> Option<String>
+    data_length: usize,
+) -> Result<Option<SequencedEntry>>
 where
     K: StringLike,
 {
-    let mut most_recent_memtable_written = File::open(&memtable_config.file_path).unwrap();
-    most_recent_memtable_written.seek(io::SeekFrom::Start(search_start_position as u64));
-
-    let reader = BufReader::new(most_recent_memtable_written);
-    let mut line_number = 0;
-    for line in reader.lines() {
-        let line_as_string = line.unwrap();
-        let first_char_in_line = line_as_string.chars().nth(0).unwrap();
-        let end_of_segment =
-            first_char_in_line == memtable_config.key_offset_indicator && line_number != 0;
+    match format_version {
+        format_version if has_data_blocks(format_version) => {
+            return search_data_block_for_key(
+                key_to_find,
+                file_path,
+                format_version,
+                search_start_position,
+                data_length,
+            )
+        }
+        BINARY_FORMAT_VERSION => {
+            return search_binary_file_for_key(
+                key_to_find,
+                memtable_config,
+                file_path,
+                search_start_position,
+                data_length,
+            )
+        }
+        _ => {}
+    }
+    let mut memtable_file = File::open(file_path)?;
+    memtable_file.seek(io::SeekFrom::Start(search_start_position as u64))?;
+
+    //The index block follows the key value lines, so reading stops where they end
+    let reader = BufReader::new(memtable_file.take((data_length - search_start_position) as u64));
+    for (line_number, line) in reader.lines().enumerate() {
+        let line_as_string = line?;
+        //An empty line is the tombstone of an empty key
+        let starts_with_offset_indicator =
+            line_as_string.starts_with(memtable_config.key_offset_indicator);
+        let end_of_segment = starts_with_offset_indicator && line_number != 0;
         if end_of_segment {
-            return None;
+            return Ok(None);
         }
+        //Only a search starting from a key offset begins on a line carrying the indicator
+        let line_key_value = if starts_with_offset_indicator && search_start_position != 0 {
+            &line_as_string[memtable_config.key_offset_indicator.len_utf8()..]
+        } else {
+            &line_as_string[..]
+        };
         //Not at end of segment - need to parse key from line and compare
-        let delimiter_position = line_as_string
-            .find(memtable_config.key_value_delimeter)
-            .unwrap();
-        if check_key_equality(&line_as_string, delimiter_position, key_to_find) {
-            let parsed_value_as_str =
-                parse_value_as_string_type_from_line(&line_as_string, delimiter_position);
-            return Some(parsed_value_as_str.to_string());
+        //A line without a delimiter is a tombstone for its key
+        let delimiter_position = line_key_value.find(memtable_config.key_value_delimeter);
+        let key_end_position = delimiter_position.unwrap_or(line_key_value.len());
+        if check_key_equality(line_key_value, key_end_position, key_to_find) {
+            let entry = match delimiter_position {
+                Some(delimiter_position) => {
+                    let parsed_value_as_str =
+                        parse_value_as_string_type_from_line(line_key_value, delimiter_position);
+                    MemtableEntry::Value(parsed_value_as_str.to_string())
+                }
+                None => MemtableEntry::Tombstone,
+            };
+            //The text formats predate sequence numbers
+            return Ok(Some(SequencedEntry {
+                sequence_number: 0,
+                entry,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+//In the block format the search start position is that of the one data block that may hold
+//the key, so only that block is read
+fn search_data_block_for_key<K>(
+    key_to_find: &K,
+    file_path: &Path,
+    format_version: u32,
+    search_start_position: usize,
+    data_length: usize,
+) -> Result<Option<SequencedEntry>>
+where
+    K: StringLike,
+{
+    let mut memtable_file = File::open(file_path)?;
+    memtable_file.seek(io::SeekFrom::Start(search_start_position as u64))?;
+    let mut reader =
+        BufReader::new(memtable_file.take((data_length - search_start_position) as u64));
+    let key_to_find_as_string: String = key_to_find.clone().into();
+    match read_framed_data_block(&mut reader, format_version)? {
+        Some(data_block) => data_block.find(&key_to_find_as_string),
+        None => Ok(None),
+    }
+}
+
+//Entries are in key order, so reading stops at the first key past the one being searched for
+fn search_binary_file_for_key<K>(
+    key_to_find: &K,
+    memtable_config: &MemtableConfig,
+    file_path: &Path,
+    search_start_position: usize,
+    data_length: usize,
+) -> Result<Option<SequencedEntry>>
+where
+    K: StringLike,
+{
+    let key_to_find_as_string: String = key_to_find.clone().into();
+    let memtable_file_iterator = MemtableFileIterator::new(
+        memtable_config,
+        file_path,
+        BINARY_FORMAT_VERSION,
+        search_start_position,
+        data_length,
+    )?;
+    for key_entry_pair in memtable_file_iterator {
+        let (key, entry) = key_entry_pair?;
+        if key >= key_to_find_as_string {
+            return Ok((key == key_to_find_as_string).then_some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// Recovers the key offsets of a memtable file written without an index block by scanning
+/// its first `data_length` bytes for lines carrying the key offset indicator.
+pub fn rebuild_key_offsets_from_file(
+    memtable_config: &MemtableConfig,
+    file_path: &Path,
+    data_length: usize,
+) -> Result<Option<Vec<(String, usize)>>> {
+    let mut reader = BufReader::new(File::open(file_path)?.take(data_length as u64));
+    let mut key_offsets = vec![];
+    let mut line_as_string = String::new();
+    let mut line_offset = 0;
+    while reader.read_line(&mut line_as_string)? != 0 {
+        let is_key_offset_line =
+            line_offset != 0 && line_as_string.starts_with(memtable_config.key_offset_indicator);
+        if is_key_offset_line {
+            let line_key_value = line_as_string[memtable_config.key_offset_indicator.len_utf8()..]
+                .trim_end_matches('\n');
+            let key_end_position = line_key_value
+                .find(memtable_config.key_value_delimeter)
+                .unwrap_or(line_key_value.len());
+            key_offsets.push((line_key_value[..key_end_position].to_string(), line_offset));
         }
-        line_number += 1;
+        line_offset += line_as_string.len();
+        line_as_string.clear();
+    }
+    if !key_offsets.is_empty() {
+        Ok(Some(key_offsets))
+    } else {
+        Ok(None)
     }
-    None
 }
 
-fn check_key_equality<K>(line_string: &String, delimiter_position: usize, key_to_find: &K) -> bool
+fn check_key_equality<K>(line_string: &str, key_end_position: usize, key_to_find: &K) -> bool
 where
     K: StringLike,
 {
-    let parsed_key = &line_string[0..delimiter_position];
+    let parsed_key = &line_string[0..key_end_position];
     //TODO: Don't like this repeated cloning, must be a better way to prep for comparison of keys
     let key_to_find_as_string: String = key_to_find.clone().into();
     if parsed_key == key_to_find_as_string {
@@ -79,20 +208,28 @@ where
     false
 }
 
-fn parse_value_as_string_type_from_line(line_string: &String, delimiter_position: usize) -> &str {
+fn parse_value_as_string_type_from_line(line_string: &str, delimiter_position: usize) -> &str {
     &line_string[delimiter_position + 1..]
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use crate::{
-        memtable::Memtable, memtable_config::MemtableConfig,
+        memtable::Memtable,
+        memtable_config::MemtableConfig,
+        memtable_entry::MemtableEntry,
+        memtable_file::memtable_file_path,
+        memtable_file_index::{BINARY_FORMAT_VERSION, FILTER_FORMAT_VERSION, TEXT_FORMAT_VERSION},
         memtable_search_file::search_file_for_key_from_starting_position_until_next_offset,
+        test_utils::test_data_directory,
     };
 
-    use super::determine_file_search_start_position;
+    use super::{determine_file_search_start_position, rebuild_key_offsets_from_file};
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn determine_file_search_start_position_is_at_beginning() {
         let key_to_find = "B".to_string();
         let mut key_offsets = vec![];
@@ -105,6 +242,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn determine_file_search_start_position_is_not_at_beginning() {
         let key_to_find = "E".to_string();
         let mut key_offsets = vec![];
@@ -118,13 +256,14 @@ mod tests {
 
     #[test]
     fn search_first_file_segment_from_some_position_key_present() {
-        let config = MemtableConfig::new(4, "./output/test_result_1.txt");
+        let data_directory = test_data_directory("search_first_segment_key_present");
+        let config = MemtableConfig::new(4, &data_directory);
         let config_clone = config.clone();
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("Hello".to_string(), "1");
-        memtable.insert("World!".to_string(), "2");
-        memtable.insert("This".to_string(), "1");
-        memtable.insert("Is".to_string(), "1");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("Hello".to_string(), "1").unwrap();
+        memtable.insert("World!".to_string(), "2").unwrap();
+        memtable.insert("This".to_string(), "1").unwrap();
+        let written_file = memtable.insert("Is".to_string(), "1").unwrap().unwrap();
         assert_eq!(memtable.current_size, 0);
 
         let key_to_find = "This";
@@ -132,25 +271,29 @@ mod tests {
             search_file_for_key_from_starting_position_until_next_offset(
                 &key_to_find,
                 &config_clone,
+                &written_file.file_path,
+                written_file.format_version,
                 0,
-            );
-        assert!(search_result_from_memtable_file.is_some());
-        let found_value = search_result_from_memtable_file
+                written_file.data_length,
+            )
             .unwrap()
-            .parse::<i32>()
-            .unwrap();
-        assert_eq!(found_value, 1);
+            .map(|sequenced_entry| sequenced_entry.entry);
+        assert_eq!(
+            search_result_from_memtable_file,
+            Some(MemtableEntry::Value("1".to_string()))
+        );
     }
 
     #[test]
     fn search_first_file_segment_from_some_position_key_not_present() {
-        let config = MemtableConfig::new(4, "./output/test_result_2.txt");
+        let data_directory = test_data_directory("search_first_segment_key_not_present");
+        let config = MemtableConfig::new(4, &data_directory);
         let config_clone = config.clone();
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("Hello".to_string(), "1");
-        memtable.insert("World!".to_string(), "2");
-        memtable.insert("This".to_string(), "1");
-        memtable.insert("Is".to_string(), "1");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("Hello".to_string(), "1").unwrap();
+        memtable.insert("World!".to_string(), "2").unwrap();
+        memtable.insert("This".to_string(), "1").unwrap();
+        let written_file = memtable.insert("Is".to_string(), "1").unwrap().unwrap();
         assert_eq!(memtable.current_size, 0);
 
         let key_to_find = "ABCD";
@@ -158,73 +301,248 @@ mod tests {
             search_file_for_key_from_starting_position_until_next_offset(
                 &key_to_find,
                 &config_clone,
+                &written_file.file_path,
+                written_file.format_version,
                 0,
-            );
+                written_file.data_length,
+            )
+            .unwrap()
+            .map(|sequenced_entry| sequenced_entry.entry);
         assert!(search_result_from_memtable_file.is_none());
     }
 
     #[test]
     fn search_non_first_file_segment_key_present() {
-        let config = MemtableConfig::new(8, "./output/test_result_3.txt");
+        let data_directory = test_data_directory("search_non_first_segment_key_present");
+        let config = MemtableConfig::new(8, &data_directory);
         let config_clone = config.clone();
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("A".to_string(), "1");
-        memtable.insert("B".to_string(), "2");
-        memtable.insert("C".to_string(), "1");
-        memtable.insert("D".to_string(), "1");
-        memtable.insert("E".to_string(), "1");
-        memtable.insert("F".to_string(), "2");
-        memtable.insert("G".to_string(), "1");
-        memtable.insert("H".to_string(), "1");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable.insert("B".to_string(), "2").unwrap();
+        memtable.insert("C".to_string(), "1").unwrap();
+        memtable.insert("D".to_string(), "1").unwrap();
+        memtable.insert("E".to_string(), "1").unwrap();
+        memtable.insert("F".to_string(), "2").unwrap();
+        memtable.insert("G".to_string(), "1").unwrap();
+        let written_file = memtable.insert("H".to_string(), "1").unwrap().unwrap();
         assert_eq!(memtable.current_size, 0);
 
         let key_to_find = "H".to_owned();
-        let offsets = memtable
-            .key_offsets_of_most_recent_written_memtable
-            .unwrap();
+        let offsets = written_file.key_offsets.unwrap();
         let offset_to_use_for_search = determine_file_search_start_position(&key_to_find, &offsets);
 
         let search_result_from_memtable_file =
             search_file_for_key_from_starting_position_until_next_offset(
                 &key_to_find,
                 &config_clone,
+                &written_file.file_path,
+                written_file.format_version,
                 offset_to_use_for_search,
-            );
-        assert!(search_result_from_memtable_file.is_some());
-        let found_value = search_result_from_memtable_file
+                written_file.data_length,
+            )
             .unwrap()
-            .parse::<i32>()
-            .unwrap();
-        assert_eq!(found_value, 1);
+            .map(|sequenced_entry| sequenced_entry.entry);
+        assert_eq!(
+            search_result_from_memtable_file,
+            Some(MemtableEntry::Value("1".to_string()))
+        );
+    }
+
+    #[test]
+    fn search_non_first_file_segment_key_at_offset() {
+        let data_directory = test_data_directory("search_non_first_segment_key_at_offset");
+        let mut config = MemtableConfig::new(8, &data_directory);
+        config.format_version = BINARY_FORMAT_VERSION;
+        let config_clone = config.clone();
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable.insert("B".to_string(), "1").unwrap();
+        memtable.insert("C".to_string(), "1").unwrap();
+        memtable.insert("D".to_string(), "1").unwrap();
+        memtable.insert("E".to_string(), "1").unwrap();
+        memtable.insert("F".to_string(), "2").unwrap();
+        memtable.insert("G".to_string(), "1").unwrap();
+        let written_file = memtable.insert("H".to_string(), "1").unwrap().unwrap();
+
+        let key_to_find = "F".to_owned();
+        let offsets = written_file.key_offsets.unwrap();
+        assert_eq!(offsets[0].0, key_to_find);
+        let offset_to_use_for_search = determine_file_search_start_position(&key_to_find, &offsets);
+
+        let search_result_from_memtable_file =
+            search_file_for_key_from_starting_position_until_next_offset(
+                &key_to_find,
+                &config_clone,
+                &written_file.file_path,
+                written_file.format_version,
+                offset_to_use_for_search,
+                written_file.data_length,
+            )
+            .unwrap()
+            .map(|sequenced_entry| sequenced_entry.entry);
+        assert_eq!(
+            search_result_from_memtable_file,
+            Some(MemtableEntry::Value("2".to_string()))
+        );
     }
 
     #[test]
     fn search_non_first_file_segment_key_not_present() {
-        let config = MemtableConfig::new(8, "./output/test_result_4.txt");
+        let data_directory = test_data_directory("search_non_first_segment_key_not_present");
+        let config = MemtableConfig::new(8, &data_directory);
         let config_clone = config.clone();
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("A".to_string(), "1");
-        memtable.insert("B".to_string(), "2");
-        memtable.insert("C".to_string(), "1");
-        memtable.insert("D".to_string(), "1");
-        memtable.insert("E".to_string(), "1");
-        memtable.insert("F".to_string(), "2");
-        memtable.insert("G".to_string(), "1");
-        memtable.insert("H".to_string(), "1");
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable.insert("B".to_string(), "2").unwrap();
+        memtable.insert("C".to_string(), "1").unwrap();
+        memtable.insert("D".to_string(), "1").unwrap();
+        memtable.insert("E".to_string(), "1").unwrap();
+        memtable.insert("F".to_string(), "2").unwrap();
+        memtable.insert("G".to_string(), "1").unwrap();
+        let written_file = memtable.insert("H".to_string(), "1").unwrap().unwrap();
         assert_eq!(memtable.current_size, 0);
 
         let key_to_find = "I".to_owned();
-        let offsets = memtable
-            .key_offsets_of_most_recent_written_memtable
-            .unwrap();
+        let offsets = written_file.key_offsets.unwrap();
         let offset_to_use_for_search = determine_file_search_start_position(&key_to_find, &offsets);
 
         let search_result_from_memtable_file =
             search_file_for_key_from_starting_position_until_next_offset(
                 &key_to_find,
                 &config_clone,
+                &written_file.file_path,
+                written_file.format_version,
                 offset_to_use_for_search,
-            );
+                written_file.data_length,
+            )
+            .unwrap()
+            .map(|sequenced_entry| sequenced_entry.entry);
         assert!(search_result_from_memtable_file.is_none());
     }
+
+    #[test]
+    fn search_block_file_reads_one_block() {
+        let data_directory = test_data_directory("search_block_file_one_block");
+        let mut config = MemtableConfig::new(300, &data_directory);
+        config.block_size = 256;
+        let config_clone = config.clone();
+        let mut memtable = Memtable::<String, String>::new(config).unwrap();
+        let mut written_file = None;
+        for i in 0..300 {
+            written_file = memtable
+                .insert(format!("user/{:04}/profile", i * 2), i.to_string())
+                .unwrap();
+        }
+        let written_file = written_file.unwrap();
+        let offsets = written_file.key_offsets.unwrap();
+        assert!(offsets.len() > 10);
+        assert_eq!(offsets[0], ("user/0000/profile".to_string(), 0));
+
+        for i in 0..600 {
+            let key_to_find = format!("user/{:04}/profile", i);
+            let search_result_from_memtable_file =
+                search_file_for_key_from_starting_position_until_next_offset(
+                    &key_to_find,
+                    &config_clone,
+                    &written_file.file_path,
+                    written_file.format_version,
+                    determine_file_search_start_position(&key_to_find, &offsets),
+                    written_file.data_length,
+                )
+                .unwrap()
+                .map(|sequenced_entry| sequenced_entry.entry);
+            let expected_entry = (i % 2 == 0).then(|| MemtableEntry::Value((i / 2).to_string()));
+            assert_eq!(search_result_from_memtable_file, expected_entry);
+        }
+    }
+
+    #[test]
+    fn search_text_file_reads_past_empty_line() {
+        let data_directory = test_data_directory("search_text_file_empty_line");
+        let config = MemtableConfig::new(4, &data_directory);
+        let file_path = memtable_file_path(&data_directory, 1);
+        fs::write(&file_path, "\nA:1\nB:2\n").unwrap();
+
+        for (key_to_find, expected_entry) in [
+            ("", MemtableEntry::Tombstone),
+            ("B", MemtableEntry::Value("2".to_string())),
+        ] {
+            let search_result_from_memtable_file =
+                search_file_for_key_from_starting_position_until_next_offset(
+                    &key_to_find,
+                    &config,
+                    &file_path,
+                    TEXT_FORMAT_VERSION,
+                    0,
+                    9,
+                )
+                .unwrap()
+                .map(|sequenced_entry| sequenced_entry.entry);
+            assert_eq!(search_result_from_memtable_file, Some(expected_entry));
+        }
+    }
+
+    #[test]
+    fn rebuild_key_offsets_matches_written_offsets() {
+        let data_directory = test_data_directory("rebuild_key_offsets_matches_written");
+        let mut config = MemtableConfig::new(12, &data_directory);
+        config.format_version = FILTER_FORMAT_VERSION;
+        let config_clone = config.clone();
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        let mut written_file = None;
+        for key in ["A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L"] {
+            written_file = memtable.insert(key.to_string(), "1").unwrap();
+        }
+        let written_file = written_file.unwrap();
+
+        let rebuilt_key_offsets = rebuild_key_offsets_from_file(
+            &config_clone,
+            &written_file.file_path,
+            written_file.data_length,
+        )
+        .unwrap();
+        assert_eq!(rebuilt_key_offsets, written_file.key_offsets);
+        assert_eq!(rebuilt_key_offsets.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn search_file_segment_finds_tombstone() {
+        let data_directory = test_data_directory("search_file_segment_finds_tombstone");
+        let mut config = MemtableConfig::new(8, &data_directory);
+        config.format_version = FILTER_FORMAT_VERSION;
+        let config_clone = config.clone();
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        for key in ["A", "B", "C", "D", "E", "G", "H"] {
+            memtable.insert(key.to_string(), "1").unwrap();
+        }
+        let written_file = memtable.remove("F".to_string()).unwrap().unwrap();
+        let offsets = written_file.key_offsets.unwrap();
+        assert_eq!(offsets[0].0, "F");
+
+        for (key_to_find, search_start_position) in [("F", offsets[0].1), ("C", 0)] {
+            let search_result_from_memtable_file =
+                search_file_for_key_from_starting_position_until_next_offset(
+                    &key_to_find,
+                    &config_clone,
+                    &written_file.file_path,
+                    written_file.format_version,
+                    search_start_position,
+                    written_file.data_length,
+                )
+                .unwrap()
+                .map(|sequenced_entry| sequenced_entry.entry);
+            let expected_entry = match key_to_find {
+                "F" => MemtableEntry::Tombstone,
+                _ => MemtableEntry::Value("1".to_string()),
+            };
+            assert_eq!(search_result_from_memtable_file, Some(expected_entry));
+        }
+        let rebuilt_key_offsets = rebuild_key_offsets_from_file(
+            &config_clone,
+            &written_file.file_path,
+            written_file.data_length,
+        )
+        .unwrap();
+        assert_eq!(rebuilt_key_offsets.unwrap()[0].0, "F");
+    }
 }
@@ -1,118 +1,419 @@
-use std::{fs::File, io::Write};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
 
-use crate::{memtable_config::MemtableConfig, sorted_string_table::StringLike};
+use crate::{
+    bloom_filter::{bloom_hash, BloomFilter},
+    data_block::{create_framed_data_block, DataBlockBuilder},
+    error::{Error, Result},
+    memtable_config::MemtableConfig,
+    memtable_entry::{MemtableEntry, SequencedEntry},
+    memtable_file::{temporary_memtable_file_path, KeyOffsets},
+    memtable_file_index::{
+        has_data_blocks, has_sequence_numbers, write_blocks_and_footer, BINARY_FORMAT_VERSION,
+    },
+    sorted_string_table::StringLike,
+    varint::encode_varint,
+};
 
-pub fn write_data_to_file<K, V>(
+//Byte following the key lengths of each entry in the binary formats
+pub const VALUE_ENTRY_TYPE: u8 = 0;
+pub const TOMBSTONE_ENTRY_TYPE: u8 = 1;
+//Only written to the data blocks of formats with sequence numbers
+pub const MERGE_ENTRY_TYPE: u8 = 2;
+pub const EXPIRING_VALUE_ENTRY_TYPE: u8 = 3;
+
+/// What was written to a memtable file, as needed to search it later.
+pub struct WrittenData {
+    pub key_offsets: Option<KeyOffsets>,
+    //Length in bytes of the entries
+    pub data_length: usize,
+    pub smallest_key: String,
+    pub largest_key: String,
+    pub bloom_filter: Option<BloomFilter>,
+    pub format_version: u32,
+}
+
+/// Writes the entries, given in key order, in the configured format version followed by the
+/// filter block, index block and footer. Sequence numbers are only kept by the format versions
+/// that store them. The first error given in place of a pair stops the write and is returned.
+///
+/// The file is written and synced under a temporary name, then renamed to `file_path` and its
+/// directory synced, so a crash leaves either the complete file or none at all.
+pub fn write_data_to_file<K, I>(
     config: &MemtableConfig,
-    key_value_pairs: &[(K, V)],
-) -> Option<Vec<(K, usize)>>
+    file_path: &Path,
+    key_value_pairs: I,
+) -> Result<WrittenData>
 where
     K: StringLike,
-    V: std::fmt::Display,
+    I: IntoIterator<Item = Result<(K, SequencedEntry)>>,
 {
-    let number_of_key_value_pairs = key_value_pairs.len();
-    let mut output = File::create(&config.file_path).unwrap();
+    let temporary_file_path = temporary_memtable_file_path(file_path);
+    let written_data = write_data_to_temporary_file(config, &temporary_file_path, key_value_pairs)
+        .inspect_err(|_| {
+            //A partly written file is of no use, and one left behind is removed on open anyway
+            let _ = fs::remove_file(&temporary_file_path);
+        })?;
+    fs::rename(&temporary_file_path, file_path)?;
+    //The rename itself only survives a crash once the directory holding it is synced
+    if let Some(directory_path) = file_path.parent() {
+        sync_directory(directory_path)?;
+    }
+    Ok(written_data)
+}
+
+fn write_data_to_temporary_file<K, I>(
+    config: &MemtableConfig,
+    temporary_file_path: &Path,
+    key_value_pairs: I,
+) -> Result<WrittenData>
+where
+    K: StringLike,
+    I: IntoIterator<Item = Result<(K, SequencedEntry)>>,
+{
+    let mut output = BufWriter::new(File::create(temporary_file_path)?);
     let mut key_offsets = vec![];
+    let mut smallest_key = None;
+    let mut largest_key = String::new();
+    let mut key_hashes = vec![];
 
     let mut index: usize = 0;
-    let end_index: usize = number_of_key_value_pairs - 1;
     let mut accumulated_offset = 0;
+    let mut data_block_builder =
+        DataBlockBuilder::new(config.block_restart_interval, config.format_version);
 
-    key_value_pairs.into_iter().for_each(|(key, value)| {
-        let key_value_line_to_write =
-            match is_key_offset_index(index, end_index, config.key_offset_frequency) {
-                true => {
-                    key_offsets.push((key.clone(), accumulated_offset));
-                    create_key_value_offset_string(
-                        key,
-                        value,
-                        config.key_offset_indicator,
-                        config.key_value_delimeter,
-                    )
+    let mut key_value_pairs = key_value_pairs.into_iter().peekable();
+    while let Some(key_value_pair) = key_value_pairs.next() {
+        let (key, sequenced_entry) = key_value_pair?;
+        let value = &sequenced_entry.entry;
+        let is_block_only_entry = matches!(
+            value,
+            MemtableEntry::Merge(_) | MemtableEntry::ExpiringValue(..)
+        );
+        if is_block_only_entry && !has_sequence_numbers(config.format_version) {
+            return Err(Error::InvalidConfiguration(format!(
+                "merge operands and expiring values cannot be written in format version {}",
+                config.format_version
+            )));
+        }
+        let is_end_index = key_value_pairs.peek().is_none();
+        if index == 0 {
+            smallest_key = Some(key.clone().into());
+        }
+        if is_end_index {
+            largest_key = key.clone().into();
+        }
+        key_hashes.push(bloom_hash(&key.clone().into()));
+        let is_key_offset = match has_data_blocks(config.format_version) {
+            //Each data block is indexed by its first key
+            true => data_block_builder.is_empty(),
+            false => is_key_offset_index(index, is_end_index, config.key_offset_frequency),
+        };
+        if is_key_offset {
+            key_offsets.push((key.clone().into(), accumulated_offset));
+        }
+        //Binary entries need no marker, as the index alone records where key offsets fall
+        let entry_to_write = match (config.format_version, is_key_offset) {
+            (format_version, _) if has_data_blocks(format_version) => {
+                let key: String = key.into();
+                data_block_builder.add(&key, &sequenced_entry);
+                //Nothing is written until the block fills or takes the last entry
+                match data_block_builder.estimated_size() >= config.block_size || is_end_index {
+                    true => create_framed_data_block(
+                        &data_block_builder.finish(),
+                        config.format_version,
+                    ),
+                    false => vec![],
                 }
-
-                false => create_key_value_string(key, value, config.key_value_delimeter),
-            };
-        let size_of_line_in_bytes = key_value_line_to_write.len();
-        accumulated_offset += size_of_line_in_bytes;
+            }
+            (BINARY_FORMAT_VERSION, _) => {
+                let key: String = key.into();
+                create_binary_entry(&key, value)
+            }
+            (_, true) => create_key_value_offset_string(
+                &key,
+                value,
+                config.key_offset_indicator,
+                config.key_value_delimeter,
+            )
+            .into_bytes(),
+            (_, false) => {
+                create_key_value_string(&key, value, config.key_value_delimeter).into_bytes()
+            }
+        };
+        let size_of_entry_in_bytes = entry_to_write.len();
+        accumulated_offset += size_of_entry_in_bytes;
         index += 1;
-        output.write(key_value_line_to_write.as_bytes()).unwrap();
-    });
-    if key_offsets.len() > 0 {
+        output.write_all(&entry_to_write)?;
+    }
+    let bloom_filter =
+        (config.bits_per_key > 0).then(|| BloomFilter::new(config.bits_per_key, &key_hashes));
+    write_blocks_and_footer(
+        &mut output,
+        bloom_filter.as_ref(),
+        &key_offsets,
+        accumulated_offset,
+        config.format_version,
+    )?;
+    //The write ahead log covering these entries is dropped once this returns
+    output
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()?;
+    let key_offsets = if !key_offsets.is_empty() {
         Some(key_offsets)
     } else {
         None
+    };
+    Ok(WrittenData {
+        key_offsets,
+        data_length: accumulated_offset,
+        smallest_key: smallest_key.unwrap_or_default(),
+        largest_key,
+        bloom_filter,
+        format_version: config.format_version,
+    })
+}
+
+/// Syncs the directory itself, so the files created, renamed or removed in it survive a crash.
+pub fn sync_directory(directory_path: &Path) -> Result<()> {
+    //An empty parent stands for the current directory
+    let directory_path = match directory_path.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => directory_path,
+    };
+    File::open(directory_path)?.sync_all()?;
+    Ok(())
+}
+
+//The entry type, then the key and for a value the value, each after its varint length
+fn create_binary_entry(key: &str, value: &MemtableEntry) -> Vec<u8> {
+    let mut entry = vec![];
+    match value {
+        MemtableEntry::Value(value) => {
+            entry.push(VALUE_ENTRY_TYPE);
+            encode_varint(key.len() as u64, &mut entry);
+            entry.extend_from_slice(key.as_bytes());
+            encode_varint(value.len() as u64, &mut entry);
+            entry.extend_from_slice(value.as_bytes());
+        }
+        MemtableEntry::Tombstone => {
+            entry.push(TOMBSTONE_ENTRY_TYPE);
+            encode_varint(key.len() as u64, &mut entry);
+            entry.extend_from_slice(key.as_bytes());
+        }
+        MemtableEntry::Merge(_) | MemtableEntry::ExpiringValue(..) => {
+            unreachable!("merge operands and expiring values are only written to data blocks")
+        }
     }
+    entry
 }
 
-fn create_key_value_offset_string<K, V>(
+fn create_key_value_offset_string<K>(
     key: &K,
-    value: &V,
+    value: &MemtableEntry,
     offset_indicator: char,
     delimeter: char,
 ) -> String
 where
     K: StringLike,
-    V: std::fmt::Display,
 {
-    format!("{}{}{}{}\n", offset_indicator, key, delimeter, value)
+    format!(
+        "{}{}{}\n",
+        offset_indicator,
+        key,
+        create_delimited_value_string(value, delimeter)
+    )
 }
 
-fn create_key_value_string<K, V>(key: &K, value: &V, delimeter: char) -> String
+fn create_key_value_string<K>(key: &K, value: &MemtableEntry, delimeter: char) -> String
 where
     K: StringLike,
-    V: std::fmt::Display,
 {
-    format!("{}{}{}\n", key, delimeter, value)
+    format!(
+        "{}{}\n",
+        key,
+        create_delimited_value_string(value, delimeter)
+    )
 }
 
-fn is_key_offset_index(index: usize, end_index: usize, key_offset_frequency: u32) -> bool {
-    index != 0 && index != end_index && index % key_offset_frequency as usize == 0
+//A tombstone is written as a line holding only the key, without a delimiter
+fn create_delimited_value_string(value: &MemtableEntry, delimeter: char) -> String {
+    match value {
+        MemtableEntry::Value(value) => format!("{}{}", delimeter, value),
+        MemtableEntry::Tombstone => String::new(),
+        MemtableEntry::Merge(_) | MemtableEntry::ExpiringValue(..) => {
+            unreachable!("merge operands and expiring values are only written to data blocks")
+        }
+    }
+}
+
+fn is_key_offset_index(index: usize, is_end_index: bool, key_offset_frequency: u32) -> bool {
+    index != 0 && !is_end_index && index.is_multiple_of(key_offset_frequency as usize)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{memtable::Memtable, memtable_config::MemtableConfig};
+    use std::fs;
 
-    use super::write_data_to_file;
+    use crate::{
+        error::{Error, Result},
+        memtable::Memtable,
+        memtable_config::MemtableConfig,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+        memtable_file::{memtable_file_path, temporary_memtable_file_path},
+        memtable_file_index::{
+            BINARY_FORMAT_VERSION, CHECKSUM_FORMAT_VERSION, FILTER_FORMAT_VERSION,
+        },
+        test_utils::test_data_directory,
+    };
+
+    use super::{write_data_to_file, TOMBSTONE_ENTRY_TYPE, VALUE_ENTRY_TYPE};
 
     #[test]
     fn write_data_to_file_test_through_memtable_exceeding_capacity() {
-        let config = MemtableConfig::new(7, "./output/test_result.txt");
-        let mut memtable = Memtable::<String, &str>::new(config);
-        memtable.insert("Hello".to_string(), "1");
-        memtable.insert("World!".to_string(), "2");
-        memtable.insert("This".to_string(), "1");
-        memtable.insert("Is".to_string(), "1");
-        memtable.insert("A".to_string(), "1");
-        memtable.insert("New".to_string(), "1");
-        memtable.insert("Sentence".to_string(), "1");
+        let data_directory = test_data_directory("write_data_to_file_through_memtable");
+        let config = MemtableConfig::new(7, &data_directory);
+        let mut memtable = Memtable::<String, &str>::new(config).unwrap();
+        memtable.insert("Hello".to_string(), "1").unwrap();
+        memtable.insert("World!".to_string(), "2").unwrap();
+        memtable.insert("This".to_string(), "1").unwrap();
+        memtable.insert("Is".to_string(), "1").unwrap();
+        memtable.insert("A".to_string(), "1").unwrap();
+        memtable.insert("New".to_string(), "1").unwrap();
+        let written_file = memtable.insert("Sentence".to_string(), "1").unwrap();
         assert_eq!(memtable.current_size, 0);
-        assert!(memtable
-            .key_offsets_of_most_recent_written_memtable
-            .is_some());
-        assert_eq!(
-            memtable
-                .key_offsets_of_most_recent_written_memtable
-                .unwrap()
-                .len(),
-            1
-        );
+        assert!(written_file.is_some());
+        let key_offsets = written_file.unwrap().key_offsets;
+        assert!(key_offsets.is_some());
+        assert_eq!(key_offsets.unwrap().len(), 1);
+    }
+
+    fn unsequenced_entry(entry: MemtableEntry) -> SequencedEntry {
+        SequencedEntry {
+            sequence_number: 0,
+            entry,
+        }
     }
 
     #[test]
     fn write_data_to_file_test() {
-        let config = MemtableConfig::new(7, "./output/test_result.txt");
-        let mut key_value_pairs = vec![];
-        key_value_pairs.push(("A", 1));
-        key_value_pairs.push(("B", 1));
-        key_value_pairs.push(("C", 1));
-        key_value_pairs.push(("D", 1));
-        key_value_pairs.push(("E", 1));
-        key_value_pairs.push(("F", 1));
-        key_value_pairs.push(("G", 1));
-        let key_offsets = write_data_to_file(&config, &key_value_pairs);
-        assert!(key_offsets.is_some());
-        assert_eq!(key_offsets.unwrap().len(), 1);
+        let data_directory = test_data_directory("write_data_to_file");
+        let mut config = MemtableConfig::new(7, &data_directory);
+        config.format_version = FILTER_FORMAT_VERSION;
+        let key_value_pairs = ["A", "B", "C", "D", "E", "F", "G"].into_iter().map(|key| {
+            Ok((
+                key,
+                unsequenced_entry(MemtableEntry::Value("1".to_string())),
+            ))
+        });
+        let file_path = memtable_file_path(&data_directory, 1);
+        let written_data = write_data_to_file(&config, &file_path, key_value_pairs).unwrap();
+        assert!(written_data.key_offsets.is_some());
+        assert_eq!(written_data.key_offsets.unwrap().len(), 1);
+        assert_eq!(written_data.data_length, 29);
+        assert_eq!(written_data.smallest_key, "A");
+        assert_eq!(written_data.largest_key, "G");
+    }
+
+    #[test]
+    fn write_data_to_file_writes_tombstone_without_delimiter() {
+        let data_directory = test_data_directory("write_data_to_file_tombstone");
+        let mut config = MemtableConfig::new(2, &data_directory);
+        config.format_version = FILTER_FORMAT_VERSION;
+        let key_value_pairs = vec![
+            Ok((
+                "A",
+                unsequenced_entry(MemtableEntry::Value("1".to_string())),
+            )),
+            Ok(("B", unsequenced_entry(MemtableEntry::Tombstone))),
+        ];
+        let file_path = memtable_file_path(&data_directory, 1);
+        let written_data = write_data_to_file(&config, &file_path, key_value_pairs).unwrap();
+        let file_contents = fs::read(&file_path).unwrap();
+        assert_eq!(&file_contents[..written_data.data_length], b"A:1\nB\n");
+        assert!(!temporary_memtable_file_path(&file_path).exists());
+    }
+
+    #[test]
+    fn write_data_to_file_writes_length_prefixed_entries() {
+        let data_directory = test_data_directory("write_data_to_file_binary");
+        let mut config = MemtableConfig::new(2, &data_directory);
+        config.format_version = BINARY_FORMAT_VERSION;
+        let long_value = "v".repeat(200);
+        let key_value_pairs = vec![
+            Ok((
+                "&A:\n",
+                unsequenced_entry(MemtableEntry::Value(long_value.clone())),
+            )),
+            Ok(("B", unsequenced_entry(MemtableEntry::Tombstone))),
+        ];
+        let file_path = memtable_file_path(&data_directory, 1);
+        let written_data = write_data_to_file(&config, &file_path, key_value_pairs).unwrap();
+        let file_contents = fs::read(&file_path).unwrap();
+        let mut expected_data = vec![VALUE_ENTRY_TYPE, 4];
+        expected_data.extend_from_slice(b"&A:\n");
+        expected_data.extend_from_slice(&[0xc8, 0x01]);
+        expected_data.extend_from_slice(long_value.as_bytes());
+        expected_data.extend_from_slice(&[TOMBSTONE_ENTRY_TYPE, 1, b'B']);
+        assert_eq!(&file_contents[..written_data.data_length], expected_data);
+        assert_eq!(written_data.format_version, BINARY_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn write_data_to_file_groups_entries_into_blocks() {
+        let data_directory = test_data_directory("write_data_to_file_blocks");
+        let mut config = MemtableConfig::new(1000, &data_directory);
+        //Sequence numbers take a byte more an entry in later formats
+        config.format_version = CHECKSUM_FORMAT_VERSION;
+        let key_value_pairs = || {
+            (0..1000).map(|i| {
+                let key = format!("tenant/0001/user/{:06}/settings", i);
+                Ok((key, unsequenced_entry(MemtableEntry::Value(i.to_string()))))
+            })
+        };
+        let file_path = memtable_file_path(&data_directory, 1);
+        let written_data = write_data_to_file(&config, &file_path, key_value_pairs()).unwrap();
+        config.format_version = BINARY_FORMAT_VERSION;
+        let binary_file_path = memtable_file_path(&data_directory, 2);
+        let binary_written_data =
+            write_data_to_file(&config, &binary_file_path, key_value_pairs()).unwrap();
+
+        //Every block is indexed by its first key
+        let key_offsets = written_data.key_offsets.unwrap();
+        assert_eq!(key_offsets[0].0, "tenant/0001/user/000000/settings");
+        assert_eq!(key_offsets[0].1, 0);
+        assert!(key_offsets.len() >= written_data.data_length / config.block_size);
+        assert!(key_offsets.len() <= written_data.data_length / config.block_size + 1);
+        assert!(written_data.data_length * 2 < binary_written_data.data_length);
+    }
+
+    #[test]
+    fn write_data_to_file_stops_at_first_error() {
+        let data_directory = test_data_directory("write_data_to_file_error");
+        let config = MemtableConfig::new(2, &data_directory);
+        let key_value_pairs = vec![
+            Ok((
+                "A",
+                unsequenced_entry(MemtableEntry::Value("1".to_string())),
+            )),
+            Err(Error::Corruption("unreadable entry".to_owned())),
+        ];
+        let file_path = memtable_file_path(&data_directory, 1);
+        let write_result = write_data_to_file(&config, &file_path, key_value_pairs);
+        assert!(matches!(write_result, Err(Error::Corruption(_))));
+        //Neither the file nor its temporary file is left behind
+        assert_eq!(fs::read_dir(&data_directory).unwrap().count(), 0);
+
+        let missing_directory_path = memtable_file_path(&format!("{}/missing", data_directory), 1);
+        let write_result = write_data_to_file(
+            &config,
+            &missing_directory_path,
+            Vec::<Result<(&str, SequencedEntry)>>::new(),
+        );
+        assert!(matches!(write_result, Err(Error::Io(_))));
     }
 }
@@ -0,0 +1,291 @@
+use std::{fmt::Display, marker::PhantomData};
+
+use num_traits::{CheckedAdd, Num};
+
+use crate::{
+    error::{Error, Result},
+    memtable_entry::{MemtableEntry, SequencedEntry},
+};
+
+/// Combines the merge operands written by [`crate::sorted_string_table::SortedStringTable::merge`]
+/// with the value they apply to. Operands are kept as written and only folded when the key is
+/// read, flushed or compacted, so either method may be called on an operand more than once.
+pub trait MergeOperator: Send + Sync {
+    /// Applies the operand to the key's value, which is `None` when the key has none. Returns
+    /// `None` when the operand cannot be applied.
+    fn full_merge(&self, key: &str, existing_value: Option<&str>, operand: &str) -> Option<String>;
+
+    /// Combines two operands into one with the same effect as applying the older and then the
+    /// newer, for when the value they apply to is in a file not being read. Returns `None`
+    /// when they cannot be combined.
+    fn partial_merge(&self, key: &str, older_operand: &str, newer_operand: &str) -> Option<String>;
+
+    /// Whether the operand can be applied at all, checked before it is written so an operand
+    /// that would fail every read and compaction of its key is rejected instead. Accepts every
+    /// operand unless overridden.
+    fn validate_operand(&self, _key: &str, _operand: &str) -> bool {
+        true
+    }
+}
+
+/// Adds decimal operands to a decimal value, with a key that has no value counting as zero.
+/// Operands that do not parse are rejected when written, while a value that does not parse,
+/// or a sum that overflows, cannot be merged.
+pub struct AddOperator<T> {
+    number_type: PhantomData<fn() -> T>,
+}
+
+/// Counters held as decimal `u64`s.
+pub type U64AddOperator = AddOperator<u64>;
+
+impl<T> AddOperator<T> {
+    pub fn new() -> Self {
+        AddOperator {
+            number_type: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for AddOperator<T> {
+    fn default() -> Self {
+        AddOperator::new()
+    }
+}
+
+impl<T> AddOperator<T>
+where
+    T: Num + CheckedAdd + Display,
+{
+    fn add(&self, first_number: &str, second_number: &str) -> Option<String> {
+        let first_number = T::from_str_radix(first_number, 10).ok()?;
+        let second_number = T::from_str_radix(second_number, 10).ok()?;
+        Some(first_number.checked_add(&second_number)?.to_string())
+    }
+}
+
+impl<T> MergeOperator for AddOperator<T>
+where
+    T: Num + CheckedAdd + Display,
+{
+    fn full_merge(
+        &self,
+        _key: &str,
+        existing_value: Option<&str>,
+        operand: &str,
+    ) -> Option<String> {
+        self.add(existing_value.unwrap_or("0"), operand)
+    }
+
+    fn partial_merge(
+        &self,
+        _key: &str,
+        older_operand: &str,
+        newer_operand: &str,
+    ) -> Option<String> {
+        self.add(older_operand, newer_operand)
+    }
+
+    fn validate_operand(&self, _key: &str, operand: &str) -> bool {
+        T::from_str_radix(operand, 10).is_ok()
+    }
+}
+
+/// Folds a key's entries, given newest first, into the single entry they add up to, taking
+/// the newest entry's sequence number. Entries are read up to the first value or tombstone,
+/// which the merge operands before it are applied to, keeping an expiring value's expiry
+/// time. Expired values should already be hidden. When the operands run out first and
+/// `may_have_older_entries` says older entries for the key may be held elsewhere, they are
+/// combined into one operand, and otherwise applied to no value.
+pub fn fold_entries<I>(
+    merge_operator: Option<&dyn MergeOperator>,
+    key: &str,
+    entries: I,
+    may_have_older_entries: bool,
+) -> Result<Option<SequencedEntry>>
+where
+    I: IntoIterator<Item = Result<SequencedEntry>>,
+{
+    let mut entries = entries.into_iter();
+    let Some(newest_entry) = entries.next().transpose()? else {
+        return Ok(None);
+    };
+    let MemtableEntry::Merge(newest_operand) = newest_entry.entry else {
+        return Ok(Some(newest_entry));
+    };
+    let mut operands = vec![newest_operand];
+    //Holds the value the operands apply to, or `Some(None)` for a tombstone
+    let mut base_value = None;
+    //The merged value expires with the value the operands apply to
+    let mut expires_at = None;
+    for entry in entries {
+        match entry?.entry {
+            MemtableEntry::Merge(operand) => operands.push(operand),
+            MemtableEntry::Value(value) => {
+                base_value = Some(Some(value));
+                break;
+            }
+            MemtableEntry::ExpiringValue(value, value_expires_at) => {
+                base_value = Some(Some(value));
+                expires_at = Some(value_expires_at);
+                break;
+            }
+            MemtableEntry::Tombstone => {
+                base_value = Some(None);
+                break;
+            }
+        }
+    }
+    let merge_operator = merge_operator.ok_or_else(|| {
+        Error::InvalidConfiguration(format!(
+            "key {} holds merge operands but no merge operator is configured",
+            key
+        ))
+    })?;
+    let merge_failed = || {
+        Error::MergeFailed(format!(
+            "merge operator could not apply an operand for key {}",
+            key
+        ))
+    };
+    let mut operands = operands.into_iter().rev();
+    let entry = match (base_value, may_have_older_entries) {
+        (None, true) => {
+            let mut combined_operand = operands.next().unwrap();
+            for operand in operands {
+                combined_operand = merge_operator
+                    .partial_merge(key, &combined_operand, &operand)
+                    .ok_or_else(merge_failed)?;
+            }
+            MemtableEntry::Merge(combined_operand)
+        }
+        (base_value, _) => {
+            let mut value = base_value.flatten();
+            for operand in operands {
+                value = Some(
+                    merge_operator
+                        .full_merge(key, value.as_deref(), &operand)
+                        .ok_or_else(merge_failed)?,
+                );
+            }
+            match expires_at {
+                Some(expires_at) => MemtableEntry::ExpiringValue(value.unwrap(), expires_at),
+                None => MemtableEntry::Value(value.unwrap()),
+            }
+        }
+    };
+    Ok(Some(SequencedEntry {
+        sequence_number: newest_entry.sequence_number,
+        entry,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+    };
+
+    use super::{fold_entries, MergeOperator, U64AddOperator};
+
+    fn entries(entries: Vec<MemtableEntry>) -> Vec<crate::error::Result<SequencedEntry>> {
+        let entry_count = entries.len() as u64;
+        (0..entry_count)
+            .rev()
+            .zip(entries)
+            .map(|(sequence_number, entry)| {
+                Ok(SequencedEntry {
+                    sequence_number,
+                    entry,
+                })
+            })
+            .collect()
+    }
+
+    fn operand(operand: &str) -> MemtableEntry {
+        MemtableEntry::Merge(operand.to_owned())
+    }
+
+    #[test]
+    fn u64_add_operator_adds_and_rejects_bad_numbers() {
+        let add_operator = U64AddOperator::new();
+        assert_eq!(
+            add_operator.full_merge("A", None, "5"),
+            Some("5".to_owned())
+        );
+        assert_eq!(
+            add_operator.full_merge("A", Some("5"), "7"),
+            Some("12".to_owned())
+        );
+        assert_eq!(
+            add_operator.partial_merge("A", "1", "2"),
+            Some("3".to_owned())
+        );
+        assert!(add_operator.validate_operand("A", "7"));
+        assert!(!add_operator.validate_operand("A", "abc"));
+        assert!(!add_operator.validate_operand("A", "-1"));
+        assert_eq!(add_operator.full_merge("A", Some("five"), "1"), None);
+        assert_eq!(add_operator.full_merge("A", Some("-1"), "1"), None);
+        assert_eq!(
+            add_operator.partial_merge("A", &u64::MAX.to_string(), "1"),
+            None
+        );
+    }
+
+    #[test]
+    fn fold_entries_applies_operands_to_the_newest_value() {
+        let add_operator = U64AddOperator::new();
+        let fold = |key_entries, may_have_older_entries| {
+            fold_entries(
+                Some(&add_operator),
+                "A",
+                entries(key_entries),
+                may_have_older_entries,
+            )
+            .unwrap()
+            .unwrap()
+        };
+        //Entries past the first value are shadowed
+        let folded_entry = fold(
+            vec![
+                operand("1"),
+                operand("2"),
+                MemtableEntry::Value("10".to_owned()),
+                MemtableEntry::Value("100".to_owned()),
+            ],
+            true,
+        );
+        assert_eq!(folded_entry.sequence_number, 3);
+        assert_eq!(folded_entry.entry, MemtableEntry::Value("13".to_owned()));
+        assert_eq!(
+            fold(vec![operand("1"), MemtableEntry::Tombstone], true).entry,
+            MemtableEntry::Value("1".to_owned())
+        );
+        assert_eq!(
+            fold(vec![operand("1"), operand("2")], true).entry,
+            operand("3")
+        );
+        assert_eq!(
+            fold(vec![operand("1"), operand("2")], false).entry,
+            MemtableEntry::Value("3".to_owned())
+        );
+        assert_eq!(
+            fold(vec![MemtableEntry::Tombstone, operand("2")], false).entry,
+            MemtableEntry::Tombstone
+        );
+
+        assert!(matches!(
+            fold_entries(None, "A", entries(vec![operand("1")]), false),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            fold_entries(
+                Some(&add_operator),
+                "A",
+                entries(vec![operand("x"), operand("1")]),
+                false
+            ),
+            Err(Error::MergeFailed(_))
+        ));
+    }
+}
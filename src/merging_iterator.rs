@@ -0,0 +1,257 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::iter;
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::memtable_entry::{current_time_in_millis, MemtableEntry, SequencedEntry};
+use crate::merge_operator::{fold_entries, MergeOperator};
+
+pub type EntryIterator<'a> = Box<dyn Iterator<Item = Result<(String, SequencedEntry)>> + 'a>;
+
+/// Merges several key ordered sources into a single key ordered iterator. Sources are given
+/// newest first, and when more than one holds a key only the entry from the newest is
+/// returned. A source may hold several entries for a key, newest first, and when the newest
+/// entry is a merge operand the entries it shadows are folded into it, see
+/// [`fold_entries`]. Values that have expired by the time the iteration starts are read as
+/// tombstones. Tombstones are returned like any other entry. An error from any source,
+/// or from folding, is returned before the entries that follow it, and ends the iteration.
+pub struct MergingIterator<'a> {
+    sources: Vec<EntryIterator<'a>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    //Whether older entries for the merged keys may be held outside the sources, so merge
+    //operands with nothing under them are kept as operands
+    may_have_older_entries: bool,
+    current_time: u64,
+    //Next unread entry of each source, keyed so the smallest key from the newest source pops first
+    next_entries: BinaryHeap<Reverse<(String, usize)>>,
+    next_entry_of_source: Vec<Option<SequencedEntry>>,
+    source_error: Option<Error>,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub fn new(
+        sources: Vec<EntryIterator<'a>>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        may_have_older_entries: bool,
+    ) -> Self {
+        let mut merging_iterator = MergingIterator {
+            next_entry_of_source: vec![None; sources.len()],
+            sources,
+            merge_operator,
+            may_have_older_entries,
+            current_time: current_time_in_millis(),
+            next_entries: BinaryHeap::new(),
+            source_error: None,
+        };
+        for source_index in 0..merging_iterator.sources.len() {
+            merging_iterator.advance_source(source_index);
+        }
+        merging_iterator
+    }
+
+    fn advance_source(&mut self, source_index: usize) {
+        match self.sources[source_index].next() {
+            Some(Ok((key, entry))) => {
+                self.next_entry_of_source[source_index] =
+                    Some(entry.hide_if_expired(self.current_time));
+                self.next_entries.push(Reverse((key, source_index)));
+            }
+            Some(Err(error)) => {
+                self.source_error.get_or_insert(error);
+            }
+            None => {}
+        }
+    }
+}
+
+impl Iterator for MergingIterator<'_> {
+    type Item = Result<(String, SequencedEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        //A failed source's next key is unknown, so no later entry can be trusted to be newest
+        if let Some(source_error) = self.source_error.take() {
+            self.next_entries.clear();
+            return Some(Err(source_error));
+        }
+        let Reverse((key, source_index)) = self.next_entries.pop()?;
+        let entry = self.next_entry_of_source[source_index].take().unwrap();
+        self.advance_source(source_index);
+
+        //Older entries for the same key are shadowed, so they are skipped unless a merge
+        //operand needs them. They pop newest first, as an advanced source's next entry for the
+        //key sorts before those of older sources
+        let is_merge_operand = matches!(entry.entry, MemtableEntry::Merge(_));
+        let mut shadowed_entries = vec![];
+        while let Some(Reverse((next_key, _))) = self.next_entries.peek() {
+            if *next_key != key {
+                break;
+            }
+            let Reverse((_, shadowed_source_index)) = self.next_entries.pop().unwrap();
+            let shadowed_entry = self.next_entry_of_source[shadowed_source_index].take();
+            if is_merge_operand {
+                shadowed_entries.extend(shadowed_entry);
+            }
+            self.advance_source(shadowed_source_index);
+        }
+        if !is_merge_operand {
+            return Some(Ok((key, entry)));
+        }
+        let entries = iter::once(entry).chain(shadowed_entries).map(Ok);
+        match fold_entries(
+            self.merge_operator.as_deref(),
+            &key,
+            entries,
+            self.may_have_older_entries,
+        ) {
+            Ok(folded_entry) => Some(Ok((key, folded_entry.unwrap()))),
+            Err(error) => {
+                self.next_entries.clear();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        error::Error,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+        merge_operator::{MergeOperator, U64AddOperator},
+    };
+
+    use super::{EntryIterator, MergingIterator};
+
+    fn source<'a>(entries: Vec<(&str, Option<&str>)>) -> EntryIterator<'a> {
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let entry = match value {
+                    Some(value) => MemtableEntry::Value(value.to_string()),
+                    None => MemtableEntry::Tombstone,
+                };
+                Ok((key.to_string(), unsequenced_entry(entry)))
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn unsequenced_entry(entry: MemtableEntry) -> SequencedEntry {
+        SequencedEntry {
+            sequence_number: 0,
+            entry,
+        }
+    }
+
+    #[test]
+    fn merging_iterator_orders_keys_across_sources() {
+        let sources = vec![
+            source(vec![("B", Some("1")), ("E", Some("1"))]),
+            source(vec![("A", Some("2")), ("C", Some("2"))]),
+            source(vec![("D", Some("3"))]),
+        ];
+        let keys: Vec<String> = MergingIterator::new(sources, None, false)
+            .map(|key_entry_pair| key_entry_pair.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec!["A", "B", "C", "D", "E"]);
+    }
+
+    #[test]
+    fn merging_iterator_newer_sources_shadow_older() {
+        let sources = vec![
+            source(vec![("A", Some("new")), ("B", None)]),
+            source(vec![
+                ("A", Some("old")),
+                ("B", Some("old")),
+                ("C", Some("old")),
+            ]),
+            source(vec![("A", Some("oldest")), ("C", Some("oldest"))]),
+        ];
+        let entries: Vec<(String, MemtableEntry)> = MergingIterator::new(sources, None, false)
+            .map(|key_entry_pair| key_entry_pair.map(|(key, entry)| (key, entry.entry)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("A".to_string(), MemtableEntry::Value("new".to_string())),
+                ("B".to_string(), MemtableEntry::Tombstone),
+                ("C".to_string(), MemtableEntry::Value("old".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn merging_iterator_folds_merge_operands_into_shadowed_entries() {
+        let merge_source = |entries: Vec<(&str, MemtableEntry)>| -> EntryIterator {
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|(key, entry)| Ok((key.to_string(), unsequenced_entry(entry))))
+                .collect();
+            Box::new(entries.into_iter())
+        };
+        let operand = |operand: &str| MemtableEntry::Merge(operand.to_string());
+        let merged_entries = |may_have_older_entries| {
+            let sources = vec![
+                merge_source(vec![
+                    ("A", operand("1")),
+                    ("A", operand("2")),
+                    ("B", operand("5")),
+                ]),
+                merge_source(vec![
+                    ("A", MemtableEntry::Value("10".to_string())),
+                    ("B", operand("1")),
+                    ("C", operand("1")),
+                ]),
+            ];
+            let merge_operator: Arc<dyn MergeOperator> = Arc::new(U64AddOperator::new());
+            MergingIterator::new(sources, Some(merge_operator), may_have_older_entries)
+                .map(|key_entry_pair| key_entry_pair.map(|(key, entry)| (key, entry.entry)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        assert_eq!(
+            merged_entries(false),
+            vec![
+                ("A".to_string(), MemtableEntry::Value("13".to_string())),
+                ("B".to_string(), MemtableEntry::Value("6".to_string())),
+                ("C".to_string(), MemtableEntry::Value("1".to_string())),
+            ]
+        );
+        //Operands with no value under them are combined rather than applied to nothing
+        assert_eq!(
+            merged_entries(true),
+            vec![
+                ("A".to_string(), MemtableEntry::Value("13".to_string())),
+                ("B".to_string(), operand("6")),
+                ("C".to_string(), operand("1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn merging_iterator_returns_source_error_and_stops() {
+        let failing_source: EntryIterator = Box::new(
+            vec![
+                Ok(("B".to_string(), unsequenced_entry(MemtableEntry::Tombstone))),
+                Err(Error::Corruption("unreadable entry".to_owned())),
+            ]
+            .into_iter(),
+        );
+        let sources = vec![
+            failing_source,
+            source(vec![("A", Some("1")), ("C", Some("1"))]),
+        ];
+        let mut merging_iterator = MergingIterator::new(sources, None, false);
+        assert_eq!(merging_iterator.next().unwrap().unwrap().0, "A");
+        assert_eq!(merging_iterator.next().unwrap().unwrap().0, "B");
+        assert!(matches!(
+            merging_iterator.next(),
+            Some(Err(Error::Corruption(_)))
+        ));
+        assert!(merging_iterator.next().is_none());
+    }
+}
@@ -1,23 +1,87 @@
 use crate::{
-    memtable::Memtable,
+    error::{Error, Result},
+    manifest::{read_manifest, Manifest, ManifestEdit, ManifestFile, ManifestState},
+    memtable::{ImmutableMemtable, Memtable},
     memtable_config::MemtableConfig,
+    memtable_entry::{current_time_in_millis, MemtableEntry, SequencedEntry},
+    memtable_file::{
+        find_memtable_files, load_manifest_file, load_memtable_file, memtable_file_path,
+        remove_obsolete_memtable_files, remove_temporary_memtable_files, MemtableFile,
+    },
+    memtable_file_index::has_sequence_numbers,
+    memtable_file_iterator::MemtableFileIterator,
+    memtable_file_verify::{verify_memtable_file, CorruptBlock},
     memtable_search_file::{
         determine_file_search_start_position,
         search_file_for_key_from_starting_position_until_next_offset,
     },
+    merge_operator::{fold_entries, MergeOperator},
+    merging_iterator::{EntryIterator, MergingIterator},
+    transaction::Transaction,
+    write_ahead_log::{find_write_ahead_logs, read_write_ahead_log, WriteAheadLog},
+    write_batch::WriteBatch,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
 pub trait StringLike: Ord + Clone + std::fmt::Display + Into<String> {}
 impl<T> StringLike for T where T: Ord + Clone + std::fmt::Display + Into<String> {}
 
+/// Every method takes `&self`, so the table can be shared between threads, see
+/// [`crate::db::Db`]. Writes are serialised by a single lock over the write ahead log and
+/// manifest, while the memtable, immutable memtable and memtable files each sit behind their
+/// own lock, which readers hold only long enough to copy an entry or take a reference.
+///
+/// Every write is tagged with the sequence number after the last, kept alongside its entry
+/// in the memtable, write ahead log and memtable files, so that a [`Snapshot`] can read the
+/// table as it was at one sequence number.
 pub struct SortedStringTable<K, V>
 where
     K: StringLike,
     V: StringLike,
 {
-    memtable: Memtable<K, V>,
+    memtable: RwLock<Memtable<K, V>>,
+    //Filled with the immutable memtable the current memtable is frozen into, for snapshots
+    //taken before the freeze. Only replaced while the memtable is locked for writing
+    frozen_memtable: Mutex<Arc<OnceLock<Arc<ImmutableMemtable>>>>,
+    //A full memtable being written to level 0 by the flush thread, and still read from until then
+    immutable_memtable: RwLock<Option<Arc<ImmutableMemtable>>>,
     memtable_config: MemtableConfig,
-    current_size: usize,
+    //Level 0 is ordered from oldest to most recently written, deeper levels by key. Readers
+    //take the current levels and read their files without holding the lock
+    memtable_file_levels: RwLock<Arc<Vec<Vec<MemtableFile>>>>,
+    //Sequence number of the newest write readable from the memtable, only advanced while the
+    //memtable is locked for writing
+    last_sequence_number: AtomicU64,
+    //Number of open snapshots at each sequence number
+    snapshot_sequence_numbers: Mutex<BTreeMap<u64, usize>>,
+    write_state: Mutex<WriteState>,
+}
+
+/// The parts of the table only touched by writes, flushes and compactions.
+struct WriteState {
+    flush_thread: Option<JoinHandle<Result<MemtableFile>>>,
+    //Number of inserts and removes made since the table was opened, overwrites included
+    write_count: usize,
+    write_ahead_log: WriteAheadLog,
+    manifest: Manifest,
+    //Levels replaced by a flush or compaction, which readers may still be reading, and the
+    //files they list that are no longer live
+    retired_memtable_file_levels: Vec<Arc<Vec<Vec<MemtableFile>>>>,
+    obsolete_file_paths: Vec<PathBuf>,
+    //A flush or compaction failure from after a write was logged, which that write could not
+    //report without being retried and applied twice
+    flush_error: Option<Error>,
 }
 
 impl<K, V> SortedStringTable<K, V>
@@ -25,120 +89,2271 @@ where
     K: StringLike,
     V: StringLike,
 {
-    pub fn new(memtable_config: MemtableConfig) -> Self {
-        SortedStringTable {
-            memtable: Memtable::new(memtable_config.clone()),
+    /// Opens the table held in the configured data directory, see [`SortedStringTable::open`].
+    pub fn new(memtable_config: MemtableConfig) -> Result<Self> {
+        let data_directory = memtable_config.data_directory.clone();
+        SortedStringTable::open(&data_directory, memtable_config)
+    }
+
+    /// Opens the table held in `data_directory`, loading the memtable files its manifest lists
+    /// as live and replaying any write ahead logs left by a process that stopped before its
+    /// memtable was flushed. Files the manifest does not list are removed, unless its last record
+    /// was cut short, and a manifest with a corrupt record is an error. A directory written
+    /// before the manifest was introduced is instead recovered from its memtable files, and
+    /// given a manifest from then on.
+    pub fn open(data_directory: &str, mut memtable_config: MemtableConfig) -> Result<Self> {
+        memtable_config.data_directory = data_directory.to_owned();
+        let mut memtable = Memtable::new(memtable_config.clone())?;
+        remove_temporary_memtable_files(data_directory)?;
+        let (mut manifest_state, mut memtable_file_levels) = match read_manifest(data_directory)? {
+            Some(manifest_state) => {
+                //A file written by the lost edit of a torn record is kept, to be removed by a
+                //later open once the manifest written below has been read in full
+                if !manifest_state.ends_in_torn_record {
+                    remove_obsolete_memtable_files(data_directory, &manifest_state)?;
+                }
+                let memtable_file_levels = manifest_state
+                    .manifest_file_levels
+                    .iter()
+                    .map(|manifest_files| {
+                        manifest_files
+                            .iter()
+                            .map(|manifest_file| {
+                                load_manifest_file(&memtable_config, manifest_file)
+                            })
+                            .collect::<Result<Vec<MemtableFile>>>()
+                    })
+                    .collect::<Result<Vec<Vec<MemtableFile>>>>()?;
+                (manifest_state, memtable_file_levels)
+            }
+            None => {
+                let memtable_file_levels = load_memtable_files(&memtable_config)?;
+                let manifest_state = manifest_state_from_directory(
+                    data_directory,
+                    memtable.next_file_number(),
+                    &memtable_file_levels,
+                )?;
+                (manifest_state, memtable_file_levels)
+            }
+        };
+        //A log created after a failed manifest write can be numbered past what it recorded
+        manifest_state.next_file_number = manifest_state
+            .next_file_number
+            .max(memtable.next_file_number());
+        //Recorded by the new manifest before the memtable's log is created under the number
+        memtable.set_next_file_number(manifest_state.new_file_number());
+        let mut manifest = Manifest::create(data_directory, &manifest_state)?;
+        let replayed_write_ahead_logs = replay_write_ahead_logs(
+            &memtable_config,
+            &mut memtable,
+            &mut memtable_file_levels[0],
+            &mut manifest,
+        )?;
+
+        let last_sequence_number = manifest_state
+            .last_sequence_number
+            .max(memtable.last_sequence_number());
+
+        //Entries still held by the memtable are logged again before the replayed logs go
+        let mut write_ahead_log =
+            WriteAheadLog::create(&memtable_config.data_directory, memtable.next_file_number())?;
+        for (key, entry) in memtable.get_all_key_value_pairs() {
+            write_ahead_log.append(&key, &entry)?;
+        }
+        manifest.commit(ManifestEdit {
+            log_number: Some(write_ahead_log.log_number),
+            ..Default::default()
+        })?;
+        for replayed_write_ahead_log in replayed_write_ahead_logs {
+            fs::remove_file(replayed_write_ahead_log)?;
+        }
+
+        Ok(SortedStringTable {
+            memtable: RwLock::new(memtable),
+            frozen_memtable: Mutex::new(Arc::new(OnceLock::new())),
+            immutable_memtable: RwLock::new(None),
             memtable_config,
-            current_size: 0,
+            memtable_file_levels: RwLock::new(Arc::new(memtable_file_levels)),
+            last_sequence_number: AtomicU64::new(last_sequence_number),
+            snapshot_sequence_numbers: Mutex::new(BTreeMap::new()),
+            write_state: Mutex::new(WriteState {
+                flush_thread: None,
+                write_count: 0,
+                write_ahead_log,
+                manifest,
+                retired_memtable_file_levels: vec![],
+                obsolete_file_paths: vec![],
+                flush_error: None,
+            }),
+        })
+    }
+
+    /// Writes the key value pair. Once the memtable is full it is flushed by a background
+    /// thread, and a write only waits for that flush when the next memtable fills before it
+    /// finishes. An error from a flush leaves the entries in the immutable memtable and their
+    /// write ahead log, to be flushed again on the next write, while an error from the
+    /// compaction that follows a flush leaves the files as they were before it, so in both
+    /// cases the write itself is kept. Such an error is returned by the next write, before it
+    /// logs anything, or by [`SortedStringTable::wait_for_flush`].
+    pub fn insert(&self, key: K, value: V) -> Result<()> {
+        self.write_entries(
+            &mut self.lock_write_state(),
+            vec![(key.into(), MemtableEntry::Value(value.into()))],
+        )
+    }
+
+    /// Writes the key value pair in the same way as [`SortedStringTable::insert`], but once the
+    /// time to live has passed the key reads as removed, and compaction drops the value. The
+    /// expiry time is kept with the value in the write ahead log and memtable files, so it
+    /// survives a restart. Fails without writing when the configured format version cannot
+    /// hold an expiry time.
+    pub fn insert_with_ttl(&self, key: K, value: V, time_to_live: Duration) -> Result<()> {
+        if !has_sequence_numbers(self.memtable_config.format_version) {
+            return Err(Error::InvalidConfiguration(format!(
+                "expiring values cannot be written in format version {}",
+                self.memtable_config.format_version
+            )));
+        }
+        let time_to_live = u64::try_from(time_to_live.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = current_time_in_millis().saturating_add(time_to_live);
+        self.write_entries(
+            &mut self.lock_write_state(),
+            vec![(
+                key.into(),
+                MemtableEntry::ExpiringValue(value.into(), expires_at),
+            )],
+        )
+    }
+
+    /// Removes the key by writing a tombstone, which hides any value for it in older files
+    /// until compaction.
+    pub fn remove(&self, key: K) -> Result<()> {
+        self.write_entries(
+            &mut self.lock_write_state(),
+            vec![(key.into(), MemtableEntry::Tombstone)],
+        )
+    }
+
+    /// Writes a merge operand for the key, which the configured merge operator applies to the
+    /// key's value when it is read, flushed or compacted. Fails without writing when no merge
+    /// operator is configured, or when the operator rejects the operand. Flushes happen as for
+    /// [`SortedStringTable::insert`].
+    pub fn merge(&self, key: K, operand: V) -> Result<()> {
+        self.write_entries(
+            &mut self.lock_write_state(),
+            vec![(key.into(), MemtableEntry::Merge(operand.into()))],
+        )
+    }
+
+    /// The merge operator the table was configured with, if any.
+    pub fn merge_operator(&self) -> Option<&dyn MergeOperator> {
+        self.memtable_config.merge_operator.as_deref()
+    }
+
+    pub fn find(&self, key: &K) -> Result<Option<String>> {
+        Ok(self.find_entry(key)?.and_then(value_of_entry))
+    }
+
+    /// Returns a transaction reading from a snapshot taken now, see [`Transaction`].
+    pub fn begin_transaction(&self) -> Transaction<'_, K, V> {
+        Transaction::new(self)
+    }
+
+    /// Writes the batch in the same way as [`SortedStringTable::write`], but only if none of
+    /// the keys read have been written since the sequence number. Otherwise nothing is written
+    /// and a conflict error is returned. Other writes wait until the batch is written, so no
+    /// write can land between the check and the batch. Each key is given with whether it had a
+    /// value at the sequence number, as a compaction may since have dropped a remove along
+    /// with that value, leaving no entry to compare. Writes are only detected by their
+    /// sequence numbers, so the check fails with a configuration error in formats without
+    /// them, and for keys read from files written in one of those formats.
+    pub fn write_if_unchanged<'k>(
+        &self,
+        read_keys: impl IntoIterator<Item = (&'k K, bool)>,
+        sequence_number: u64,
+        write_batch: WriteBatch<K, V>,
+    ) -> Result<()>
+    where
+        K: 'k,
+    {
+        if !has_sequence_numbers(self.memtable_config.format_version) {
+            return Err(Error::InvalidConfiguration(format!(
+                "writes to read keys cannot be detected in format version {}",
+                self.memtable_config.format_version
+            )));
+        }
+        let mut write_state = self.lock_write_state();
+        for (read_key, had_value) in read_keys {
+            let Some(sequenced_entry) = self.find_entry(read_key)? else {
+                if had_value {
+                    return Err(Error::Conflict(format!(
+                        "key {} was removed after sequence number {}",
+                        read_key, sequence_number
+                    )));
+                }
+                continue;
+            };
+            //Every write is numbered from 1, so 0 is only given to entries read from files
+            //that predate sequence numbers
+            if sequenced_entry.sequence_number == 0 {
+                return Err(Error::InvalidConfiguration(format!(
+                    "key {} is held in a file without sequence numbers, so writes to it \
+                     cannot be detected",
+                    read_key
+                )));
+            }
+            if sequenced_entry.sequence_number > sequence_number {
+                return Err(Error::Conflict(format!(
+                    "key {} was written at sequence number {}, after {}",
+                    read_key, sequenced_entry.sequence_number, sequence_number
+                )));
+            }
+        }
+        if write_batch.is_empty() {
+            return Ok(());
+        }
+        self.write_entries(&mut write_state, write_batch.into_key_entry_pairs())
+    }
+
+    /// Returns the newest entry for the key, tombstones included, with merge operands folded
+    /// into the entries under them.
+    fn find_entry(&self, key: &K) -> Result<Option<SequencedEntry>> {
+        let key_as_string: String = key.clone().into();
+        //Merge operands must be folded with every entry under them exactly once, so the places
+        //are taken together while no entries can move between them
+        let (memtable_versions, immutable_memtable, memtable_file_levels) = {
+            let memtable = self.memtable.read().unwrap();
+            let immutable_memtable = self.immutable_memtable.read().unwrap();
+            (
+                memtable.versions_as_of(&key_as_string, u64::MAX).to_vec(),
+                immutable_memtable.clone(),
+                self.current_memtable_file_levels(),
+            )
+        };
+        self.fold_entries_for_key(
+            key,
+            memtable_versions,
+            immutable_memtable.as_deref(),
+            &memtable_file_levels,
+            u64::MAX,
+        )
+    }
+
+    /// Returns a view of the table as of the last write made before it, which `find` and
+    /// `range` go on reading while later writes, flushes and compactions run. Versions the
+    /// snapshot can see are kept in the memtable, and the files it reads kept on disk, until
+    /// it is dropped.
+    pub fn snapshot(&self) -> Snapshot<'_, K, V> {
+        //Holding the memtable lock keeps writes from being put or frozen until the snapshot
+        //is registered, so everything it holds was written at or before its sequence number
+        let _memtable = self.memtable.read().unwrap();
+        //A flush swaps its file into the levels while holding the immutable slot, so the two
+        //agree on where each entry is
+        let immutable_memtable = self.immutable_memtable.read().unwrap();
+        let sequence_number = self.last_sequence_number.load(Ordering::SeqCst);
+        *self
+            .snapshot_sequence_numbers
+            .lock()
+            .unwrap()
+            .entry(sequence_number)
+            .or_default() += 1;
+        Snapshot {
+            sorted_string_table: self,
+            sequence_number,
+            frozen_memtable: Arc::clone(&self.frozen_memtable.lock().unwrap()),
+            immutable_memtable: immutable_memtable.clone(),
+            memtable_file_levels: self.current_memtable_file_levels(),
+        }
+    }
+
+    /// Returns the live key value pairs with keys inside the range, in key order. Entries are
+    /// read lazily from the memtable and every memtable file, with newer entries shadowing
+    /// older ones and removed keys skipped. A read error is returned in place of the next
+    /// pair and ends the iteration.
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = Result<(String, String)>> + '_
+    where
+        R: RangeBounds<K>,
+    {
+        let start = map_bound_to_string(range.start_bound());
+        let end = map_bound_to_string(range.end_bound());
+        self.range_of_string_bounds(start, end)
+    }
+
+    /// Returns the live key value pairs whose keys start with the prefix, in key order. Only
+    /// the keys sorting between the prefix and the first key past it are read.
+    pub fn scan_prefix(&self, prefix: &str) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let end = match first_key_after_prefix(prefix) {
+            Some(first_key_after_prefix) => Bound::Excluded(first_key_after_prefix),
+            None => Bound::Unbounded,
+        };
+        self.range_of_string_bounds(Bound::Included(prefix.to_owned()), end)
+    }
+
+    /// Counts the distinct keys holding a value, reading every entry in the table to find them
+    /// as overwritten and removed keys cannot be told apart from live ones any other way.
+    pub fn count_live_keys(&self) -> Result<usize> {
+        let mut live_key_count = 0;
+        for key_value_pair in self.range(..) {
+            key_value_pair?;
+            live_key_count += 1;
+        }
+        Ok(live_key_count)
+    }
+
+    /// Reads every block of every memtable file, returning those whose contents do not match
+    /// their checksum or cannot be decoded. Write ahead log records are checked as they are
+    /// replayed by `open`.
+    pub fn verify(&self) -> Result<Vec<CorruptBlock>> {
+        let mut corrupt_blocks = vec![];
+        for memtable_file in self.current_memtable_file_levels().iter().flatten() {
+            corrupt_blocks.extend(verify_memtable_file(&self.memtable_config, memtable_file)?);
+        }
+        Ok(corrupt_blocks)
+    }
+
+    /// The memtable is copied within the bounds when the iteration starts, while the immutable
+    /// memtable and the memtable files are held and read as it goes, so later writes are not
+    /// seen and no lock is held between items.
+    fn range_of_string_bounds(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        //Taken together for the same reason as in `find_entry`
+        let (memtable_entries, immutable_memtable, memtable_file_levels) = {
+            let memtable = self.memtable.read().unwrap();
+            let immutable_memtable = self.immutable_memtable.read().unwrap();
+            let memtable_entries: Vec<(String, SequencedEntry)> = memtable
+                .range(start.clone(), end.clone(), u64::MAX)
+                .collect();
+            (
+                memtable_entries,
+                immutable_memtable.clone(),
+                self.current_memtable_file_levels(),
+            )
+        };
+        let mut sources: Vec<EntryIterator> = vec![Box::new(memtable_entries.into_iter().map(Ok))];
+        if let Some(immutable_memtable) = immutable_memtable {
+            sources.push(Box::new(
+                immutable_memtable
+                    .shared_range(start.clone(), end.clone(), u64::MAX)
+                    .map(Ok),
+            ));
+        }
+        self.range_of_sources_and_files(sources, memtable_file_levels, start, end)
+    }
+
+    /// Merges the memtable sources, newest first, with the entries of the memtable files
+    /// inside the bounds, folding merge operands and skipping removed keys.
+    fn range_of_sources_and_files<'a>(
+        &'a self,
+        mut sources: Vec<EntryIterator<'a>>,
+        memtable_file_levels: Arc<Vec<Vec<MemtableFile>>>,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> impl Iterator<Item = Result<(String, String)>> + 'a {
+        for memtable_file in memtable_file_levels[0].iter().rev() {
+            sources.push(self.iterate_memtable_file_from(memtable_file, start.clone()));
+        }
+        //The files of a deeper level never overlap, so the level is read as one source
+        for level in 1..memtable_file_levels.len() {
+            let memtable_files = &memtable_file_levels[level];
+            let first_file_index = match &start {
+                Bound::Included(start_key) | Bound::Excluded(start_key) => memtable_files
+                    .partition_point(|memtable_file| memtable_file.largest_key < *start_key),
+                Bound::Unbounded => 0,
+            };
+            let file_count = memtable_files.len();
+            let memtable_file_levels = Arc::clone(&memtable_file_levels);
+            let start = start.clone();
+            sources.push(Box::new((first_file_index..file_count).flat_map(
+                move |file_index| {
+                    self.iterate_memtable_file_from(
+                        &memtable_file_levels[level][file_index],
+                        start.clone(),
+                    )
+                },
+            )));
         }
+        MergingIterator::new(sources, self.memtable_config.merge_operator.clone(), false)
+            .take_while(move |key_entry_pair| {
+                //Holding the levels keeps their files on disk until the iteration is dropped
+                let _ = &memtable_file_levels;
+                match key_entry_pair {
+                    Ok((key, _)) => is_before_end_bound(key, &end),
+                    Err(_) => true,
+                }
+            })
+            .filter_map(|key_entry_pair| match key_entry_pair {
+                Ok((key, sequenced_entry)) => Some(Ok((key, value_of_entry(sequenced_entry)?))),
+                Err(error) => Some(Err(error)),
+            })
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        self.memtable.insert(key, value);
-        self.current_size += 1;
+    /// Blocks until the memtable being flushed in the background, if there is one, has been
+    /// written and its file added to level 0. Also returns any flush or compaction failure
+    /// since the last write, which writes leave for the next write or this to report.
+    pub fn wait_for_flush(&self) -> Result<()> {
+        let mut write_state = self.lock_write_state();
+        if self.immutable_memtable.read().unwrap().is_some() && write_state.flush_thread.is_none() {
+            self.start_flush(&mut write_state);
+        }
+        let flush_result = self.finish_flush(&mut write_state);
+        match write_state.flush_error.take() {
+            Some(flush_error) => Err(flush_error),
+            None => flush_result,
+        }
     }
 
-    pub fn find(&self, key: &K) -> Option<String> {
-        let memtable_search_result = self.memtable.find(key);
-        if memtable_search_result.is_some() {
-            let result_as_string = memtable_search_result.unwrap().to_string();
-            return Some(result_as_string);
+    /// Applies every insert and remove in the batch atomically. The batch is logged as a
+    /// single write ahead log record and takes consecutive sequence numbers, and its entries
+    /// reach the memtable together, so neither readers nor a replay after a crash see only
+    /// part of it. Flushes happen as for [`SortedStringTable::insert`].
+    pub fn write(&self, write_batch: WriteBatch<K, V>) -> Result<()> {
+        if write_batch.is_empty() {
+            return Ok(());
         }
+        self.write_entries(
+            &mut self.lock_write_state(),
+            write_batch.into_key_entry_pairs(),
+        )
+    }
 
-        let key_offsets_of_most_recent_written_memtable =
-            &self.memtable.key_offsets_of_most_recent_written_memtable;
-        if key_offsets_of_most_recent_written_memtable.is_some() {
-            let search_file_result = self.search_most_recent_memtable_file(
+    fn write_entries(
+        &self,
+        write_state: &mut WriteState,
+        key_entry_pairs: Vec<(String, MemtableEntry)>,
+    ) -> Result<()> {
+        //An operand that cannot be applied would fail every read and compaction of its key, so
+        //it is rejected before anything is logged
+        for (key, entry) in &key_entry_pairs {
+            let MemtableEntry::Merge(operand) = entry else {
+                continue;
+            };
+            let merge_operator = self.merge_operator().ok_or_else(|| {
+                Error::InvalidConfiguration(
+                    "merge operands cannot be written without a merge operator".to_owned(),
+                )
+            })?;
+            if !merge_operator.validate_operand(key, operand) {
+                return Err(Error::MergeFailed(format!(
+                    "merge operator cannot apply operand {} for key {}",
+                    operand, key
+                )));
+            }
+        }
+        //Reported before anything is logged, so the write can be retried
+        if let Some(flush_error) = write_state.flush_error.take() {
+            return Err(flush_error);
+        }
+        //A log that failed to rotate after the last freeze is rotated before it takes new entries
+        if write_state.write_ahead_log.log_number
+            != self.memtable.read().unwrap().next_file_number()
+        {
+            self.rotate_write_ahead_log(write_state)?;
+        }
+        //Writes are serialised by the write state, so no other write can take the numbers
+        let first_sequence_number = self.last_sequence_number.load(Ordering::SeqCst) + 1;
+        let write_count = key_entry_pairs.len();
+        match key_entry_pairs.as_slice() {
+            [(key, entry)] => write_state.write_ahead_log.append(
                 key,
-                key_offsets_of_most_recent_written_memtable
-                    .as_ref()
-                    .unwrap(),
-            );
-            search_file_result
-        } else {
-            None
+                &SequencedEntry {
+                    sequence_number: first_sequence_number,
+                    entry: entry.clone(),
+                },
+            )?,
+            _ => write_state
+                .write_ahead_log
+                .append_batch(first_sequence_number, &key_entry_pairs)?,
         }
+        let memtable_is_full = {
+            let mut memtable = self.memtable.write().unwrap();
+            let oldest_snapshot_sequence_number = self
+                .snapshot_sequence_numbers
+                .lock()
+                .unwrap()
+                .keys()
+                .next()
+                .copied();
+            let mut sequence_number = first_sequence_number;
+            for (key, entry) in key_entry_pairs {
+                let sequenced_entry = SequencedEntry {
+                    sequence_number,
+                    entry,
+                };
+                memtable.put_entry(key, sequenced_entry, oldest_snapshot_sequence_number);
+                sequence_number += 1;
+            }
+            self.last_sequence_number
+                .store(sequence_number - 1, Ordering::SeqCst);
+            memtable.is_full()
+        };
+        write_state.write_count += write_count;
+
+        //The write is logged and readable, so it stands whether or not the flush succeeds
+        if let Err(flush_error) = self.flush_after_write(write_state, memtable_is_full) {
+            write_state.flush_error = Some(flush_error);
+        }
+        Ok(())
     }
 
-    fn search_most_recent_memtable_file(
+    /// Freezes the memtable once it is full. A flush that has finished is only added to level
+    /// 0, and the files compacted, when the memtable fills again, so writes that do not fill
+    /// it never wait on either.
+    fn flush_after_write(
         &self,
-        key_to_find: &K,
-        key_offsets_of_most_recent_written_memtable: &Vec<(K, usize)>,
-    ) -> Option<String> {
-        let search_start_position = determine_file_search_start_position(
-            key_to_find,
-            key_offsets_of_most_recent_written_memtable,
+        write_state: &mut WriteState,
+        memtable_is_full: bool,
+    ) -> Result<()> {
+        //A second full memtable has to wait for the immutable memtable to be flushed
+        if memtable_is_full && write_state.flush_thread.is_some() {
+            self.finish_flush(write_state)?;
+        }
+        let has_immutable_memtable = self.immutable_memtable.read().unwrap().is_some();
+        match has_immutable_memtable {
+            //Retries a flush that failed
+            true if write_state.flush_thread.is_none() => self.start_flush(write_state),
+            true => {}
+            false if memtable_is_full => self.freeze_memtable(write_state)?,
+            false => {}
+        }
+        Ok(())
+    }
+
+    /// Swaps the full memtable into the immutable slot and starts flushing it, moving writes
+    /// on to a new memtable and write ahead log.
+    fn freeze_memtable(&self, write_state: &mut WriteState) -> Result<()> {
+        {
+            //The entries reach the immutable slot before a reader can see the memtable empty
+            let mut memtable = self.memtable.write().unwrap();
+            let immutable_memtable = Arc::new(memtable.freeze());
+            let mut frozen_memtable = self.frozen_memtable.lock().unwrap();
+            let _ = frozen_memtable.set(Arc::clone(&immutable_memtable));
+            *frozen_memtable = Arc::new(OnceLock::new());
+            *self.immutable_memtable.write().unwrap() = Some(immutable_memtable);
+            //The new log's number is recorded as used before the log is created
+            memtable.set_next_file_number(write_state.manifest.new_file_number());
+        }
+        self.start_flush(write_state);
+        write_state.manifest.commit(ManifestEdit::default())?;
+        self.rotate_write_ahead_log(write_state)
+    }
+
+    fn start_flush(&self, write_state: &mut WriteState) {
+        if let Some(immutable_memtable) = self.immutable_memtable.read().unwrap().clone() {
+            write_state.flush_thread =
+                Some(thread::spawn(move || immutable_memtable.write_to_file()));
+        }
+    }
+
+    /// Waits for the flush thread, then adds its file to level 0 in place of the immutable
+    /// memtable and commits it to the manifest. The write ahead logs of flushed memtables are
+    /// then removed, and the files compacted.
+    fn finish_flush(&self, write_state: &mut WriteState) -> Result<()> {
+        let Some(flush_thread) = write_state.flush_thread.take() else {
+            return Ok(());
+        };
+        let written_file = flush_thread
+            .join()
+            .map_err(|_| Error::Io(io::Error::other("memtable flush thread panicked")))??;
+        let mut memtable_file_levels = Vec::clone(&self.current_memtable_file_levels());
+        //Every entry of the memtable is in the log numbered after it
+        let next_log_number = self.memtable.read().unwrap().next_file_number();
+        record_flushed_file(
+            &mut write_state.manifest,
+            &mut memtable_file_levels[0],
+            written_file,
+            Some(next_log_number),
+            self.last_sequence_number.load(Ordering::SeqCst),
+        )?;
+        //Readers take the immutable memtable and the levels together, so they see its entries
+        //in one or the other but never both
+        {
+            let mut immutable_memtable = self.immutable_memtable.write().unwrap();
+            self.replace_memtable_file_levels(write_state, memtable_file_levels.clone())?;
+            *immutable_memtable = None;
+        }
+        for (log_number, log_path) in find_write_ahead_logs(&self.memtable_config.data_directory)? {
+            if log_number < write_state.manifest.log_number() {
+                fs::remove_file(log_path)?;
+            }
+        }
+        //Bounds the number of files a lookup may read. Any compaction committed before an
+        //error is kept
+        let compaction_result = self.memtable_config.compaction_strategy.compact(
+            &self.memtable_config,
+            &mut memtable_file_levels,
+            &mut write_state.manifest,
         );
-        let search_result = search_file_for_key_from_starting_position_until_next_offset(
+        self.replace_memtable_file_levels(write_state, memtable_file_levels)?;
+        compaction_result
+    }
+
+    /// Makes the levels the ones new reads use. Files only the replaced levels list are
+    /// removed once no reader holds any replaced levels, or otherwise by `open`.
+    fn replace_memtable_file_levels(
+        &self,
+        write_state: &mut WriteState,
+        memtable_file_levels: Vec<Vec<MemtableFile>>,
+    ) -> Result<()> {
+        let live_file_paths: HashSet<&Path> = memtable_file_levels
+            .iter()
+            .flatten()
+            .map(|memtable_file| memtable_file.file_path.as_path())
+            .collect();
+        let obsolete_file_paths: Vec<PathBuf> = self
+            .current_memtable_file_levels()
+            .iter()
+            .flatten()
+            .filter(|memtable_file| !live_file_paths.contains(memtable_file.file_path.as_path()))
+            .map(|memtable_file| memtable_file.file_path.clone())
+            .collect();
+        let replaced_memtable_file_levels = std::mem::replace(
+            &mut *self.memtable_file_levels.write().unwrap(),
+            Arc::new(memtable_file_levels),
+        );
+        write_state.obsolete_file_paths.extend(obsolete_file_paths);
+        write_state
+            .retired_memtable_file_levels
+            .push(replaced_memtable_file_levels);
+        //Retired levels can no longer be taken, so a count of one means no reader holds them
+        let has_readers = write_state
+            .retired_memtable_file_levels
+            .iter()
+            .any(|memtable_file_levels| Arc::strong_count(memtable_file_levels) > 1);
+        if !has_readers {
+            write_state.retired_memtable_file_levels.clear();
+            for obsolete_file_path in std::mem::take(&mut write_state.obsolete_file_paths) {
+                fs::remove_file(obsolete_file_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn current_memtable_file_levels(&self) -> Arc<Vec<Vec<MemtableFile>>> {
+        Arc::clone(&self.memtable_file_levels.read().unwrap())
+    }
+
+    fn lock_write_state(&self) -> MutexGuard<'_, WriteState> {
+        self.write_state.lock().unwrap()
+    }
+
+    /// Folds the key's memtable versions, then those of the immutable memtable as of the
+    /// sequence number, then its entries in the files from newest to oldest. Files are only
+    /// searched while the entries found so far are merge operands, so without merges the
+    /// first place holding the key gives its latest entry. Expired values are read as
+    /// tombstones.
+    fn fold_entries_for_key(
+        &self,
+        key: &K,
+        memtable_versions: Vec<SequencedEntry>,
+        immutable_memtable: Option<&ImmutableMemtable>,
+        memtable_file_levels: &[Vec<MemtableFile>],
+        sequence_number: u64,
+    ) -> Result<Option<SequencedEntry>> {
+        let key_as_string: String = key.clone().into();
+        let immutable_versions = immutable_memtable.map_or(&[][..], |immutable_memtable| {
+            immutable_memtable.versions_as_of(&key_as_string, sequence_number)
+        });
+        let file_entries = memtable_files_for_key(memtable_file_levels, &key_as_string)
+            .filter_map(|memtable_file| self.search_memtable_file(key, memtable_file).transpose());
+        let current_time = current_time_in_millis();
+        let entries = memtable_versions
+            .into_iter()
+            .chain(immutable_versions.iter().cloned())
+            .map(Ok)
+            .chain(file_entries)
+            .map(|entry| entry.map(|entry| entry.hide_if_expired(current_time)));
+        fold_entries(self.merge_operator(), &key_as_string, entries, false)
+    }
+
+    fn search_memtable_file(
+        &self,
+        key_to_find: &K,
+        memtable_file: &MemtableFile,
+    ) -> Result<Option<SequencedEntry>> {
+        let search_start_position = match &memtable_file.key_offsets {
+            Some(key_offsets) => {
+                let key_to_find_as_string: String = key_to_find.clone().into();
+                determine_file_search_start_position(&key_to_find_as_string, key_offsets)
+            }
+            None => 0,
+        };
+        search_file_for_key_from_starting_position_until_next_offset(
             key_to_find,
             &self.memtable_config,
+            &memtable_file.file_path,
+            memtable_file.format_version,
             search_start_position,
-        );
-        if search_result.is_some() {
-            return search_result;
-        } else {
-            None
+            memtable_file.data_length,
+        )
+    }
+
+    /// Iterates the file's entries from the start bound onwards, using its key offsets to seek
+    /// to the segment holding the start key rather than reading from the beginning. A file that
+    /// cannot be opened gives its error as its only item.
+    fn iterate_memtable_file_from(
+        &self,
+        memtable_file: &MemtableFile,
+        start: Bound<String>,
+    ) -> EntryIterator<'static> {
+        let start_position = match (&start, &memtable_file.key_offsets) {
+            (Bound::Included(start_key) | Bound::Excluded(start_key), Some(key_offsets)) => {
+                determine_file_search_start_position(start_key, key_offsets)
+            }
+            _ => 0,
+        };
+        let memtable_file_iterator = match MemtableFileIterator::new(
+            &self.memtable_config,
+            &memtable_file.file_path,
+            memtable_file.format_version,
+            start_position,
+            memtable_file.data_length,
+        ) {
+            Ok(memtable_file_iterator) => memtable_file_iterator,
+            Err(error) => return Box::new(std::iter::once(Err(error))),
+        };
+        Box::new(
+            memtable_file_iterator.skip_while(move |key_entry_pair| match key_entry_pair {
+                Ok((key, _)) => match &start {
+                    Bound::Included(start_key) => key < start_key,
+                    Bound::Excluded(start_key) => key <= start_key,
+                    Bound::Unbounded => false,
+                },
+                Err(_) => false,
+            }),
+        )
+    }
+
+    /// Starts a new log for the memtable that follows a freeze. The previous log is kept until
+    /// the manifest records the file its memtable is flushed to, or otherwise for `open` to
+    /// replay.
+    fn rotate_write_ahead_log(&self, write_state: &mut WriteState) -> Result<()> {
+        write_state.write_ahead_log = WriteAheadLog::create(
+            &self.memtable_config.data_directory,
+            self.memtable.read().unwrap().next_file_number(),
+        )?;
+        Ok(())
+    }
+}
+
+/// A consistent view of the table as of one sequence number, see
+/// [`SortedStringTable::snapshot`].
+pub struct Snapshot<'a, K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    sorted_string_table: &'a SortedStringTable<K, V>,
+    sequence_number: u64,
+    //Entries of the memtable move here if it is frozen while the snapshot is open
+    frozen_memtable: Arc<OnceLock<Arc<ImmutableMemtable>>>,
+    immutable_memtable: Option<Arc<ImmutableMemtable>>,
+    memtable_file_levels: Arc<Vec<Vec<MemtableFile>>>,
+}
+
+impl<K, V> Snapshot<'_, K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    /// The sequence number of the last write the snapshot sees.
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    /// Returns the key's value as of the snapshot's sequence number.
+    pub fn find(&self, key: &K) -> Result<Option<String>> {
+        let key_as_string: String = key.clone().into();
+        let memtable_versions = {
+            //The memtable cannot be frozen while it is read, so the entries are in one or the other
+            let memtable = self.sorted_string_table.memtable.read().unwrap();
+            match self.frozen_memtable.get() {
+                Some(frozen_memtable) => frozen_memtable
+                    .versions_as_of(&key_as_string, self.sequence_number)
+                    .to_vec(),
+                None => memtable
+                    .versions_as_of(&key_as_string, self.sequence_number)
+                    .to_vec(),
+            }
+        };
+        let find_result = self.sorted_string_table.fold_entries_for_key(
+            key,
+            memtable_versions,
+            self.immutable_memtable.as_deref(),
+            &self.memtable_file_levels,
+            self.sequence_number,
+        )?;
+        Ok(find_result.and_then(value_of_entry))
+    }
+
+    /// Returns the live key value pairs with keys inside the range as of the snapshot's
+    /// sequence number, in the same way as [`SortedStringTable::range`].
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = Result<(String, String)>> + '_
+    where
+        R: RangeBounds<K>,
+    {
+        let start = map_bound_to_string(range.start_bound());
+        let end = map_bound_to_string(range.end_bound());
+        let memtable_source: EntryIterator = {
+            let memtable = self.sorted_string_table.memtable.read().unwrap();
+            match self.frozen_memtable.get() {
+                Some(frozen_memtable) => Box::new(
+                    Arc::clone(frozen_memtable)
+                        .shared_range(start.clone(), end.clone(), self.sequence_number)
+                        .map(Ok),
+                ),
+                None => {
+                    let memtable_entries: Vec<(String, SequencedEntry)> = memtable
+                        .range(start.clone(), end.clone(), self.sequence_number)
+                        .collect();
+                    Box::new(memtable_entries.into_iter().map(Ok))
+                }
+            }
+        };
+        let mut sources = vec![memtable_source];
+        if let Some(immutable_memtable) = &self.immutable_memtable {
+            sources.push(Box::new(
+                Arc::clone(immutable_memtable)
+                    .shared_range(start.clone(), end.clone(), self.sequence_number)
+                    .map(Ok),
+            ));
+        }
+        self.sorted_string_table.range_of_sources_and_files(
+            sources,
+            Arc::clone(&self.memtable_file_levels),
+            start,
+            end,
+        )
+    }
+}
+
+impl<K, V> Drop for Snapshot<'_, K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    fn drop(&mut self) {
+        let mut snapshot_sequence_numbers = self
+            .sorted_string_table
+            .snapshot_sequence_numbers
+            .lock()
+            .unwrap();
+        if let Some(snapshot_count) = snapshot_sequence_numbers.get_mut(&self.sequence_number) {
+            *snapshot_count -= 1;
+            if *snapshot_count == 0 {
+                snapshot_sequence_numbers.remove(&self.sequence_number);
+            }
+        }
+    }
+}
+
+impl<K, V> Drop for SortedStringTable<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    //A flush left running could otherwise still be writing once the table is opened again
+    fn drop(&mut self) {
+        if let Ok(mut write_state) = self.write_state.lock() {
+            let _ = self.finish_flush(&mut write_state);
         }
     }
 }
 
+/// Returns the files that may hold the key, from newest to oldest. Every level 0 file whose
+/// key range covers the key is included, but at most one file from each deeper level, as
+/// their files do not overlap.
+fn memtable_files_for_key<'a>(
+    memtable_file_levels: &'a [Vec<MemtableFile>],
+    key: &'a str,
+) -> impl Iterator<Item = &'a MemtableFile> + 'a {
+    let level_zero_files = memtable_file_levels[0]
+        .iter()
+        .rev()
+        .filter(move |memtable_file| memtable_file.may_contain_key(key));
+    let deeper_level_files = memtable_file_levels[1..]
+        .iter()
+        .filter_map(move |memtable_files| {
+            let file_index = memtable_files
+                .partition_point(|memtable_file| memtable_file.largest_key.as_str() < key);
+            memtable_files
+                .get(file_index)
+                .filter(|memtable_file| memtable_file.may_contain_key(key))
+        });
+    level_zero_files.chain(deeper_level_files)
+}
+
+fn value_of_entry(sequenced_entry: SequencedEntry) -> Option<String> {
+    match sequenced_entry.entry {
+        MemtableEntry::Value(value) | MemtableEntry::ExpiringValue(value, _) => Some(value),
+        MemtableEntry::Tombstone => None,
+        MemtableEntry::Merge(_) => unreachable!("merge operands are folded before being read"),
+    }
+}
+
+fn map_bound_to_string<K>(bound: Bound<&K>) -> Bound<String>
+where
+    K: StringLike,
+{
+    bound.map(|key| key.clone().into())
+}
+
+/// Returns the smallest key that sorts after every key starting with the prefix, or `None`
+/// when there is no such key, as for an empty prefix.
+fn first_key_after_prefix(prefix: &str) -> Option<String> {
+    let mut first_key_after_prefix = prefix.to_owned();
+    while let Some(last_char) = first_key_after_prefix.pop() {
+        let next_char = match last_char {
+            '\u{D7FF}' => Some('\u{E000}'),
+            _ => char::from_u32(last_char as u32 + 1),
+        };
+        if let Some(next_char) = next_char {
+            first_key_after_prefix.push(next_char);
+            return Some(first_key_after_prefix);
+        }
+    }
+    None
+}
+
+fn is_before_end_bound(key: &String, end: &Bound<String>) -> bool {
+    match end {
+        Bound::Included(end_key) => key <= end_key,
+        Bound::Excluded(end_key) => key < end_key,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Loads the memtable files of each level. A compaction interrupted by a crash can leave the
+/// files it was replacing in a deeper level alongside its higher numbered output, in which
+/// case the older overlapping files are superseded and removed. Only used for directories
+/// without a manifest.
+fn load_memtable_files(memtable_config: &MemtableConfig) -> Result<Vec<Vec<MemtableFile>>> {
+    let mut memtable_file_levels: Vec<Vec<MemtableFile>> = vec![vec![]];
+    for (file_number, level, file_path) in find_memtable_files(&memtable_config.data_directory)? {
+        if memtable_file_levels.len() <= level {
+            memtable_file_levels.resize_with(level + 1, Vec::new);
+        }
+        let memtable_file = load_memtable_file(memtable_config, file_number, level, file_path)?;
+        memtable_file_levels[level].push(memtable_file);
+    }
+    for memtable_files in &mut memtable_file_levels[1..] {
+        let mut live_memtable_files: Vec<MemtableFile> = vec![];
+        for memtable_file in std::mem::take(memtable_files).into_iter().rev() {
+            let is_superseded = live_memtable_files.iter().any(|live_memtable_file| {
+                live_memtable_file
+                    .overlaps_key_range(&memtable_file.smallest_key, &memtable_file.largest_key)
+            });
+            match is_superseded {
+                true => fs::remove_file(&memtable_file.file_path)?,
+                false => live_memtable_files.push(memtable_file),
+            }
+        }
+        live_memtable_files.sort_by(|first_file, second_file| {
+            first_file.smallest_key.cmp(&second_file.smallest_key)
+        });
+        *memtable_files = live_memtable_files;
+    }
+    Ok(memtable_file_levels)
+}
+
+/// Builds the manifest state of a directory written before the manifest was introduced. Its
+/// logs were only removed once their memtable reached a file, so the oldest log without one
+/// is the first that still needs replaying.
+fn manifest_state_from_directory(
+    data_directory: &str,
+    next_file_number: u64,
+    memtable_file_levels: &[Vec<MemtableFile>],
+) -> Result<ManifestState> {
+    let log_number = find_write_ahead_logs(data_directory)?
+        .into_iter()
+        .map(|(log_number, _)| log_number)
+        .find(|log_number| !memtable_file_path(data_directory, *log_number).exists())
+        .unwrap_or(next_file_number);
+    Ok(ManifestState {
+        log_number,
+        next_file_number,
+        last_sequence_number: 0,
+        ends_in_torn_record: false,
+        manifest_file_levels: memtable_file_levels
+            .iter()
+            .map(|memtable_files| memtable_files.iter().map(ManifestFile::from).collect())
+            .collect(),
+    })
+}
+
+/// Replays each write ahead log the manifest still needs, oldest first, and returns the
+/// paths of every log found. Logs of memtables that were flushed are skipped.
+fn replay_write_ahead_logs<K, V>(
+    memtable_config: &MemtableConfig,
+    memtable: &mut Memtable<K, V>,
+    memtable_files: &mut Vec<MemtableFile>,
+    manifest: &mut Manifest,
+) -> Result<Vec<std::path::PathBuf>>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    let write_ahead_logs = find_write_ahead_logs(&memtable_config.data_directory)?;
+    for (log_number, log_path) in &write_ahead_logs {
+        if *log_number < manifest.log_number() {
+            continue;
+        }
+        for (key, entry) in read_write_ahead_log(log_path)? {
+            if let Some(written_file) = memtable.insert_entry(key, entry)? {
+                memtable.set_next_file_number(manifest.new_file_number());
+                //The rest of the replayed entries are only logged once replay finishes
+                let last_sequence_number = memtable.last_sequence_number();
+                record_flushed_file(
+                    manifest,
+                    memtable_files,
+                    written_file,
+                    None,
+                    last_sequence_number,
+                )?;
+            }
+        }
+    }
+    Ok(write_ahead_logs
+        .into_iter()
+        .map(|(_, log_path)| log_path)
+        .collect())
+}
+
+/// Adds a file just flushed from a memtable to level 0 and commits it to the manifest. When
+/// every entry not yet flushed is in logs numbered from `log_number` on, the edit also marks
+/// the older logs as no longer needed. The last sequence number given is recorded so that
+/// sequence numbers go on increasing once the file's log is removed.
+fn record_flushed_file(
+    manifest: &mut Manifest,
+    memtable_files: &mut Vec<MemtableFile>,
+    written_file: MemtableFile,
+    log_number: Option<u64>,
+    last_sequence_number: u64,
+) -> Result<()> {
+    let edit = ManifestEdit {
+        log_number,
+        added_files: vec![ManifestFile::from(&written_file)],
+        last_sequence_number: Some(last_sequence_number),
+        ..Default::default()
+    };
+    memtable_files.push(written_file);
+    manifest.commit(edit)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::memtable_config::MemtableConfig;
+    use std::{fs, path::PathBuf, sync::Arc, thread, time::Duration};
+
+    use crate::{
+        error::Error,
+        leveled_compaction::LeveledCompaction,
+        manifest::manifest_path,
+        memtable_config::MemtableConfig,
+        memtable_file::{leveled_memtable_file_path, memtable_file_path},
+        memtable_file_index::{FILTER_FORMAT_VERSION, SEQUENCE_FORMAT_VERSION},
+        merge_operator::U64AddOperator,
+        test_utils::test_data_directory,
+        write_batch::WriteBatch,
+    };
 
-    use super::SortedStringTable;
+    use super::{first_key_after_prefix, memtable_files_for_key, SortedStringTable};
 
     #[test]
     fn new_sorted_string_table() {
-        let memtable_config = MemtableConfig::new(7, "./output/test_result.txt");
-        let sorted_string_table = SortedStringTable::<String, String>::new(memtable_config);
-        assert_eq!(sorted_string_table.current_size, 0);
+        let data_directory = test_data_directory("new_sorted_string_table");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        assert_eq!(sorted_string_table.lock_write_state().write_count, 0);
+    }
+
+    #[test]
+    fn sorted_string_table_open_rejects_invalid_configuration() {
+        let data_directory = test_data_directory("sorted_string_table_invalid_configuration");
+        let mut memtable_config = MemtableConfig::new(7, &data_directory);
+        memtable_config.key_offset_indicator = memtable_config.key_value_delimeter;
+        let open_result =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config);
+        assert!(matches!(open_result, Err(Error::InvalidConfiguration(_))));
     }
 
     #[test]
     fn sorted_string_table_insert() {
-        let memtable_config = MemtableConfig::new(7, "./output/test_result.txt");
-        let mut sorted_string_table = SortedStringTable::<String, String>::new(memtable_config);
-        sorted_string_table.insert("A".to_owned(), "1".to_owned());
-        assert_eq!(sorted_string_table.current_size, 1);
+        let data_directory = test_data_directory("sorted_string_table_insert");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        sorted_string_table
+            .insert("A".to_owned(), "1".to_owned())
+            .unwrap();
+        assert_eq!(sorted_string_table.lock_write_state().write_count, 1);
     }
 
     #[test]
     fn sorted_string_table_find_value_still_in_memtable() {
-        let memtable_config = MemtableConfig::new(7, "./output/test_result.txt");
-        let mut sorted_string_table = SortedStringTable::<String, String>::new(memtable_config);
-        sorted_string_table.insert("A".to_owned(), "1".to_owned());
-        assert_eq!(sorted_string_table.current_size, 1);
+        let data_directory = test_data_directory("sorted_string_table_find_in_memtable");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        sorted_string_table
+            .insert("A".to_owned(), "1".to_owned())
+            .unwrap();
+        assert_eq!(sorted_string_table.lock_write_state().write_count, 1);
         let key_to_find = "A".to_string();
-        let find_result = sorted_string_table.find(&key_to_find);
+        let find_result = sorted_string_table.find(&key_to_find).unwrap();
         assert!(find_result.is_some());
         assert_eq!(find_result.unwrap(), "1");
     }
 
     #[test]
     fn sorted_string_table_find_value_not_present() {
-        let memtable_config = MemtableConfig::new(7, "./output/test_result.txt");
-        let mut sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config);
-        sorted_string_table.insert("A".to_owned(), "1");
-        assert_eq!(sorted_string_table.current_size, 1);
+        let data_directory = test_data_directory("sorted_string_table_find_not_present");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("A".to_owned(), "1").unwrap();
+        assert_eq!(sorted_string_table.lock_write_state().write_count, 1);
         let key_to_find = "B".to_string();
-        let find_result = sorted_string_table.find(&key_to_find);
+        let find_result = sorted_string_table.find(&key_to_find).unwrap();
         assert!(find_result.is_none());
     }
 
     #[test]
     fn sorted_string_table_insert_beyond_memtable_capacity() {
-        let memtable_config = MemtableConfig::new(7, "./output/test_result.txt");
-        let mut sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config);
-        sorted_string_table.insert("A".to_owned(), "1");
-        sorted_string_table.insert("B".to_owned(), "1");
-        sorted_string_table.insert("C".to_owned(), "1");
-        sorted_string_table.insert("D".to_owned(), "1");
-        sorted_string_table.insert("E".to_owned(), "1");
-        sorted_string_table.insert("F".to_owned(), "1");
-        sorted_string_table.insert("G".to_owned(), "1");
-        sorted_string_table.insert("H".to_owned(), "1");
-        assert_eq!(sorted_string_table.current_size, 8);
-        assert_eq!(sorted_string_table.memtable.current_size, 1)
+        let data_directory = test_data_directory("sorted_string_table_beyond_capacity");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("A".to_owned(), "1").unwrap();
+        sorted_string_table.insert("B".to_owned(), "1").unwrap();
+        sorted_string_table.insert("C".to_owned(), "1").unwrap();
+        sorted_string_table.insert("D".to_owned(), "1").unwrap();
+        sorted_string_table.insert("E".to_owned(), "1").unwrap();
+        sorted_string_table.insert("F".to_owned(), "1").unwrap();
+        sorted_string_table.insert("G".to_owned(), "1").unwrap();
+        sorted_string_table.insert("H".to_owned(), "1").unwrap();
+        assert_eq!(sorted_string_table.lock_write_state().write_count, 8);
+        assert_eq!(sorted_string_table.memtable.read().unwrap().current_size, 1);
+    }
+
+    #[test]
+    fn sorted_string_table_adds_finished_flushes_once_the_memtable_fills() {
+        let data_directory = test_data_directory("sorted_string_table_adds_finished_flushes");
+        let memtable_config = MemtableConfig::new(2, &data_directory);
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("A", "1").unwrap();
+        sorted_string_table.insert("B", "1").unwrap();
+        while !sorted_string_table
+            .lock_write_state()
+            .flush_thread
+            .as_ref()
+            .unwrap()
+            .is_finished()
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        //A write that does not fill the memtable leaves the flush, and any compaction, for later
+        sorted_string_table.insert("C", "1").unwrap();
+        assert!(sorted_string_table
+            .immutable_memtable
+            .read()
+            .unwrap()
+            .is_some());
+        assert!(sorted_string_table.current_memtable_file_levels()[0].is_empty());
+        sorted_string_table.insert("D", "1").unwrap();
+        assert_eq!(
+            sorted_string_table.current_memtable_file_levels()[0].len(),
+            1
+        );
+        for key in ["A", "B", "C", "D"] {
+            assert_eq!(
+                sorted_string_table.find(&key).unwrap(),
+                Some("1".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn sorted_string_table_reports_flush_errors_after_the_write_that_caused_them() {
+        let data_directory = test_data_directory("sorted_string_table_reports_flush_errors");
+        let memtable_config = MemtableConfig::new(2, &data_directory);
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("A", "1").unwrap();
+        fs::remove_dir_all(&data_directory).unwrap();
+
+        //The write is logged before its memtable fails to flush, so it is kept and succeeds
+        sorted_string_table.insert("B", "1").unwrap();
+        assert_eq!(
+            sorted_string_table.find(&"B").unwrap(),
+            Some("1".to_owned())
+        );
+        assert!(matches!(
+            sorted_string_table.insert("C", "1"),
+            Err(Error::Io(_))
+        ));
+        assert_eq!(sorted_string_table.find(&"C").unwrap(), None);
+        assert!(matches!(
+            sorted_string_table.wait_for_flush(),
+            Err(Error::Io(_))
+        ));
+
+        fs::create_dir_all(&data_directory).unwrap();
+        sorted_string_table.insert("C", "1").unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+        assert_eq!(
+            sorted_string_table.current_memtable_file_levels()[0].len(),
+            1
+        );
+        for key in ["A", "B", "C"] {
+            assert_eq!(
+                sorted_string_table.find(&key).unwrap(),
+                Some("1".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn sorted_string_table_counts_writes_and_live_keys_separately() {
+        let data_directory = test_data_directory("sorted_string_table_writes_and_live_keys");
+        let memtable_config = MemtableConfig::new(3, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        for i in 0..20 {
+            sorted_string_table
+                .insert(format!("key{}", i % 4), i.to_string())
+                .unwrap();
+        }
+        sorted_string_table.remove("key1".to_owned()).unwrap();
+        sorted_string_table.remove("missing".to_owned()).unwrap();
+        assert_eq!(sorted_string_table.lock_write_state().write_count, 22);
+        assert_eq!(sorted_string_table.count_live_keys().unwrap(), 3);
+    }
+
+    #[test]
+    fn sorted_string_table_find_values_across_flushed_files() {
+        let data_directory = test_data_directory("sorted_string_table_across_flushed_files");
+        let memtable_config = MemtableConfig::new(3, &data_directory);
+        let sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("A".to_owned(), "1").unwrap();
+        sorted_string_table.insert("B".to_owned(), "1").unwrap();
+        sorted_string_table.insert("C".to_owned(), "1").unwrap();
+        sorted_string_table.insert("A".to_owned(), "2").unwrap();
+        sorted_string_table.insert("D".to_owned(), "2").unwrap();
+        sorted_string_table.insert("E".to_owned(), "2").unwrap();
+        sorted_string_table.insert("F".to_owned(), "3").unwrap();
+        //Read from the immutable memtable should its flush still be running
+        assert_eq!(
+            sorted_string_table.find(&"A".to_owned()).unwrap().unwrap(),
+            "2"
+        );
+
+        sorted_string_table.wait_for_flush().unwrap();
+        assert_eq!(
+            sorted_string_table.current_memtable_file_levels()[0].len(),
+            2
+        );
+
+        assert_eq!(
+            sorted_string_table.find(&"A".to_owned()).unwrap().unwrap(),
+            "2"
+        );
+        assert_eq!(
+            sorted_string_table.find(&"B".to_owned()).unwrap().unwrap(),
+            "1"
+        );
+        assert_eq!(
+            sorted_string_table.find(&"E".to_owned()).unwrap().unwrap(),
+            "2"
+        );
+        assert_eq!(
+            sorted_string_table.find(&"F".to_owned()).unwrap().unwrap(),
+            "3"
+        );
+        assert!(sorted_string_table.find(&"G".to_owned()).unwrap().is_none());
+    }
+
+    #[test]
+    fn sorted_string_table_replays_write_ahead_log_after_restart() {
+        let data_directory = test_data_directory("sorted_string_table_replays_log");
+        let memtable_config = MemtableConfig::new(3, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, &str>::new(memtable_config.clone()).unwrap();
+        sorted_string_table.insert("A".to_owned(), "1").unwrap();
+        sorted_string_table.insert("B".to_owned(), "1").unwrap();
+        sorted_string_table.insert("C".to_owned(), "1").unwrap();
+        sorted_string_table.insert("D".to_owned(), "2").unwrap();
+        drop(sorted_string_table);
+
+        let restarted_sorted_string_table =
+            SortedStringTable::<String, &str>::new(memtable_config.clone()).unwrap();
+        assert_eq!(
+            restarted_sorted_string_table
+                .memtable
+                .read()
+                .unwrap()
+                .current_size,
+            1
+        );
+        assert_eq!(
+            restarted_sorted_string_table
+                .find(&"D".to_owned())
+                .unwrap()
+                .unwrap(),
+            "2"
+        );
+        drop(restarted_sorted_string_table);
+
+        //The replayed entry is carried into the new log, so it survives a second restart
+        let restarted_again_sorted_string_table =
+            SortedStringTable::<String, &str>::new(memtable_config).unwrap();
+        assert_eq!(
+            restarted_again_sorted_string_table
+                .find(&"D".to_owned())
+                .unwrap()
+                .unwrap(),
+            "2"
+        );
+    }
+
+    #[test]
+    fn sorted_string_table_removes_write_ahead_log_after_flush() {
+        let data_directory = test_data_directory("sorted_string_table_removes_flushed_log");
+        let memtable_config = MemtableConfig::new(2, &data_directory);
+        let sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config).unwrap();
+        let first_write_ahead_log_path = sorted_string_table
+            .lock_write_state()
+            .write_ahead_log
+            .file_path
+            .clone();
+        sorted_string_table.insert("A".to_owned(), "1").unwrap();
+        assert!(first_write_ahead_log_path.exists());
+        sorted_string_table.insert("B".to_owned(), "1").unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+        assert!(!first_write_ahead_log_path.exists());
+        assert!(sorted_string_table
+            .lock_write_state()
+            .write_ahead_log
+            .file_path
+            .exists());
+    }
+
+    #[test]
+    fn sorted_string_table_open_finds_values_in_existing_files() {
+        let data_directory = test_data_directory("sorted_string_table_open_existing_files");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        for i in 0..20 {
+            sorted_string_table
+                .insert(format!("key{:02}", i), i.to_string())
+                .unwrap();
+        }
+        sorted_string_table
+            .insert("key03".to_owned(), "overwritten".to_owned())
+            .unwrap();
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config).unwrap();
+        assert_eq!(
+            reopened_sorted_string_table.current_memtable_file_levels()[0].len(),
+            3
+        );
+        for i in 0..20 {
+            let find_result = reopened_sorted_string_table
+                .find(&format!("key{:02}", i))
+                .unwrap();
+            let expected_value = match i {
+                3 => "overwritten".to_owned(),
+                _ => i.to_string(),
+            };
+            assert_eq!(find_result, Some(expected_value));
+        }
+    }
+
+    #[test]
+    fn sorted_string_table_open_reads_files_without_index_block() {
+        let data_directory = test_data_directory("sorted_string_table_open_without_index_block");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let legacy_file_contents: String = (0..12)
+            .map(|i| match i {
+                5 | 10 => format!("&key{:02}:{}\n", i, i),
+                _ => format!("key{:02}:{}\n", i, i),
+            })
+            .collect();
+        fs::write(memtable_file_path(&data_directory, 1), legacy_file_contents).unwrap();
+
+        let sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config).unwrap();
+        let memtable_file_levels = sorted_string_table.current_memtable_file_levels();
+        let key_offsets = memtable_file_levels[0][0].key_offsets.as_ref().unwrap();
+        assert_eq!(key_offsets.len(), 2);
+        for i in 0..12 {
+            let find_result = sorted_string_table.find(&format!("key{:02}", i)).unwrap();
+            assert_eq!(find_result, Some(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn sorted_string_table_keys_and_values_with_format_characters() {
+        let data_directory = test_data_directory("sorted_string_table_format_characters");
+        let mut text_memtable_config = MemtableConfig::new(3, &data_directory);
+        text_memtable_config.format_version = FILTER_FORMAT_VERSION;
+        let sorted_string_table =
+            SortedStringTable::<String, &str>::new(text_memtable_config).unwrap();
+        for key in ["text1", "text2", "text3"] {
+            sorted_string_table.insert(key.to_owned(), "1").unwrap();
+        }
+        drop(sorted_string_table);
+
+        //Files already written in the text format stay readable once the binary format is used
+        let memtable_config = MemtableConfig::new(3, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, &str>::open(&data_directory, memtable_config.clone())
+                .unwrap();
+        let key_value_pairs = [
+            ("&offset", "a:b"),
+            ("key:with:delimiters", "line\nbreak"),
+            ("", "empty key"),
+            ("multi\nline", ""),
+        ];
+        for (key, value) in key_value_pairs {
+            sorted_string_table.insert(key.to_owned(), value).unwrap();
+        }
+        sorted_string_table.remove("text2".to_owned()).unwrap();
+        sorted_string_table.insert("text4".to_owned(), "1").unwrap();
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, &str>::open(&data_directory, memtable_config).unwrap();
+        let memtable_file_format_versions: Vec<u32> = reopened_sorted_string_table
+            .current_memtable_file_levels()[0]
+            .iter()
+            .map(|memtable_file| memtable_file.format_version)
+            .collect();
+        assert_eq!(
+            memtable_file_format_versions,
+            vec![
+                FILTER_FORMAT_VERSION,
+                SEQUENCE_FORMAT_VERSION,
+                SEQUENCE_FORMAT_VERSION
+            ]
+        );
+        for (key, value) in key_value_pairs {
+            let find_result = reopened_sorted_string_table.find(&key.to_owned()).unwrap();
+            assert_eq!(find_result, Some(value.to_owned()));
+        }
+        assert!(reopened_sorted_string_table
+            .find(&"text2".to_owned())
+            .unwrap()
+            .is_none());
+        let keys: Vec<String> = reopened_sorted_string_table
+            .range(..)
+            .map(|key_value_pair| key_value_pair.unwrap().0)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "",
+                "&offset",
+                "key:with:delimiters",
+                "multi\nline",
+                "text1",
+                "text3",
+                "text4"
+            ]
+        );
+    }
+
+    #[test]
+    fn sorted_string_table_remove_hides_value_in_older_file() {
+        let data_directory = test_data_directory("sorted_string_table_remove_older_file");
+        let memtable_config = MemtableConfig::new(3, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, &str>::new(memtable_config.clone()).unwrap();
+        sorted_string_table.insert("A".to_owned(), "1").unwrap();
+        sorted_string_table.insert("B".to_owned(), "1").unwrap();
+        sorted_string_table.insert("C".to_owned(), "1").unwrap();
+        sorted_string_table.remove("A".to_owned()).unwrap();
+        assert!(sorted_string_table.find(&"A".to_owned()).unwrap().is_none());
+
+        sorted_string_table.insert("D".to_owned(), "1").unwrap();
+        sorted_string_table.remove("B".to_owned()).unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+        assert_eq!(
+            sorted_string_table.current_memtable_file_levels()[0].len(),
+            2
+        );
+        assert!(sorted_string_table.find(&"A".to_owned()).unwrap().is_none());
+        assert!(sorted_string_table.find(&"B".to_owned()).unwrap().is_none());
+        assert_eq!(
+            sorted_string_table.find(&"C".to_owned()).unwrap().unwrap(),
+            "1"
+        );
+
+        sorted_string_table.insert("A".to_owned(), "2").unwrap();
+        assert_eq!(
+            sorted_string_table.find(&"A".to_owned()).unwrap().unwrap(),
+            "2"
+        );
+        sorted_string_table.remove("D".to_owned()).unwrap();
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, &str>::open(&data_directory, memtable_config).unwrap();
+        assert_eq!(
+            reopened_sorted_string_table
+                .find(&"A".to_owned())
+                .unwrap()
+                .unwrap(),
+            "2"
+        );
+        assert!(reopened_sorted_string_table
+            .find(&"B".to_owned())
+            .unwrap()
+            .is_none());
+        assert!(reopened_sorted_string_table
+            .find(&"D".to_owned())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn sorted_string_table_range_merges_memtable_and_files() {
+        let data_directory = test_data_directory("sorted_string_table_range_merges");
+        let memtable_config = MemtableConfig::new(7, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        for i in 0..20 {
+            sorted_string_table
+                .insert(format!("key{:02}", i), "old".to_owned())
+                .unwrap();
+        }
+        sorted_string_table
+            .insert("key08".to_owned(), "new".to_owned())
+            .unwrap();
+        sorted_string_table.remove("key09".to_owned()).unwrap();
+        sorted_string_table
+            .insert("key10a".to_owned(), "new".to_owned())
+            .unwrap();
+
+        let range_result: Vec<(String, String)> = sorted_string_table
+            .range("key07".to_owned().."key12".to_owned())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected_range_result: Vec<(String, String)> = [
+            ("key07", "old"),
+            ("key08", "new"),
+            ("key10", "old"),
+            ("key10a", "new"),
+            ("key11", "old"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+        assert_eq!(range_result, expected_range_result);
+
+        let keys_from_start: Vec<String> = sorted_string_table
+            .range(..="key02".to_owned())
+            .map(|key_value_pair| key_value_pair.unwrap().0)
+            .collect();
+        assert_eq!(keys_from_start, vec!["key00", "key01", "key02"]);
+        assert_eq!(sorted_string_table.range(..).count(), 20);
+        assert_eq!(
+            sorted_string_table
+                .range("key12".to_owned().."key07".to_owned())
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn first_key_after_prefix_increments_last_char() {
+        assert_eq!(first_key_after_prefix("user:"), Some("user;".to_owned()));
+        assert_eq!(first_key_after_prefix("a\u{10FFFF}"), Some("b".to_owned()));
+        assert_eq!(first_key_after_prefix("\u{10FFFF}"), None);
+        assert_eq!(first_key_after_prefix(""), None);
+    }
+
+    #[test]
+    fn sorted_string_table_scan_prefix() {
+        let data_directory = test_data_directory("sorted_string_table_scan_prefix");
+        let memtable_config = MemtableConfig::new(4, &data_directory);
+        let sorted_string_table = SortedStringTable::<String, &str>::new(memtable_config).unwrap();
+        sorted_string_table
+            .insert("user/1/profile".to_owned(), "1")
+            .unwrap();
+        sorted_string_table
+            .insert("user/12/profile".to_owned(), "12")
+            .unwrap();
+        sorted_string_table
+            .insert("user/2/profile".to_owned(), "2")
+            .unwrap();
+        sorted_string_table
+            .insert("user/1".to_owned(), "1")
+            .unwrap();
+        sorted_string_table
+            .insert("user0".to_owned(), "after")
+            .unwrap();
+        sorted_string_table
+            .insert("user".to_owned(), "before")
+            .unwrap();
+        sorted_string_table
+            .insert("user/1/settings".to_owned(), "1")
+            .unwrap();
+        sorted_string_table
+            .remove("user/12/profile".to_owned())
+            .unwrap();
+
+        let scan_result: Vec<(String, String)> = sorted_string_table
+            .scan_prefix("user/1")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected_scan_result: Vec<(String, String)> = [
+            ("user/1", "1"),
+            ("user/1/profile", "1"),
+            ("user/1/settings", "1"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+        assert_eq!(scan_result, expected_scan_result);
+        assert_eq!(sorted_string_table.scan_prefix("user/").count(), 4);
+        assert_eq!(sorted_string_table.scan_prefix("").count(), 6);
+    }
+
+    #[test]
+    fn compacted_files_outlive_ranges_still_reading_them() {
+        let data_directory = test_data_directory("compacted_files_outlive_ranges");
+        let mut memtable_config = MemtableConfig::new(2, &data_directory);
+        memtable_config.compaction_threshold = 2;
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        for i in 0..4 {
+            sorted_string_table
+                .insert(format!("key{i}"), i.to_string())
+                .unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+        let memtable_file_paths: Vec<_> = sorted_string_table
+            .current_memtable_file_levels()
+            .iter()
+            .flatten()
+            .map(|memtable_file| memtable_file.file_path.clone())
+            .collect();
+
+        let mut range = sorted_string_table.range(..);
+        for i in 4..8 {
+            sorted_string_table
+                .insert(format!("key{i}"), i.to_string())
+                .unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+        assert!(memtable_file_paths
+            .iter()
+            .all(|file_path| file_path.exists()));
+        assert_eq!(range.next().unwrap().unwrap().0, "key0");
+        assert_eq!(range.count(), 3);
+
+        //Removed by the next flush, once the range is no longer reading them
+        for i in 8..10 {
+            sorted_string_table
+                .insert(format!("key{i}"), i.to_string())
+                .unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+        assert!(memtable_file_paths
+            .iter()
+            .all(|file_path| !file_path.exists()));
+        assert_eq!(sorted_string_table.count_live_keys().unwrap(), 10);
+    }
+
+    #[test]
+    fn snapshot_reads_table_as_of_its_sequence_number() {
+        let data_directory = test_data_directory("snapshot_reads_as_of_sequence_number");
+        let mut memtable_config = MemtableConfig::new(3, &data_directory);
+        memtable_config.compaction_threshold = 2;
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        for key in ["A", "B", "C", "D"] {
+            sorted_string_table
+                .insert(key.to_owned(), "old".to_owned())
+                .unwrap();
+        }
+        let snapshot = sorted_string_table.snapshot();
+        assert_eq!(snapshot.sequence_number(), 4);
+
+        //Overwrites and removes reach the memtable, a flush and a compaction
+        sorted_string_table
+            .insert("D".to_owned(), "new".to_owned())
+            .unwrap();
+        sorted_string_table.remove("A".to_owned()).unwrap();
+        for i in 0..8 {
+            sorted_string_table
+                .insert(format!("E{i}"), "new".to_owned())
+                .unwrap();
+            sorted_string_table
+                .insert("B".to_owned(), format!("new{i}"))
+                .unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+
+        for key in ["A", "B", "C", "D"] {
+            assert_eq!(
+                snapshot.find(&key.to_owned()).unwrap(),
+                Some("old".to_owned())
+            );
+        }
+        assert_eq!(snapshot.find(&"E0".to_owned()).unwrap(), None);
+        let snapshot_key_value_pairs: Vec<(String, String)> =
+            snapshot.range(..).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            snapshot_key_value_pairs,
+            ["A", "B", "C", "D"].map(|key| (key.to_owned(), "old".to_owned()))
+        );
+        assert_eq!(sorted_string_table.find(&"A".to_owned()).unwrap(), None);
+        assert_eq!(
+            sorted_string_table.find(&"B".to_owned()).unwrap(),
+            Some("new7".to_owned())
+        );
+        assert_eq!(sorted_string_table.snapshot().sequence_number(), 22);
+
+        drop(snapshot);
+        assert!(sorted_string_table
+            .snapshot_sequence_numbers
+            .lock()
+            .unwrap()
+            .is_empty());
+        assert_eq!(sorted_string_table.count_live_keys().unwrap(), 11);
+    }
+
+    #[test]
+    fn sequence_numbers_keep_increasing_after_reopen() {
+        let data_directory = test_data_directory("sequence_numbers_after_reopen");
+        let memtable_config = MemtableConfig::new(2, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(memtable_config.clone()).unwrap();
+        for key in ["A", "B", "C", "D"] {
+            sorted_string_table.insert(key, "1").unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+        drop(sorted_string_table);
+
+        //Every write ahead log holding a sequence number is gone, so it comes from the manifest
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(memtable_config.clone()).unwrap();
+        assert_eq!(sorted_string_table.snapshot().sequence_number(), 4);
+        sorted_string_table.insert("E", "1").unwrap();
+        drop(sorted_string_table);
+
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        assert_eq!(sorted_string_table.snapshot().sequence_number(), 5);
+    }
+
+    #[test]
+    fn write_batch_is_seen_whole_by_readers_and_after_restart() {
+        let data_directory = test_data_directory("write_batch_seen_whole");
+        let memtable_config = MemtableConfig::new(4, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..50 {
+                    let mut write_batch = WriteBatch::new();
+                    write_batch
+                        .insert("entity".to_owned(), i.to_string())
+                        .insert(format!("index/{i:02}"), "entity".to_owned());
+                    if i > 0 {
+                        write_batch.remove(format!("index/{:02}", i - 1));
+                    }
+                    sorted_string_table.write(write_batch).unwrap();
+                }
+            });
+            for _ in 0..50 {
+                let snapshot = sorted_string_table.snapshot();
+                let index_keys: Vec<String> = snapshot
+                    .range("index/".to_owned().."index0".to_owned())
+                    .map(|key_value_pair| key_value_pair.unwrap().0)
+                    .collect();
+                match snapshot.find(&"entity".to_owned()).unwrap() {
+                    Some(entity) => assert_eq!(index_keys, vec![format!("index/{entity:0>2}")]),
+                    None => assert!(index_keys.is_empty()),
+                }
+            }
+        });
+        assert_eq!(sorted_string_table.snapshot().sequence_number(), 149);
+        drop(sorted_string_table);
+
+        //A batch logged but not flushed is replayed in full
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        assert_eq!(
+            sorted_string_table.find(&"entity".to_owned()).unwrap(),
+            Some("49".to_owned())
+        );
+        let key_value_pairs: Vec<(String, String)> = sorted_string_table
+            .range(..)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            key_value_pairs,
+            vec![
+                ("entity".to_owned(), "49".to_owned()),
+                ("index/49".to_owned(), "entity".to_owned())
+            ]
+        );
+        assert_eq!(sorted_string_table.snapshot().sequence_number(), 149);
+    }
+
+    #[test]
+    fn merge_operands_fold_across_flushes_compactions_and_restarts() {
+        let data_directory = test_data_directory("merge_operands_fold");
+        let mut memtable_config = MemtableConfig::new(3, &data_directory);
+        memtable_config.compaction_threshold = 2;
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        assert!(matches!(
+            sorted_string_table.merge("counter".to_owned(), "1".to_owned()),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        drop(sorted_string_table);
+
+        memtable_config.merge_operator = Some(Arc::new(U64AddOperator::new()));
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        sorted_string_table
+            .insert("counter".to_owned(), "10".to_owned())
+            .unwrap();
+        //An operand the operator cannot apply is never written
+        assert!(matches!(
+            sorted_string_table.merge("counter".to_owned(), "abc".to_owned()),
+            Err(Error::MergeFailed(_))
+        ));
+        let mut write_batch = WriteBatch::new();
+        write_batch
+            .insert("batched".to_owned(), "1".to_owned())
+            .merge("counter".to_owned(), "-1".to_owned());
+        assert!(matches!(
+            sorted_string_table.write(write_batch),
+            Err(Error::MergeFailed(_))
+        ));
+        assert_eq!(
+            sorted_string_table.find(&"batched".to_owned()).unwrap(),
+            None
+        );
+        let mut snapshot = None;
+        //Other keys fill the memtable, so the operands are spread over flushed and compacted files
+        for i in 0..20 {
+            sorted_string_table
+                .merge("counter".to_owned(), "1".to_owned())
+                .unwrap();
+            sorted_string_table
+                .merge("unset".to_owned(), "2".to_owned())
+                .unwrap();
+            sorted_string_table
+                .insert(format!("key{i:02}"), "1".to_owned())
+                .unwrap();
+            if i == 9 {
+                snapshot = Some(sorted_string_table.snapshot());
+            }
+        }
+        let snapshot = snapshot.unwrap();
+        assert_eq!(
+            snapshot.find(&"counter".to_owned()).unwrap(),
+            Some("20".to_owned())
+        );
+        drop(snapshot);
+        let read_counters = |sorted_string_table: &SortedStringTable<String, String>| {
+            let key_value_pairs: Vec<(String, String)> = sorted_string_table
+                .range("counter".to_owned()..="unset".to_owned())
+                .filter(|key_value_pair| {
+                    key_value_pair
+                        .as_ref()
+                        .map_or(true, |(key, _)| !key.starts_with("key"))
+                })
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(
+                sorted_string_table.find(&"counter".to_owned()).unwrap(),
+                Some(key_value_pairs[0].1.clone())
+            );
+            key_value_pairs
+        };
+        assert_eq!(
+            read_counters(&sorted_string_table),
+            vec![
+                ("counter".to_owned(), "30".to_owned()),
+                ("unset".to_owned(), "40".to_owned())
+            ]
+        );
+
+        //Operands still in the write ahead log are replayed on top of those in files
+        sorted_string_table
+            .merge("counter".to_owned(), "5".to_owned())
+            .unwrap();
+        drop(sorted_string_table);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config).unwrap();
+        assert_eq!(
+            read_counters(&sorted_string_table),
+            vec![
+                ("counter".to_owned(), "35".to_owned()),
+                ("unset".to_owned(), "40".to_owned())
+            ]
+        );
+        //A removed key counts from nothing again
+        sorted_string_table.remove("counter".to_owned()).unwrap();
+        sorted_string_table
+            .merge("counter".to_owned(), "3".to_owned())
+            .unwrap();
+        assert_eq!(
+            sorted_string_table.find(&"counter".to_owned()).unwrap(),
+            Some("3".to_owned())
+        );
+    }
+
+    #[test]
+    fn expired_values_read_as_removed_and_expiry_survives_restart() {
+        let data_directory = test_data_directory("expired_values_read_as_removed");
+        let memtable_config = MemtableConfig::new(2, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(memtable_config.clone()).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        sorted_string_table.insert("A", "old").unwrap();
+        sorted_string_table.insert("B", "old").unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+        sorted_string_table
+            .insert_with_ttl("A", "new", Duration::ZERO)
+            .unwrap();
+        sorted_string_table
+            .insert_with_ttl("C", "new", hour)
+            .unwrap();
+        sorted_string_table
+            .insert_with_ttl("D", "new", Duration::ZERO)
+            .unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+
+        let assert_expiry_applied = |sorted_string_table: &SortedStringTable<&str, &str>| {
+            //An expired value hides the older value it replaced
+            assert_eq!(sorted_string_table.find(&"A").unwrap(), None);
+            assert_eq!(
+                sorted_string_table.find(&"C").unwrap(),
+                Some("new".to_owned())
+            );
+            assert_eq!(sorted_string_table.find(&"D").unwrap(), None);
+            let key_value_pairs: Vec<(String, String)> = sorted_string_table
+                .range(..)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(
+                key_value_pairs,
+                vec![
+                    ("B".to_owned(), "old".to_owned()),
+                    ("C".to_owned(), "new".to_owned())
+                ]
+            );
+        };
+        assert_expiry_applied(&sorted_string_table);
+        sorted_string_table
+            .insert_with_ttl("E", "unflushed", Duration::ZERO)
+            .unwrap();
+        drop(sorted_string_table);
+
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(memtable_config.clone()).unwrap();
+        assert_expiry_applied(&sorted_string_table);
+
+        let mut memtable_config = MemtableConfig::new(2, &test_data_directory("ttl_old_format"));
+        memtable_config.format_version = FILTER_FORMAT_VERSION;
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        assert!(matches!(
+            sorted_string_table.insert_with_ttl("A", "1", hour),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        assert_eq!(sorted_string_table.find(&"A").unwrap(), None);
+    }
+
+    #[test]
+    fn sorted_string_table_compacts_similar_sized_files() {
+        let data_directory = test_data_directory("sorted_string_table_compacts_files");
+        let mut memtable_config = MemtableConfig::new(4, &data_directory);
+        memtable_config.compaction_threshold = 3;
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        for i in 0..60 {
+            sorted_string_table
+                .insert(format!("key{:02}", i % 20), format!("{}", i))
+                .unwrap();
+        }
+        sorted_string_table.remove("key05".to_owned()).unwrap();
+        for i in 0..3 {
+            sorted_string_table
+                .insert(format!("other{}", i), "1".to_owned())
+                .unwrap();
+        }
+
+        //Sixteen flushes, left as at most a couple of files in each size tier
+        assert!(sorted_string_table.current_memtable_file_levels()[0].len() <= 4);
+        assert!(sorted_string_table
+            .find(&"key05".to_owned())
+            .unwrap()
+            .is_none());
+        for i in (0..20).filter(|i| *i != 5) {
+            let find_result = sorted_string_table.find(&format!("key{:02}", i)).unwrap();
+            assert_eq!(find_result, Some(format!("{}", i + 40)));
+        }
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config).unwrap();
+        assert_eq!(reopened_sorted_string_table.range(..).count(), 22);
+    }
+
+    #[test]
+    fn sorted_string_table_open_rebuilds_levels_from_manifest() {
+        let data_directory = test_data_directory("sorted_string_table_open_from_manifest");
+        let mut memtable_config = MemtableConfig::new(2, &data_directory);
+        memtable_config.compaction_threshold = 3;
+        memtable_config.compaction_strategy = Arc::new(LeveledCompaction::default());
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        for i in 0..10 {
+            sorted_string_table
+                .insert(format!("key{:02}", i), format!("{}", i))
+                .unwrap();
+        }
+        let file_numbers = |sorted_string_table: &SortedStringTable<String, String>| {
+            sorted_string_table
+                .current_memtable_file_levels()
+                .iter()
+                .map(|memtable_files| {
+                    memtable_files
+                        .iter()
+                        .map(|memtable_file| memtable_file.file_number)
+                        .collect::<Vec<u64>>()
+                })
+                .collect::<Vec<Vec<u64>>>()
+        };
+        sorted_string_table.wait_for_flush().unwrap();
+        let live_file_numbers = file_numbers(&sorted_string_table);
+        assert_eq!(live_file_numbers[0].len(), 2);
+        assert_eq!(live_file_numbers[1].len(), 1);
+
+        //Left behind as though by a compaction that never committed
+        let level_one_file_path =
+            &sorted_string_table.current_memtable_file_levels()[1][0].file_path;
+        let uncommitted_file_path = leveled_memtable_file_path(&data_directory, 90, 1);
+        fs::copy(level_one_file_path, &uncommitted_file_path).unwrap();
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config).unwrap();
+        assert_eq!(
+            file_numbers(&reopened_sorted_string_table),
+            live_file_numbers
+        );
+        assert!(!uncommitted_file_path.exists());
+        assert_eq!(
+            reopened_sorted_string_table
+                .find(&"key03".to_owned())
+                .unwrap(),
+            Some("3".to_owned())
+        );
+        assert_eq!(reopened_sorted_string_table.range(..).count(), 10);
+    }
+
+    #[test]
+    fn sorted_string_table_open_rejects_manifest_with_corrupt_record_length() {
+        let data_directory = test_data_directory("sorted_string_table_corrupt_manifest_length");
+        let memtable_config = MemtableConfig::new(3, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(memtable_config.clone()).unwrap();
+        for key in ["A", "B", "C", "D", "E", "F", "G", "H", "I"] {
+            sorted_string_table.insert(key, "1").unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+        let memtable_file_paths: Vec<PathBuf> = sorted_string_table
+            .current_memtable_file_levels()
+            .iter()
+            .flatten()
+            .map(|memtable_file| memtable_file.file_path.clone())
+            .collect();
+        assert_eq!(memtable_file_paths.len(), 3);
+        drop(sorted_string_table);
+
+        //Each record is its checksum, then the length of its edit and the edit
+        let mut manifest_contents = fs::read(manifest_path(&data_directory)).unwrap();
+        let first_edit_length = u32::from_le_bytes(manifest_contents[4..8].try_into().unwrap());
+        let second_record_start = 8 + first_edit_length as usize;
+        manifest_contents[second_record_start + 7] = 0x7f;
+        fs::write(manifest_path(&data_directory), manifest_contents).unwrap();
+
+        assert!(matches!(
+            SortedStringTable::<&str, &str>::open(&data_directory, memtable_config),
+            Err(Error::ChecksumMismatch(_))
+        ));
+        for memtable_file_path in memtable_file_paths {
+            assert!(memtable_file_path.exists());
+        }
+    }
+
+    #[test]
+    fn sorted_string_table_leveled_compaction_probes_one_file_per_level() {
+        let data_directory = test_data_directory("sorted_string_table_leveled_compaction");
+        let mut memtable_config = MemtableConfig::new(5, &data_directory);
+        memtable_config.compaction_threshold = 2;
+        memtable_config.compaction_strategy = Arc::new(LeveledCompaction {
+            level_one_size: 100,
+            level_size_ratio: 2,
+            target_file_size: 40,
+        });
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        for i in 0..200 {
+            sorted_string_table
+                .insert(format!("key{:02}", (i * 7) % 50), format!("{}", i))
+                .unwrap();
+        }
+        sorted_string_table.remove("key07".to_owned()).unwrap();
+
+        let memtable_file_levels = sorted_string_table.current_memtable_file_levels();
+        let deeper_levels = memtable_file_levels.len() - 1;
+        assert!(deeper_levels >= 2);
+        for i in 0..50 {
+            let key = format!("key{:02}", i);
+            let deeper_level_files_probed = memtable_files_for_key(&memtable_file_levels, &key)
+                .filter(|memtable_file| memtable_file.level > 0)
+                .count();
+            assert!(deeper_level_files_probed <= deeper_levels);
+            let expected_value = (150..200).find(|j| (j * 7) % 50 == i).unwrap();
+            let find_result = sorted_string_table.find(&key).unwrap();
+            match i {
+                7 => assert!(find_result.is_none()),
+                _ => assert_eq!(find_result, Some(expected_value.to_string())),
+            }
+        }
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config).unwrap();
+        assert_eq!(reopened_sorted_string_table.range(..).count(), 49);
+        assert_eq!(
+            reopened_sorted_string_table
+                .find(&"key49".to_owned())
+                .unwrap(),
+            Some("157".to_owned())
+        );
+    }
+
+    #[test]
+    fn sorted_string_table_bloom_filters_skip_files_for_missing_keys() {
+        let data_directory = test_data_directory("sorted_string_table_bloom_filters");
+        let memtable_config = MemtableConfig::new(10, &data_directory);
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        for i in 0..100 {
+            sorted_string_table
+                .insert(format!("key{:02}", i), i.to_string())
+                .unwrap();
+        }
+        drop(sorted_string_table);
+
+        let reopened_sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config).unwrap();
+        assert!(
+            reopened_sorted_string_table.current_memtable_file_levels()[0]
+                .iter()
+                .all(|memtable_file| memtable_file.bloom_filter.is_some())
+        );
+        //The missing keys fall inside the key ranges of the files, so only the filters rule them out
+        let files_probed: usize = (0..100)
+            .map(|i| {
+                let missing_key = format!("key{:02}a", i);
+                assert!(reopened_sorted_string_table
+                    .find(&missing_key)
+                    .unwrap()
+                    .is_none());
+                memtable_files_for_key(
+                    &reopened_sorted_string_table.current_memtable_file_levels(),
+                    &missing_key,
+                )
+                .count()
+            })
+            .sum();
+        assert!(files_probed < 10, "{} files probed", files_probed);
+        assert_eq!(
+            reopened_sorted_string_table
+                .find(&"key42".to_owned())
+                .unwrap(),
+            Some("42".to_owned())
+        );
+    }
+
+    #[test]
+    fn sorted_string_table_verify_reports_corrupt_blocks() {
+        let data_directory = test_data_directory("sorted_string_table_verify");
+        let mut memtable_config = MemtableConfig::new(200, &data_directory);
+        memtable_config.block_size = 256;
+        let sorted_string_table =
+            SortedStringTable::<String, String>::new(memtable_config.clone()).unwrap();
+        for i in 0..400 {
+            sorted_string_table
+                .insert(format!("key{:03}", i), i.to_string())
+                .unwrap();
+        }
+        assert!(sorted_string_table.verify().unwrap().is_empty());
+        drop(sorted_string_table);
+
+        //Flip a byte inside the third data block of the first file
+        let sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config.clone())
+                .unwrap();
+        let (third_block_key, third_block_offset) = sorted_string_table
+            .current_memtable_file_levels()[0][0]
+            .key_offsets
+            .as_ref()
+            .unwrap()[2]
+            .clone();
+        drop(sorted_string_table);
+        let first_file_path = memtable_file_path(&data_directory, 1);
+        let mut file_contents = fs::read(&first_file_path).unwrap();
+        file_contents[third_block_offset + 10] ^= 1;
+        fs::write(&first_file_path, file_contents).unwrap();
+
+        let sorted_string_table =
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config.clone())
+                .unwrap();
+        let corrupt_blocks = sorted_string_table.verify().unwrap();
+        assert_eq!(corrupt_blocks.len(), 1);
+        assert_eq!(corrupt_blocks[0].file_path, first_file_path);
+        assert_eq!(corrupt_blocks[0].block_offset, Some(third_block_offset));
+        assert!(matches!(
+            corrupt_blocks[0].error,
+            Error::ChecksumMismatch(_)
+        ));
+        assert!(matches!(
+            sorted_string_table.find(&third_block_key),
+            Err(Error::ChecksumMismatch(_))
+        ));
+        assert_eq!(
+            sorted_string_table.find(&"key000".to_owned()).unwrap(),
+            Some("0".to_owned())
+        );
+        assert!(sorted_string_table
+            .range(..)
+            .any(|key_value_pair| key_value_pair.is_err()));
+        drop(sorted_string_table);
+
+        //The index block is read on open, so a bad one stops the table opening
+        let second_file_path = memtable_file_path(&data_directory, 2);
+        let mut file_contents = fs::read(&second_file_path).unwrap();
+        let index_block_byte = file_contents.len() - 50;
+        file_contents[index_block_byte] ^= 1;
+        fs::write(&second_file_path, file_contents).unwrap();
+        assert!(matches!(
+            SortedStringTable::<String, String>::open(&data_directory, memtable_config),
+            Err(Error::ChecksumMismatch(_))
+        ));
     }
 }
@@ -0,0 +1,10 @@
+use std::fs;
+
+/// Gives each test its own empty data directory under `./output`, so tests can run in
+/// parallel and repeated runs do not pick up files left behind by earlier ones.
+pub fn test_data_directory(test_name: &str) -> String {
+    let data_directory = format!("./output/{}", test_name);
+    let _ = fs::remove_dir_all(&data_directory);
+    fs::create_dir_all(&data_directory).unwrap();
+    data_directory
+}
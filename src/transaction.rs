@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::iter;
+
+use crate::{
+    error::Result,
+    memtable_entry::{MemtableEntry, SequencedEntry},
+    merge_operator::fold_entries,
+    sorted_string_table::{Snapshot, SortedStringTable, StringLike},
+    write_batch::WriteBatch,
+};
+
+/// Reads from a snapshot taken when it began and collects writes that are only applied on
+/// `commit`. Finds see the transaction's own writes before the snapshot. Commit fails with a
+/// conflict when a key the transaction read has been written since, so that a value computed
+/// from what was read is never written over a change it did not see.
+pub struct Transaction<'a, K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    sorted_string_table: &'a SortedStringTable<K, V>,
+    snapshot: Snapshot<'a, K, V>,
+    //Whether the snapshot held a value for each key read from it
+    read_keys: BTreeMap<K, bool>,
+    write_batch: WriteBatch<K, V>,
+}
+
+impl<'a, K, V> Transaction<'a, K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    pub fn new(sorted_string_table: &'a SortedStringTable<K, V>) -> Self {
+        Transaction {
+            sorted_string_table,
+            snapshot: sorted_string_table.snapshot(),
+            read_keys: BTreeMap::new(),
+            write_batch: WriteBatch::new(),
+        }
+    }
+
+    /// Returns the value the transaction last wrote for the key, with any merges it wrote
+    /// since applied, or otherwise the key's value in the snapshot, which is then checked for
+    /// changes on commit. The snapshot is also read for merges written over no value.
+    pub fn find(&mut self, key: &K) -> Result<Option<String>> {
+        let key_as_string: String = key.clone().into();
+        let sequenced_entry = |entry| SequencedEntry {
+            sequence_number: self.snapshot.sequence_number(),
+            entry,
+        };
+        let written_entries = self
+            .write_batch
+            .entries_for_key(&key_as_string)
+            .map(|entry| Ok(sequenced_entry(entry.clone())));
+        let mut snapshot_has_value = None;
+        let snapshot_entry = iter::once_with(|| {
+            let snapshot_value = self.snapshot.find(key)?;
+            snapshot_has_value = Some(snapshot_value.is_some());
+            let entry = match snapshot_value {
+                Some(value) => MemtableEntry::Value(value),
+                None => MemtableEntry::Tombstone,
+            };
+            Ok(sequenced_entry(entry))
+        });
+        let fold_result = fold_entries(
+            self.sorted_string_table.merge_operator(),
+            &key_as_string,
+            written_entries.chain(snapshot_entry),
+            false,
+        )?;
+        if let Some(snapshot_has_value) = snapshot_has_value {
+            self.read_keys.insert(key.clone(), snapshot_has_value);
+        }
+        Ok(
+            fold_result.and_then(|sequenced_entry| match sequenced_entry.entry {
+                MemtableEntry::Value(value) => Some(value),
+                _ => None,
+            }),
+        )
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.write_batch.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: K) {
+        self.write_batch.remove(key);
+    }
+
+    /// Adds a merge operand for the key, which does not read the key, so merges from
+    /// concurrent transactions never conflict with one another.
+    pub fn merge(&mut self, key: K, operand: V) {
+        self.write_batch.merge(key, operand);
+    }
+
+    /// Writes the transaction's inserts, removes and merges as one atomic batch, unless a key
+    /// it read has been written since its snapshot, see
+    /// [`SortedStringTable::write_if_unchanged`].
+    pub fn commit(self) -> Result<()> {
+        self.sorted_string_table.write_if_unchanged(
+            self.read_keys
+                .iter()
+                .map(|(read_key, has_value)| (read_key, *has_value)),
+            self.snapshot.sequence_number(),
+            self.write_batch,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        error::Error,
+        memtable_config::MemtableConfig,
+        memtable_file_index::{CHECKSUM_FORMAT_VERSION, SEQUENCE_FORMAT_VERSION},
+        merge_operator::U64AddOperator,
+        sorted_string_table::SortedStringTable,
+        test_utils::test_data_directory,
+    };
+
+    #[test]
+    fn transaction_reads_its_own_writes_and_commits_atomically() {
+        let data_directory = test_data_directory("transaction_reads_own_writes");
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(MemtableConfig::new(4, &data_directory)).unwrap();
+        sorted_string_table.insert("A", "1").unwrap();
+        sorted_string_table.insert("B", "1").unwrap();
+
+        let mut transaction = sorted_string_table.begin_transaction();
+        assert_eq!(transaction.find(&"A").unwrap(), Some("1".to_owned()));
+        transaction.insert("A", "2");
+        transaction.remove("B");
+        transaction.insert("C", "2");
+        assert_eq!(transaction.find(&"A").unwrap(), Some("2".to_owned()));
+        assert_eq!(transaction.find(&"B").unwrap(), None);
+        //Nothing is visible outside the transaction until it commits
+        assert_eq!(
+            sorted_string_table.find(&"A").unwrap(),
+            Some("1".to_owned())
+        );
+        assert_eq!(sorted_string_table.find(&"C").unwrap(), None);
+
+        //A write to a key the transaction did not read does not conflict
+        sorted_string_table.insert("D", "1").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(
+            sorted_string_table.find(&"A").unwrap(),
+            Some("2".to_owned())
+        );
+        assert_eq!(sorted_string_table.find(&"B").unwrap(), None);
+        assert_eq!(
+            sorted_string_table.find(&"C").unwrap(),
+            Some("2".to_owned())
+        );
+    }
+
+    #[test]
+    fn transaction_conflicts_with_writes_to_keys_it_read() {
+        let data_directory = test_data_directory("transaction_conflicts");
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(MemtableConfig::new(2, &data_directory)).unwrap();
+        sorted_string_table.insert("counter", "1").unwrap();
+
+        let mut first_transaction = sorted_string_table.begin_transaction();
+        let mut second_transaction = sorted_string_table.begin_transaction();
+        let mut absent_key_transaction = sorted_string_table.begin_transaction();
+        assert_eq!(
+            first_transaction.find(&"counter").unwrap(),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            second_transaction.find(&"counter").unwrap(),
+            Some("1".to_owned())
+        );
+        assert_eq!(absent_key_transaction.find(&"new").unwrap(), None);
+        first_transaction.insert("counter", "2");
+        second_transaction.insert("counter", "2");
+        absent_key_transaction.insert("counter", "3");
+
+        first_transaction.commit().unwrap();
+        //Flushed so the conflicting write is found in a memtable file
+        sorted_string_table.insert("new", "1").unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+        assert!(matches!(
+            second_transaction.commit(),
+            Err(Error::Conflict(_))
+        ));
+        assert!(matches!(
+            absent_key_transaction.commit(),
+            Err(Error::Conflict(_))
+        ));
+        assert_eq!(
+            sorted_string_table.find(&"counter").unwrap(),
+            Some("2".to_owned())
+        );
+    }
+
+    #[test]
+    fn transaction_conflicts_with_removes_compacted_away() {
+        let data_directory = test_data_directory("transaction_conflicts_with_compacted_removes");
+        let mut memtable_config = MemtableConfig::new(2, &data_directory);
+        memtable_config.compaction_threshold = 2;
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("A", "1").unwrap();
+
+        let mut transaction = sorted_string_table.begin_transaction();
+        assert_eq!(transaction.find(&"A").unwrap(), Some("1".to_owned()));
+        transaction.insert("A", "2");
+        //Compacting every file together drops the tombstone along with the value it hid
+        sorted_string_table.remove("A").unwrap();
+        for key in ["B", "C", "D", "E", "F"] {
+            sorted_string_table.insert(key, "1").unwrap();
+        }
+        sorted_string_table.wait_for_flush().unwrap();
+        assert!(matches!(transaction.commit(), Err(Error::Conflict(_))));
+        assert_eq!(sorted_string_table.find(&"A").unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_merges_only_conflict_once_the_key_is_read() {
+        let data_directory = test_data_directory("transaction_merges");
+        let mut memtable_config = MemtableConfig::new(4, &data_directory);
+        memtable_config.merge_operator = Some(Arc::new(U64AddOperator::new()));
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        sorted_string_table.insert("counter", "1").unwrap();
+
+        let mut first_transaction = sorted_string_table.begin_transaction();
+        let mut second_transaction = sorted_string_table.begin_transaction();
+        first_transaction.merge("counter", "2");
+        second_transaction.merge("counter", "3");
+        //A find applies the transaction's own merges to the snapshot's value
+        assert_eq!(
+            first_transaction.find(&"counter").unwrap(),
+            Some("3".to_owned())
+        );
+        second_transaction.commit().unwrap();
+        //The find read the key, so the first transaction now conflicts
+        assert!(matches!(
+            first_transaction.commit(),
+            Err(Error::Conflict(_))
+        ));
+
+        let mut transaction = sorted_string_table.begin_transaction();
+        transaction.merge("counter", "5");
+        sorted_string_table.merge("counter", "10").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(
+            sorted_string_table.find(&"counter").unwrap(),
+            Some("19".to_owned())
+        );
+    }
+
+    #[test]
+    fn transaction_commit_fails_when_writes_cannot_be_detected() {
+        let data_directory = test_data_directory("transaction_without_sequence_numbers");
+        let mut memtable_config = MemtableConfig::new(2, &data_directory);
+        memtable_config.format_version = CHECKSUM_FORMAT_VERSION;
+        let sorted_string_table =
+            SortedStringTable::<&str, &str>::new(memtable_config.clone()).unwrap();
+        sorted_string_table.insert("A", "1").unwrap();
+        sorted_string_table.insert("B", "1").unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+
+        let mut transaction = sorted_string_table.begin_transaction();
+        assert_eq!(transaction.find(&"A").unwrap(), Some("1".to_owned()));
+        transaction.insert("A", "2");
+        sorted_string_table.insert("A", "3").unwrap();
+        sorted_string_table.insert("C", "3").unwrap();
+        sorted_string_table.wait_for_flush().unwrap();
+        assert!(matches!(
+            transaction.commit(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        drop(sorted_string_table);
+
+        //Files written before the table moved to a format with sequence numbers keep lacking them
+        memtable_config.format_version = SEQUENCE_FORMAT_VERSION;
+        let sorted_string_table = SortedStringTable::<&str, &str>::new(memtable_config).unwrap();
+        let mut transaction = sorted_string_table.begin_transaction();
+        assert_eq!(transaction.find(&"B").unwrap(), Some("1".to_owned()));
+        transaction.insert("B", "2");
+        assert!(matches!(
+            transaction.commit(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+        assert_eq!(
+            sorted_string_table.find(&"A").unwrap(),
+            Some("3".to_owned())
+        );
+        assert_eq!(
+            sorted_string_table.find(&"B").unwrap(),
+            Some("1".to_owned())
+        );
+    }
+}
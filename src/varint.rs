@@ -0,0 +1,100 @@
+use std::io::{self, Read};
+
+//Each byte carries seven bits of the value, with the high bit set on all but the last
+const CONTINUATION_BIT: u8 = 0x80;
+const MAX_VARINT_LENGTH: usize = 10;
+
+/// Appends the value as a little endian base 128 varint, taking a single byte for values
+/// below 128.
+pub fn encode_varint(mut value: u64, output: &mut Vec<u8>) {
+    while value >= CONTINUATION_BIT as u64 {
+        output.push(value as u8 | CONTINUATION_BIT);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+/// Decodes the varint starting at `position`, moving it past the varint. Returns `None` when
+/// the bytes end part way through the varint or it is longer than any `u64` needs.
+pub fn decode_varint(bytes: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for varint_length in 0..MAX_VARINT_LENGTH {
+        let byte = *bytes.get(*position + varint_length)?;
+        value |= ((byte & !CONTINUATION_BIT) as u64) << (7 * varint_length);
+        if byte & CONTINUATION_BIT == 0 {
+            *position += varint_length + 1;
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Reads a varint from the reader, failing with `UnexpectedEof` when it ends part way through.
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    for varint_length in 0..MAX_VARINT_LENGTH {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & !CONTINUATION_BIT) as u64) << (7 * varint_length);
+        if byte[0] & CONTINUATION_BIT == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint is longer than ten bytes",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_varint, encode_varint, read_varint};
+
+    #[test]
+    fn varint_round_trip() {
+        let values = [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ];
+        let mut encoded_values = vec![];
+        for value in values {
+            encode_varint(value, &mut encoded_values);
+        }
+        let mut position = 0;
+        for value in values {
+            assert_eq!(decode_varint(&encoded_values, &mut position), Some(value));
+        }
+        assert_eq!(position, encoded_values.len());
+
+        let mut reader = &encoded_values[..];
+        for value in values {
+            assert_eq!(read_varint(&mut reader).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn varint_lengths() {
+        let mut encoded_value = vec![];
+        encode_varint(127, &mut encoded_value);
+        assert_eq!(encoded_value, vec![0x7f]);
+        encoded_value.clear();
+        encode_varint(300, &mut encoded_value);
+        assert_eq!(encoded_value, vec![0xac, 0x02]);
+    }
+
+    #[test]
+    fn truncated_varint_is_not_decoded() {
+        let mut position = 0;
+        assert_eq!(decode_varint(&[0xac], &mut position), None);
+        assert_eq!(position, 0);
+        assert!(read_varint(&mut &[0xac][..]).is_err());
+        assert_eq!(decode_varint(&[0xff; 11], &mut position), None);
+    }
+}
@@ -0,0 +1,553 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::crc32c::crc32c;
+use crate::error::{Error, Result};
+use crate::memtable_entry::{MemtableEntry, SequencedEntry};
+
+const WRITE_AHEAD_LOG_EXTENSION: &str = "log";
+const LENGTH_PREFIX_SIZE: usize = 4;
+const RECORD_CHECKSUM_SIZE: usize = 4;
+const SEQUENCE_NUMBER_SIZE: usize = 8;
+//Records written before sequence numbers, replayed with sequence number 0
+const VALUE_RECORD_TYPE: u8 = 0;
+const TOMBSTONE_RECORD_TYPE: u8 = 1;
+const SEQUENCED_VALUE_RECORD_TYPE: u8 = 2;
+const SEQUENCED_TOMBSTONE_RECORD_TYPE: u8 = 3;
+const BATCH_RECORD_TYPE: u8 = 4;
+const SEQUENCED_MERGE_RECORD_TYPE: u8 = 5;
+//The value is followed by the time it expires at
+const SEQUENCED_EXPIRING_VALUE_RECORD_TYPE: u8 = 6;
+const EXPIRY_TIME_SIZE: usize = 8;
+
+/// Append only log of the mutations held by the current memtable. The log shares its number
+/// with the memtable file the memtable will be flushed to, so once that file exists the log
+/// is no longer needed.
+pub struct WriteAheadLog {
+    pub log_number: u64,
+    pub file_path: PathBuf,
+    file: File,
+}
+
+impl WriteAheadLog {
+    pub fn create(data_directory: &str, log_number: u64) -> Result<Self> {
+        let file_path = write_ahead_log_path(data_directory, log_number);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file_path)?;
+        Ok(WriteAheadLog {
+            log_number,
+            file_path,
+            file,
+        })
+    }
+
+    /// Appends a record for the key and its entry. The record is handed to the operating
+    /// system before returning, so it survives the process crashing.
+    pub fn append(&mut self, key: &str, sequenced_entry: &SequencedEntry) -> Result<()> {
+        let record = create_write_ahead_log_record(key, sequenced_entry);
+        self.file.write_all(&record)?;
+        Ok(())
+    }
+
+    /// Appends the entries as a single record, numbered in order from the first sequence
+    /// number. A crash part way through the append drops the whole record on replay, so the
+    /// entries are recovered together or not at all.
+    pub fn append_batch(
+        &mut self,
+        first_sequence_number: u64,
+        key_entry_pairs: &[(String, MemtableEntry)],
+    ) -> Result<()> {
+        let record = create_batch_record(first_sequence_number, key_entry_pairs);
+        self.file.write_all(&record)?;
+        Ok(())
+    }
+}
+
+pub fn write_ahead_log_path(data_directory: &str, log_number: u64) -> PathBuf {
+    Path::new(data_directory).join(format!("{:06}.{}", log_number, WRITE_AHEAD_LOG_EXTENSION))
+}
+
+pub fn parse_write_ahead_log_number(file_path: &Path) -> Option<u64> {
+    if file_path.extension()? != WRITE_AHEAD_LOG_EXTENSION {
+        return None;
+    }
+    file_path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
+
+/// Returns the numbers and paths of every write ahead log in the data directory, oldest first.
+pub fn find_write_ahead_logs(data_directory: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let mut write_ahead_logs = vec![];
+    for entry in fs::read_dir(data_directory)? {
+        let file_path = entry?.path();
+        if let Some(log_number) = parse_write_ahead_log_number(&file_path) {
+            write_ahead_logs.push((log_number, file_path));
+        }
+    }
+    write_ahead_logs.sort();
+    Ok(write_ahead_logs)
+}
+
+/// Reads back every complete record in the log. A record cut short by a crash part way
+/// through an append was never acknowledged, so it is dropped along with anything after it,
+/// as is a last record whose checksum does not match. A mismatch in any earlier record, or a
+/// record that cannot be read in full but is followed by a complete one, as when one of its
+/// lengths is corrupt, cannot be explained by a crash and is returned as an error.
+pub fn read_write_ahead_log(file_path: &Path) -> Result<Vec<(String, SequencedEntry)>> {
+    let log_contents = fs::read(file_path)?;
+    let mut key_entry_pairs = vec![];
+    let mut record_start = 0;
+    loop {
+        let mut position = record_start;
+        match read_write_ahead_log_record(&log_contents, &mut position)? {
+            Some(record_entries) => key_entry_pairs.extend(record_entries),
+            None => break,
+        }
+        record_start = position;
+    }
+    let has_complete_record_after = (record_start + 1..log_contents.len()).any(|later_start| {
+        let mut position = later_start;
+        matches!(
+            read_write_ahead_log_record(&log_contents, &mut position),
+            Ok(Some(_))
+        )
+    });
+    if has_complete_record_after {
+        return Err(Error::ChecksumMismatch(format!(
+            "write ahead log record at offset {} cannot be read but is followed by others",
+            record_start
+        )));
+    }
+    Ok(key_entry_pairs)
+}
+
+//The CRC32C of the rest of the record, then the record type, sequence number and length
+//prefixed key and value
+fn create_write_ahead_log_record(key: &str, sequenced_entry: &SequencedEntry) -> Vec<u8> {
+    let mut record = Vec::with_capacity(
+        RECORD_CHECKSUM_SIZE + 1 + SEQUENCE_NUMBER_SIZE + 2 * LENGTH_PREFIX_SIZE + key.len(),
+    );
+    record.extend_from_slice(&[0; RECORD_CHECKSUM_SIZE]);
+    record.push(record_type_of_entry(&sequenced_entry.entry));
+    record.extend_from_slice(&sequenced_entry.sequence_number.to_le_bytes());
+    encode_key_and_entry(key, &sequenced_entry.entry, &mut record);
+    set_record_checksum(&mut record);
+    record
+}
+
+//The CRC32C of the rest of the record, then the record type, the first sequence number and
+//the number of entries, each written as the record type it would have alone followed by its
+//length prefixed key and value
+fn create_batch_record(
+    first_sequence_number: u64,
+    key_entry_pairs: &[(String, MemtableEntry)],
+) -> Vec<u8> {
+    let mut record = vec![0; RECORD_CHECKSUM_SIZE];
+    record.push(BATCH_RECORD_TYPE);
+    record.extend_from_slice(&first_sequence_number.to_le_bytes());
+    record.extend_from_slice(&(key_entry_pairs.len() as u32).to_le_bytes());
+    for (key, entry) in key_entry_pairs {
+        record.push(record_type_of_entry(entry));
+        encode_key_and_entry(key, entry, &mut record);
+    }
+    set_record_checksum(&mut record);
+    record
+}
+
+fn record_type_of_entry(entry: &MemtableEntry) -> u8 {
+    match entry {
+        MemtableEntry::Value(_) => SEQUENCED_VALUE_RECORD_TYPE,
+        MemtableEntry::Tombstone => SEQUENCED_TOMBSTONE_RECORD_TYPE,
+        MemtableEntry::Merge(_) => SEQUENCED_MERGE_RECORD_TYPE,
+        MemtableEntry::ExpiringValue(..) => SEQUENCED_EXPIRING_VALUE_RECORD_TYPE,
+    }
+}
+
+fn encode_key_and_entry(key: &str, entry: &MemtableEntry, record: &mut Vec<u8>) {
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    if let MemtableEntry::Value(value)
+    | MemtableEntry::Merge(value)
+    | MemtableEntry::ExpiringValue(value, _) = entry
+    {
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(value.as_bytes());
+    }
+    if let MemtableEntry::ExpiringValue(_, expires_at) = entry {
+        record.extend_from_slice(&expires_at.to_le_bytes());
+    }
+}
+
+fn set_record_checksum(record: &mut [u8]) {
+    let checksum = crc32c(&record[RECORD_CHECKSUM_SIZE..]);
+    record[..RECORD_CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+}
+
+//Returns `None` once the remaining bytes do not hold a complete record. A batch record gives
+//every entry of its batch, and a record of any other type a single entry
+fn read_write_ahead_log_record(
+    log_contents: &[u8],
+    position: &mut usize,
+) -> Result<Option<Vec<(String, SequencedEntry)>>> {
+    let record_start = *position;
+    let stored_checksum = match log_contents.get(record_start..record_start + RECORD_CHECKSUM_SIZE)
+    {
+        Some(stored_checksum) => stored_checksum,
+        None => return Ok(None),
+    };
+    *position += RECORD_CHECKSUM_SIZE;
+    let record_type = match log_contents.get(*position) {
+        Some(record_type) => *record_type,
+        None => return Ok(None),
+    };
+    *position += 1;
+    let sequence_number = match record_type {
+        SEQUENCED_VALUE_RECORD_TYPE
+        | SEQUENCED_TOMBSTONE_RECORD_TYPE
+        | SEQUENCED_MERGE_RECORD_TYPE
+        | SEQUENCED_EXPIRING_VALUE_RECORD_TYPE
+        | BATCH_RECORD_TYPE => {
+            let sequence_number_end = *position + SEQUENCE_NUMBER_SIZE;
+            let sequence_number_bytes = match log_contents.get(*position..sequence_number_end) {
+                Some(sequence_number_bytes) => sequence_number_bytes,
+                None => return Ok(None),
+            };
+            *position = sequence_number_end;
+            u64::from_le_bytes(sequence_number_bytes.try_into().unwrap())
+        }
+        _ => 0,
+    };
+    //The lengths are read before the checksum can be checked, so are bounded by the log
+    let encoded_entries = match record_type {
+        BATCH_RECORD_TYPE => read_batch_entries(log_contents, position),
+        _ => read_encoded_entry(log_contents, position, record_type)
+            .map(|encoded_entry| vec![encoded_entry]),
+    };
+    let Some(encoded_entries) = encoded_entries else {
+        return Ok(None);
+    };
+    let checksum = crc32c(&log_contents[record_start + RECORD_CHECKSUM_SIZE..*position]);
+    if stored_checksum != checksum.to_le_bytes() {
+        return match *position == log_contents.len() {
+            true => Ok(None),
+            false => Err(Error::ChecksumMismatch(format!(
+                "write ahead log record at offset {} does not match its checksum",
+                record_start
+            ))),
+        };
+    }
+    let mut key_entry_pairs = Vec::with_capacity(encoded_entries.len());
+    for (entry_index, (entry_type, key_bytes, value_bytes, expiry_time_bytes)) in
+        encoded_entries.into_iter().enumerate()
+    {
+        let key = bytes_to_string(key_bytes)?;
+        let entry = match (entry_type, value_bytes) {
+            (SEQUENCED_EXPIRING_VALUE_RECORD_TYPE, Some(value_bytes)) => {
+                let expires_at = u64::from_le_bytes(expiry_time_bytes.unwrap().try_into().unwrap());
+                MemtableEntry::ExpiringValue(bytes_to_string(value_bytes)?, expires_at)
+            }
+            (VALUE_RECORD_TYPE | SEQUENCED_VALUE_RECORD_TYPE, Some(value_bytes)) => {
+                MemtableEntry::Value(bytes_to_string(value_bytes)?)
+            }
+            (TOMBSTONE_RECORD_TYPE | SEQUENCED_TOMBSTONE_RECORD_TYPE, _) => {
+                MemtableEntry::Tombstone
+            }
+            (SEQUENCED_MERGE_RECORD_TYPE, Some(operand_bytes)) => {
+                MemtableEntry::Merge(bytes_to_string(operand_bytes)?)
+            }
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "unknown write ahead log record type {}",
+                    entry_type
+                )))
+            }
+        };
+        key_entry_pairs.push((
+            key,
+            SequencedEntry {
+                sequence_number: sequence_number + entry_index as u64,
+                entry,
+            },
+        ));
+    }
+    Ok(Some(key_entry_pairs))
+}
+
+//An entry's type, key bytes and, for a value, value bytes and for an expiring value the bytes of
+//its expiry time, as read before the checksum is checked
+type EncodedEntry<'a> = (u8, &'a [u8], Option<&'a [u8]>, Option<&'a [u8]>);
+
+fn read_encoded_entry<'a>(
+    log_contents: &'a [u8],
+    position: &mut usize,
+    entry_type: u8,
+) -> Option<EncodedEntry<'a>> {
+    let key_bytes = read_length_prefixed_bytes(log_contents, position)?;
+    let value_bytes = match entry_type {
+        VALUE_RECORD_TYPE
+        | SEQUENCED_VALUE_RECORD_TYPE
+        | SEQUENCED_MERGE_RECORD_TYPE
+        | SEQUENCED_EXPIRING_VALUE_RECORD_TYPE => {
+            Some(read_length_prefixed_bytes(log_contents, position)?)
+        }
+        _ => None,
+    };
+    let expiry_time_bytes = match entry_type {
+        SEQUENCED_EXPIRING_VALUE_RECORD_TYPE => {
+            let expiry_time_bytes = log_contents.get(*position..*position + EXPIRY_TIME_SIZE)?;
+            *position += EXPIRY_TIME_SIZE;
+            Some(expiry_time_bytes)
+        }
+        _ => None,
+    };
+    Some((entry_type, key_bytes, value_bytes, expiry_time_bytes))
+}
+
+fn read_batch_entries<'a>(
+    log_contents: &'a [u8],
+    position: &mut usize,
+) -> Option<Vec<EncodedEntry<'a>>> {
+    let count_end = *position + LENGTH_PREFIX_SIZE;
+    let count_bytes = log_contents.get(*position..count_end)?;
+    let entry_count = u32::from_le_bytes(count_bytes.try_into().unwrap());
+    *position = count_end;
+    let mut encoded_entries = vec![];
+    for _ in 0..entry_count {
+        let entry_type = *log_contents.get(*position)?;
+        *position += 1;
+        encoded_entries.push(read_encoded_entry(log_contents, position, entry_type)?);
+    }
+    Some(encoded_entries)
+}
+
+//Returns `None` when the bytes end before the length or the bytes it covers
+fn read_length_prefixed_bytes<'a>(
+    log_contents: &'a [u8],
+    position: &mut usize,
+) -> Option<&'a [u8]> {
+    let length_end = *position + LENGTH_PREFIX_SIZE;
+    let length_bytes = log_contents.get(*position..length_end)?;
+    let length = u32::from_le_bytes(length_bytes.try_into().unwrap()) as usize;
+    let bytes = log_contents.get(length_end..length_end + length)?;
+    *position = length_end + length;
+    Some(bytes)
+}
+
+fn bytes_to_string(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| Error::Corruption("write ahead log record is not valid UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use crate::{
+        crc32c::crc32c,
+        error::Error,
+        memtable_entry::{MemtableEntry, SequencedEntry},
+        test_utils::test_data_directory,
+    };
+
+    use super::{
+        find_write_ahead_logs, read_write_ahead_log, WriteAheadLog, RECORD_CHECKSUM_SIZE,
+        SEQUENCE_NUMBER_SIZE,
+    };
+
+    fn sequenced_entry(sequence_number: u64, entry: MemtableEntry) -> SequencedEntry {
+        SequencedEntry {
+            sequence_number,
+            entry,
+        }
+    }
+
+    fn value(value: &str) -> MemtableEntry {
+        MemtableEntry::Value(value.to_string())
+    }
+
+    #[test]
+    fn write_ahead_log_round_trip() {
+        let data_directory = test_data_directory("write_ahead_log_round_trip");
+        let mut write_ahead_log = WriteAheadLog::create(&data_directory, 3).unwrap();
+        let key_entry_pairs = vec![
+            ("A".to_string(), sequenced_entry(1, value("1"))),
+            (
+                "key:with\ndelimiters".to_string(),
+                sequenced_entry(2, value("")),
+            ),
+            (
+                "counter".to_string(),
+                sequenced_entry(3, MemtableEntry::Merge("5".to_string())),
+            ),
+            (
+                "session".to_string(),
+                sequenced_entry(4, MemtableEntry::ExpiringValue("1".to_string(), u64::MAX)),
+            ),
+            (
+                "A".to_string(),
+                sequenced_entry(u64::MAX, MemtableEntry::Tombstone),
+            ),
+        ];
+        for (key, entry) in &key_entry_pairs {
+            write_ahead_log.append(key, entry).unwrap();
+        }
+        assert_eq!(
+            read_write_ahead_log(&write_ahead_log.file_path).unwrap(),
+            key_entry_pairs
+        );
+    }
+
+    #[test]
+    fn write_ahead_log_batch_is_replayed_whole_or_not_at_all() {
+        let data_directory = test_data_directory("write_ahead_log_batch");
+        let mut write_ahead_log = WriteAheadLog::create(&data_directory, 1).unwrap();
+        write_ahead_log
+            .append("A", &sequenced_entry(1, value("1")))
+            .unwrap();
+        let batch = vec![
+            ("B".to_string(), value("2")),
+            ("A".to_string(), MemtableEntry::Tombstone),
+            ("C".to_string(), value("")),
+        ];
+        write_ahead_log.append_batch(2, &batch).unwrap();
+        assert_eq!(
+            read_write_ahead_log(&write_ahead_log.file_path).unwrap(),
+            vec![
+                ("A".to_string(), sequenced_entry(1, value("1"))),
+                ("B".to_string(), sequenced_entry(2, value("2"))),
+                (
+                    "A".to_string(),
+                    sequenced_entry(3, MemtableEntry::Tombstone)
+                ),
+                ("C".to_string(), sequenced_entry(4, value(""))),
+            ]
+        );
+
+        //A batch cut short loses every entry, not just the last
+        let log_contents = fs::read(&write_ahead_log.file_path).unwrap();
+        fs::write(
+            &write_ahead_log.file_path,
+            &log_contents[..log_contents.len() - 1],
+        )
+        .unwrap();
+        assert_eq!(
+            read_write_ahead_log(&write_ahead_log.file_path).unwrap(),
+            vec![("A".to_string(), sequenced_entry(1, value("1")))]
+        );
+    }
+
+    #[test]
+    fn write_ahead_log_reads_records_without_sequence_numbers() {
+        let data_directory = test_data_directory("write_ahead_log_unsequenced_records");
+        let write_ahead_log = WriteAheadLog::create(&data_directory, 1).unwrap();
+        let mut log_file = OpenOptions::new()
+            .append(true)
+            .open(&write_ahead_log.file_path)
+            .unwrap();
+        let record_body = [1, 1, 0, 0, 0, b'A'];
+        log_file
+            .write_all(&crc32c(&record_body).to_le_bytes())
+            .unwrap();
+        log_file.write_all(&record_body).unwrap();
+        assert_eq!(
+            read_write_ahead_log(&write_ahead_log.file_path).unwrap(),
+            vec![(
+                "A".to_string(),
+                sequenced_entry(0, MemtableEntry::Tombstone)
+            )]
+        );
+    }
+
+    #[test]
+    fn write_ahead_log_ignores_torn_record() {
+        let data_directory = test_data_directory("write_ahead_log_torn_record");
+        let mut write_ahead_log = WriteAheadLog::create(&data_directory, 1).unwrap();
+        write_ahead_log
+            .append("A", &sequenced_entry(1, value("1")))
+            .unwrap();
+        let mut log_file = OpenOptions::new()
+            .append(true)
+            .open(&write_ahead_log.file_path)
+            .unwrap();
+        log_file.write_all(&[0, 5, 0, 0, 0, b'B']).unwrap();
+        let key_entry_pairs = read_write_ahead_log(&write_ahead_log.file_path).unwrap();
+        assert_eq!(
+            key_entry_pairs,
+            vec![("A".to_string(), sequenced_entry(1, value("1")))]
+        );
+    }
+
+    #[test]
+    fn find_write_ahead_logs_in_order() {
+        let data_directory = test_data_directory("find_write_ahead_logs");
+        WriteAheadLog::create(&data_directory, 4).unwrap();
+        WriteAheadLog::create(&data_directory, 2).unwrap();
+        let log_numbers: Vec<u64> = find_write_ahead_logs(&data_directory)
+            .unwrap()
+            .into_iter()
+            .map(|(log_number, _)| log_number)
+            .collect();
+        assert_eq!(log_numbers, vec![2, 4]);
+    }
+
+    #[test]
+    fn write_ahead_log_rejects_unknown_record_type() {
+        let data_directory = test_data_directory("write_ahead_log_unknown_record_type");
+        let write_ahead_log = WriteAheadLog::create(&data_directory, 1).unwrap();
+        let mut log_file = OpenOptions::new()
+            .append(true)
+            .open(&write_ahead_log.file_path)
+            .unwrap();
+        let record_body = [7, 1, 0, 0, 0, b'A'];
+        log_file
+            .write_all(&crc32c(&record_body).to_le_bytes())
+            .unwrap();
+        log_file.write_all(&record_body).unwrap();
+        assert!(matches!(
+            read_write_ahead_log(&write_ahead_log.file_path),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn write_ahead_log_detects_flipped_byte() {
+        let data_directory = test_data_directory("write_ahead_log_flipped_byte");
+        let mut write_ahead_log = WriteAheadLog::create(&data_directory, 1).unwrap();
+        for key in ["A", "B", "C"] {
+            write_ahead_log
+                .append(key, &sequenced_entry(1, value("1")))
+                .unwrap();
+        }
+        let log_contents = fs::read(&write_ahead_log.file_path).unwrap();
+        let record_length = log_contents.len() / 3;
+
+        //Only the last record can have been torn by a crash, so only it is dropped
+        let mut flipped_log_contents = log_contents.clone();
+        flipped_log_contents[3 * record_length - 1] ^= 1;
+        fs::write(&write_ahead_log.file_path, &flipped_log_contents).unwrap();
+        let key_entry_pairs = read_write_ahead_log(&write_ahead_log.file_path).unwrap();
+        assert_eq!(key_entry_pairs.len(), 2);
+
+        let mut flipped_log_contents = log_contents.clone();
+        flipped_log_contents[record_length + 9] ^= 1;
+        fs::write(&write_ahead_log.file_path, &flipped_log_contents).unwrap();
+        assert!(matches!(
+            read_write_ahead_log(&write_ahead_log.file_path),
+            Err(Error::ChecksumMismatch(_))
+        ));
+
+        //A key length running past the end of the log would look like a torn record, were it
+        //not followed by a complete one
+        let mut flipped_log_contents = log_contents;
+        let key_length_start = record_length + RECORD_CHECKSUM_SIZE + 1 + SEQUENCE_NUMBER_SIZE;
+        flipped_log_contents[key_length_start + 3] = 0x7f;
+        fs::write(&write_ahead_log.file_path, &flipped_log_contents).unwrap();
+        assert!(matches!(
+            read_write_ahead_log(&write_ahead_log.file_path),
+            Err(Error::ChecksumMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+use std::marker::PhantomData;
+
+use crate::{memtable_entry::MemtableEntry, sorted_string_table::StringLike};
+
+/// Inserts, removes and merges collected to be applied to a table together, see
+/// [`crate::sorted_string_table::SortedStringTable::write`]. Entries are applied in the order
+/// they were added, so a later entry for a key takes the place of an earlier one.
+pub struct WriteBatch<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    key_entry_pairs: Vec<(String, MemtableEntry)>,
+    key_value_types: PhantomData<(K, V)>,
+}
+
+impl<K, V> WriteBatch<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    pub fn new() -> Self {
+        WriteBatch {
+            key_entry_pairs: vec![],
+            key_value_types: PhantomData,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.key_entry_pairs
+            .push((key.into(), MemtableEntry::Value(value.into())));
+        self
+    }
+
+    pub fn remove(&mut self, key: K) -> &mut Self {
+        self.key_entry_pairs
+            .push((key.into(), MemtableEntry::Tombstone));
+        self
+    }
+
+    pub fn merge(&mut self, key: K, operand: V) -> &mut Self {
+        self.key_entry_pairs
+            .push((key.into(), MemtableEntry::Merge(operand.into())));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.key_entry_pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key_entry_pairs.is_empty()
+    }
+
+    /// Returns the entries added for the key, last added first.
+    pub fn entries_for_key<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a MemtableEntry> {
+        self.key_entry_pairs
+            .iter()
+            .rev()
+            .filter(move |(batch_key, _)| batch_key == key)
+            .map(|(_, entry)| entry)
+    }
+
+    pub fn into_key_entry_pairs(self) -> Vec<(String, MemtableEntry)> {
+        self.key_entry_pairs
+    }
+}
+
+impl<K, V> Default for WriteBatch<K, V>
+where
+    K: StringLike,
+    V: StringLike,
+{
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}